@@ -1,8 +1,12 @@
 fn main() {
+    #[cfg(windows)]
     windows::build!(
         Windows::Win32::Controls::*,
         Windows::Win32::DisplayDevices::POINT,
+        Windows::Win32::Gdi::CreateBitmap,
+        Windows::Win32::Gdi::DeleteObject,
         Windows::Win32::Gdi::HBRUSH,
+        Windows::Win32::KeyboardAndMouseInput::GetKeyState,
         Windows::Win32::MenusAndResources::*,
         Windows::Win32::Shell::*,
         Windows::Win32::SystemServices::*,
@@ -1,4 +1,5 @@
 fn main() {
+    #[cfg(windows)]
     windows::build!(
         Windows::Win32::WindowsAndMessaging::*
     );
@@ -1,14 +1,19 @@
+#[cfg(windows)]
 mod bindings {
     windows::include_bindings!();
 }
 
+#[cfg(windows)]
 use bindings::Windows::Win32::WindowsAndMessaging::{
     DispatchMessageA, PeekMessageA, TranslateMessage, WaitMessage, HWND, MSG,
     PEEK_MESSAGE_REMOVE_TYPE, WM_QUIT,
 };
+#[cfg(windows)]
 use std::time::Duration;
+#[cfg(windows)]
 use trayicon::*;
 
+#[cfg(windows)]
 #[derive(Copy, Clone, Eq, PartialEq, Debug)]
 enum Events {
     ClickTrayIcon,
@@ -24,6 +29,12 @@ enum Events {
     SubItem3,
 }
 
+#[cfg(not(windows))]
+fn main() {
+    eprintln!("This example runs only on Windows");
+}
+
+#[cfg(windows)]
 fn main() {
     let (s, r) = std::sync::mpsc::channel::<Events>();
     let icon = include_bytes!("../../../src/testresource/icon1.ico");
@@ -60,7 +71,7 @@ fn main() {
                     icon: None,
                 })
                 .separator()
-                .item("E&xit", Events::Exit),
+                .item(MenuLabel::new("Exit").with_mnemonic('x'), Events::Exit),
         )
         .build()
         .unwrap();
@@ -1,9 +1,23 @@
+use self::ico::{IcoDir, IcoImage};
 use crate::{Error, IconBase};
-use std::fmt::Debug;
+use std::borrow::Cow;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::{fmt::Debug, path::Path, sync::Arc};
 
+pub(crate) mod ico;
+
+/// Icon is identified by its decoded images, so icons from different sources
+/// with the same pixels are equal
 #[derive(Clone)]
 pub struct Icon {
-    buffer: Option<&'static [u8]>,
+    content: u64,
+
+    /// Decoded images, for the menus which draw the icon themselves
+    images: Arc<Vec<IcoImage>>,
+
+    /// Path or name the icon is referred to by in saved menus
+    name: Option<String>,
     pub(crate) sys: crate::IconSys,
 }
 
@@ -19,15 +33,265 @@ impl Icon {
         width: Option<u32>,
         height: Option<u32>,
     ) -> Result<Icon, Error> {
-        Ok(Icon {
-            buffer: Some(buffer),
-            sys: crate::IconSys::from_buffer(buffer, width, height)?,
+        Icon::from_bytes(buffer, width, height)
+    }
+
+    /// Icon from owned ICO or PNG data
+    ///
+    /// The format is sniffed from the data. ICO picks the entry closest to
+    /// the size, PNG is a single image and ignores it.
+    pub fn from_bytes<B>(bytes: B, width: Option<u32>, height: Option<u32>) -> Result<Icon, Error>
+    where
+        B: Into<Arc<[u8]>>,
+    {
+        let bytes = bytes.into();
+        if bytes.starts_with(ico::PNG_SIGNATURE) {
+            // PNG file is like an icon with a single entry
+            let image = ico::decode_png(&bytes)
+                .map_err(|reason| Error::IconEntryCorrupt { entry: 0, reason })?;
+            return Ok(Icon::new(
+                crate::IconSys::from_rgba(image.width, image.height, &image.rgba)?,
+                vec![image],
+            ));
+        }
+        let images = IcoDir::parse(&bytes).and_then(|dir| dir.images(width, height));
+
+        // Windows loads entries the portable decoder doesn't, like 16 bits
+        // per pixel or RLE ones. Such icons are shown, only the menus drawing
+        // the icon themselves have no images of it.
+        #[cfg(target_os = "windows")]
+        {
+            if images.is_err() {
+                if let Ok(sys) = crate::IconSys::from_buffer(&bytes, width, height) {
+                    return Ok(Icon {
+                        content: content_hash(&*bytes),
+                        images: Arc::new(vec![]),
+                        name: None,
+                        sys,
+                    });
+                }
+            }
+        }
+
+        let images = images?;
+        Ok(Icon::new(
+            crate::IconSys::from_buffer(&bytes, width, height)?,
+            images,
+        ))
+    }
+
+    /// Icon from ICO or PNG file
+    ///
+    /// Errors are wrapped in `Error::IconFile` with the path of the file.
+    pub fn from_path<P>(path: P, width: Option<u32>, height: Option<u32>) -> Result<Icon, Error>
+    where
+        P: AsRef<Path>,
+    {
+        let path = path.as_ref();
+        let file_error = |reason| Error::IconFile {
+            path: path.to_path_buf(),
+            reason: Box::new(reason),
+        };
+        let bytes = std::fs::read(path).map_err(|e| file_error(Error::Io(e.kind())))?;
+        let icon = Icon::from_bytes(bytes, width, height).map_err(file_error)?;
+        Ok(icon.with_name(&path.to_string_lossy()))
+    }
+
+    /// Icon from raw pixels, four bytes per pixel in R, G, B, A order, rows
+    /// top to bottom
+    ///
+    /// Returns `Error::InvalidRgba` if either dimension is zero or the length
+    /// of the buffer isn't `width * height * 4`.
+    pub fn from_rgba(width: u32, height: u32, rgba: Vec<u8>) -> Result<Icon, Error> {
+        let expected = (width as usize)
+            .checked_mul(height as usize)
+            .and_then(|pixels| pixels.checked_mul(4));
+        if width == 0 || height == 0 || expected != Some(rgba.len()) {
+            return Err(Error::InvalidRgba {
+                width,
+                height,
+                len: rgba.len(),
+            });
+        }
+        Ok(Icon::new(
+            crate::IconSys::from_rgba(width, height, &rgba)?,
+            vec![IcoImage {
+                width,
+                height,
+                rgba,
+            }],
+        ))
+    }
+
+    /// Name the icon is referred to by in saved menus, icons from files
+    /// are named by their path
+    ///
+    /// The name doesn't take part in the equality of icons.
+    pub fn with_name(mut self, name: &str) -> Icon {
+        self.name = Some(name.to_string());
+        self
+    }
+
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    fn new(sys: crate::IconSys, images: Vec<IcoImage>) -> Icon {
+        Icon {
+            content: content_hash(&images),
+            images: Arc::new(images),
+            name: None,
+            sys,
+        }
+    }
+
+    /// Smallest image at least `size` pixels wide, or the largest one
+    pub(crate) fn image(&self, size: u32) -> Option<&IcoImage> {
+        self.images
+            .iter()
+            .filter(|i| i.width >= size)
+            .min_by_key(|i| i.width)
+            .or_else(|| self.images.iter().max_by_key(|i| i.width))
+    }
+
+    /// Image exactly `size` pixels square, the one of `image` scaled to it
+    /// unless the icon has an entry of the size
+    pub(crate) fn image_sized(&self, size: u32) -> Option<Cow<'_, IcoImage>> {
+        self.image(size).map(|image| {
+            if image.width == size && image.height == size {
+                Cow::Borrowed(image)
+            } else {
+                Cow::Owned(image.scaled(size, size))
+            }
         })
     }
 }
 
+/// Hash of the images, or of the data if they aren't known, computed once
+/// when the icon is made
+fn content_hash<C>(content: &C) -> u64
+where
+    C: Hash + ?Sized,
+{
+    let mut hasher = DefaultHasher::new();
+    content.hash(&mut hasher);
+    hasher.finish()
+}
+
 impl PartialEq for Icon {
+    /// Hash tells most different icons apart, equal hashes are checked
     fn eq(&self, other: &Self) -> bool {
-        self.buffer == other.buffer
+        self.content == other.content
+            && (Arc::ptr_eq(&self.images, &other.images) || self.images == other.images)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_rgba() {
+        let red = [255, 0, 0, 255].repeat(4);
+        let icon = Icon::from_rgba(2, 2, red.clone()).unwrap();
+        assert_eq!(icon, Icon::from_rgba(2, 2, red.clone()).unwrap());
+        assert_ne!(icon, Icon::from_rgba(1, 4, red.clone()).unwrap());
+        assert_ne!(icon, Icon::from_rgba(2, 2, vec![0; 16]).unwrap());
+
+        assert_eq!(
+            Icon::from_rgba(2, 3, red).err(),
+            Some(Error::InvalidRgba {
+                width: 2,
+                height: 3,
+                len: 16
+            })
+        );
+        assert!(Icon::from_rgba(0, 0, vec![]).is_err());
+        assert!(Icon::from_rgba(u32::MAX, u32::MAX, vec![]).is_err());
+    }
+
+    #[test]
+    fn test_hash_collision() {
+        let red = Icon::from_rgba(1, 1, vec![255, 0, 0, 255]).unwrap();
+        let mut blue = Icon::from_rgba(1, 1, vec![0, 0, 255, 255]).unwrap();
+        blue.content = red.content;
+        assert_ne!(red, blue);
+        assert_eq!(red, red.clone());
+    }
+
+    #[test]
+    fn test_from_bytes_and_path() {
+        let buffer = include_bytes!("./testresource/icon1.ico");
+        let icon = Icon::from_bytes(buffer.to_vec(), None, None).unwrap();
+        assert_eq!(icon, Icon::from_buffer(buffer, None, None).unwrap());
+        assert_ne!(icon, Icon::from_buffer(buffer, Some(16), Some(16)).unwrap());
+        assert_ne!(
+            icon,
+            Icon::from_buffer(include_bytes!("./testresource/icon2.ico"), None, None).unwrap()
+        );
+
+        let dir = std::env::temp_dir().join(format!("trayicon-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("icon.ico");
+        std::fs::write(&path, &buffer[..]).unwrap();
+        assert_eq!(Icon::from_path(&path, None, None).unwrap(), icon);
+
+        // Errors tell the file
+        let garbage = dir.join("garbage.ico");
+        std::fs::write(&garbage, b"not an icon").unwrap();
+        assert_eq!(
+            Icon::from_path(&garbage, None, None).err(),
+            Some(Error::IconFile {
+                path: garbage,
+                reason: Box::new(Error::IconLoadingFailed)
+            })
+        );
+        let missing = dir.join("missing.ico");
+        assert_eq!(
+            Icon::from_path(&missing, None, None).err(),
+            Some(Error::IconFile {
+                path: missing,
+                reason: Box::new(Error::Io(std::io::ErrorKind::NotFound))
+            })
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_image_size() {
+        let icon =
+            Icon::from_buffer(include_bytes!("./testresource/icon1.ico"), None, None).unwrap();
+        assert_eq!(icon.image(16).map(|i| i.width), Some(16));
+        assert_eq!(icon.image(20).map(|i| i.width), Some(24));
+        assert_eq!(icon.image(100).map(|i| i.width), Some(64));
+
+        let size = |size| icon.image_sized(size).map(|i| (i.width, i.height));
+        assert!(matches!(icon.image_sized(16), Some(Cow::Borrowed(_))));
+        assert_eq!(size(20), Some((20, 20)));
+        assert_eq!(size(100), Some((100, 100)));
+    }
+
+    #[test]
+    fn test_from_png() {
+        let mut png = vec![];
+        let mut encoder = png::Encoder::new(&mut png, 2, 1);
+        encoder.set_color(png::ColorType::Rgba);
+        let mut writer = encoder.write_header().unwrap();
+        writer
+            .write_image_data(&[255, 0, 0, 255, 0, 0, 255, 128])
+            .unwrap();
+        writer.finish().unwrap();
+
+        // Same pixels are the same icon
+        let icon = Icon::from_bytes(png.clone(), None, None).unwrap();
+        let rgba = vec![255, 0, 0, 255, 0, 0, 255, 128];
+        assert_eq!(icon, Icon::from_rgba(2, 1, rgba).unwrap());
+        assert_ne!(icon, Icon::from_rgba(1, 2, vec![0; 8]).unwrap());
+
+        png.truncate(png.len() - 20);
+        assert!(matches!(
+            Icon::from_bytes(png, None, None),
+            Err(Error::IconEntryCorrupt { entry: 0, .. })
+        ));
     }
 }
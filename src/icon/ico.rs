@@ -0,0 +1,562 @@
+//! Portable parser for `.ico` files
+//!
+//! Reads the ICONDIR and ICONDIRENTRY structures, and decodes the BMP/DIB
+//! and PNG images of the entries to RGBA. The non-Windows backends need the
+//! pixels, and all backends compare icons by them.
+
+use crate::{Error, IconEntryError};
+
+/// Size picked when the caller doesn't ask for any, the usual size of a
+/// Windows icon
+pub(crate) const DEFAULT_SIZE: u32 = 32;
+
+/// Decoded image, RGBA rows top to bottom
+#[derive(Debug, Clone, PartialEq, Hash)]
+pub(crate) struct IcoImage {
+    pub width: u32,
+    pub height: u32,
+    pub rgba: Vec<u8>,
+}
+
+impl IcoImage {
+    /// Image scaled to `width` by `height`, each pixel averages the pixels
+    /// it covers, weighted by their alpha
+    pub fn scaled(&self, width: u32, height: u32) -> IcoImage {
+        let (from_w, from_h) = (self.width as usize, self.height as usize);
+        let (to_w, to_h) = (width as usize, height as usize);
+        let span = |i: usize, from: usize, to: usize| {
+            let start = i * from / to;
+            start..((i + 1) * from).div_ceil(to).max(start + 1)
+        };
+        let mut rgba = Vec::with_capacity(to_w * to_h * 4);
+        for y in 0..to_h {
+            for x in 0..to_w {
+                let mut sum = [0u32; 4];
+                let mut count = 0;
+                for sy in span(y, from_h, to_h) {
+                    for sx in span(x, from_w, to_w) {
+                        let pixel = &self.rgba[(sy * from_w + sx) * 4..][..4];
+                        let alpha = pixel[3] as u32;
+                        for c in 0..3 {
+                            sum[c] += pixel[c] as u32 * alpha;
+                        }
+                        sum[3] += alpha;
+                        count += 1;
+                    }
+                }
+                match sum[3] {
+                    0 => rgba.extend_from_slice(&[0; 4]),
+                    alpha => rgba.extend_from_slice(&[
+                        (sum[0] / alpha) as u8,
+                        (sum[1] / alpha) as u8,
+                        (sum[2] / alpha) as u8,
+                        (alpha / count) as u8,
+                    ]),
+                }
+            }
+        }
+        IcoImage {
+            width,
+            height,
+            rgba,
+        }
+    }
+}
+
+/// Images larger than this are not decoded, the directory can't even tell
+/// sizes above 256
+const MAX_SIZE: u32 = 1024;
+
+pub(crate) const PNG_SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+
+/// Single ICONDIRENTRY
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct IcoEntry {
+    pub index: usize,
+    pub width: u32,
+    pub height: u32,
+    pub bit_count: u16,
+    offset: usize,
+    size: usize,
+}
+
+/// Parsed icon directory, images are decoded on demand
+#[derive(Debug, Clone)]
+pub(crate) struct IcoDir<'a> {
+    buffer: &'a [u8],
+    pub entries: Vec<IcoEntry>,
+}
+
+impl<'a> IcoDir<'a> {
+    /// Parse the directory, each entry must point inside the buffer
+    pub fn parse(buffer: &'a [u8]) -> Result<IcoDir<'a>, Error> {
+        let header = |at| read_u16(buffer, at).ok_or(Error::IconLoadingFailed);
+        let count = header(4)? as usize;
+        if header(0)? != 0 || header(2)? != 1 || count == 0 {
+            return Err(Error::IconLoadingFailed);
+        }
+        let entries = (0..count)
+            .map(|i| {
+                let entry = buffer
+                    .get(6 + i * 16..6 + (i + 1) * 16)
+                    .ok_or(Error::IconLoadingFailed)?;
+
+                // Zero width or height means 256
+                let size = |b: u8| if b == 0 { 256 } else { b as u32 };
+                let entry = IcoEntry {
+                    index: i,
+                    width: size(entry[0]),
+                    height: size(entry[1]),
+                    bit_count: u16::from_le_bytes([entry[6], entry[7]]),
+                    size: u32::from_le_bytes([entry[8], entry[9], entry[10], entry[11]]) as usize,
+                    offset: u32::from_le_bytes([entry[12], entry[13], entry[14], entry[15]])
+                        as usize,
+                };
+                match entry.offset.checked_add(entry.size) {
+                    Some(end) if end <= buffer.len() => Ok(entry),
+                    _ => Err(Error::IconLoadingFailed),
+                }
+            })
+            .collect::<Result<Vec<_>, Error>>()?;
+        Ok(IcoDir { buffer, entries })
+    }
+
+    /// Raw image data of the entry
+    pub fn data(&self, entry: &IcoEntry) -> &'a [u8] {
+        &self.buffer[entry.offset..entry.offset + entry.size]
+    }
+
+    /// Decode image of the entry, PNG or DIB depending on the signature
+    pub fn decode(&self, entry: &IcoEntry) -> Result<IcoImage, Error> {
+        let data = self.data(entry);
+        let image = if data.starts_with(PNG_SIGNATURE) {
+            decode_png(data)
+        } else {
+            decode_dib(data)
+        };
+        image.map_err(|reason| Error::IconEntryCorrupt {
+            entry: entry.index,
+            reason,
+        })
+    }
+
+    /// Decode all entries, the ones that can't be decoded are left out
+    ///
+    /// Fails with the error of the first entry if none can be decoded.
+    pub fn decode_all(&self) -> Result<Vec<IcoImage>, Error> {
+        let mut first_error = None;
+        let images = self
+            .entries
+            .iter()
+            .filter_map(|e| match self.decode(e) {
+                Ok(image) => Some(image),
+                Err(err) => {
+                    first_error.get_or_insert(err);
+                    None
+                }
+            })
+            .collect::<Vec<_>>();
+        match first_error {
+            Some(err) if images.is_empty() => Err(err),
+            _ => Ok(images),
+        }
+    }
+
+    /// Images for the requested size
+    ///
+    /// Explicit size keeps only the best match, otherwise all sizes are
+    /// decoded.
+    pub fn images(&self, width: Option<u32>, height: Option<u32>) -> Result<Vec<IcoImage>, Error> {
+        if width.is_some() || height.is_some() {
+            let entry = self.best(width, height).ok_or(Error::IconLoadingFailed)?;
+            Ok(vec![self.decode(entry)?])
+        } else {
+            self.decode_all()
+        }
+    }
+
+    /// Entry that fits the requested size best
+    ///
+    /// Missing width or height is taken from the other, and without either
+    /// `DEFAULT_SIZE` is used. The closest size wins, on a tie the larger
+    /// one since scaling down looks better, and then the one with most
+    /// colors.
+    pub fn best(&self, width: Option<u32>, height: Option<u32>) -> Option<&IcoEntry> {
+        let (width, height) = match (width, height) {
+            (Some(w), Some(h)) => (w, h),
+            (Some(s), None) | (None, Some(s)) => (s, s),
+            (None, None) => (DEFAULT_SIZE, DEFAULT_SIZE),
+        };
+        self.entries.iter().min_by_key(|e| {
+            let distance =
+                (e.width as i64 - width as i64).abs() + (e.height as i64 - height as i64).abs();
+            let smaller = e.width < width || e.height < height;
+            (distance, smaller, std::cmp::Reverse(e.bit_count))
+        })
+    }
+}
+
+/// Decode PNG stream to RGBA
+///
+/// All PNG color types and bit depths are converted to 8 bit RGBA.
+pub(crate) fn decode_png(data: &[u8]) -> Result<IcoImage, IconEntryError> {
+    let mut decoder = png::Decoder::new(data);
+    decoder.set_transformations(png::Transformations::normalize_to_color8());
+    let mut reader = decoder.read_info().map_err(png_error)?;
+    let (width, height) = reader.info().size();
+    if width > MAX_SIZE || height > MAX_SIZE {
+        return Err(IconEntryError::Unsupported);
+    }
+    let mut buffer = vec![0; reader.output_buffer_size()];
+    let frame = reader.next_frame(&mut buffer).map_err(png_error)?;
+    let pixels = &buffer[..frame.buffer_size()];
+
+    let rgba = match frame.color_type {
+        png::ColorType::Rgba => pixels.to_vec(),
+        png::ColorType::Rgb => pixels
+            .chunks_exact(3)
+            .flat_map(|p| [p[0], p[1], p[2], 255])
+            .collect(),
+        png::ColorType::GrayscaleAlpha => pixels
+            .chunks_exact(2)
+            .flat_map(|p| [p[0], p[0], p[0], p[1]])
+            .collect(),
+        png::ColorType::Grayscale => pixels.iter().flat_map(|&p| [p, p, p, 255]).collect(),
+        png::ColorType::Indexed => return Err(IconEntryError::Unsupported),
+    };
+    Ok(IcoImage {
+        width,
+        height,
+        rgba,
+    })
+}
+
+fn png_error(err: png::DecodingError) -> IconEntryError {
+    match err {
+        png::DecodingError::IoError(_) => IconEntryError::Truncated,
+        _ => IconEntryError::InvalidPng,
+    }
+}
+
+/// Decode BITMAPINFOHEADER image with its AND mask to RGBA
+///
+/// ICO entries store the height of XOR and AND masks combined, rows are
+/// bottom-up. Images of 32 bits per pixel have alpha channel, unless all of
+/// it is zero, then the AND mask is used like with the other depths.
+fn decode_dib(data: &[u8]) -> Result<IcoImage, IconEntryError> {
+    let u16_at = |at| read_u16(data, at).ok_or(IconEntryError::Truncated);
+    let u32_at = |at| read_u32(data, at).ok_or(IconEntryError::Truncated);
+    let header_size = u32_at(0)? as usize;
+    let width = u32_at(4)? as i32;
+    let height = (u32_at(8)? as i32).unsigned_abs() / 2;
+    let bpp = u16_at(14)? as usize;
+    let compression = u32_at(16)?;
+    let colors_used = u32_at(32)? as usize;
+
+    // BI_RGB, and BI_BITFIELDS which the icons use only with the default
+    // masks
+    if header_size < 40 || width <= 0 || height == 0 {
+        return Err(IconEntryError::Truncated);
+    }
+    if compression != 0 && compression != 3 || width as u32 > MAX_SIZE || height > MAX_SIZE {
+        return Err(IconEntryError::Unsupported);
+    }
+    let (width, height) = (width as usize, height as usize);
+
+    let palette_size = match bpp {
+        1 | 4 | 8 if colors_used == 0 => 1 << bpp,
+        1 | 4 | 8 => colors_used.min(1 << bpp),
+        24 | 32 => 0,
+        _ => return Err(IconEntryError::Unsupported),
+    };
+    // Bitfield masks follow the 40 byte header, the later headers have
+    // them inside
+    let masks = if compression == 3 && header_size == 40 {
+        12
+    } else {
+        0
+    };
+    let palette_start = header_size + masks;
+    let palette = data
+        .get(palette_start..palette_start + palette_size * 4)
+        .ok_or(IconEntryError::Truncated)?;
+
+    let stride = (width * bpp).div_ceil(32) * 4;
+    let pixels_start = palette_start + palette.len();
+    let pixels = data
+        .get(pixels_start..pixels_start + stride * height)
+        .ok_or(IconEntryError::Truncated)?;
+
+    // Some 32 bit icons leave out the AND mask
+    let mask_stride = width.div_ceil(32) * 4;
+    let mask_start = pixels_start + pixels.len();
+    let mask = data.get(mask_start..mask_start + mask_stride * height);
+
+    let mut rgba = Vec::with_capacity(width * height * 4);
+    for y in (0..height).rev() {
+        let row = &pixels[y * stride..(y + 1) * stride];
+        for x in 0..width {
+            let bgra = match bpp {
+                32 => [row[x * 4], row[x * 4 + 1], row[x * 4 + 2], row[x * 4 + 3]],
+                24 => [row[x * 3], row[x * 3 + 1], row[x * 3 + 2], 255],
+                _ => {
+                    let bit = x * bpp;
+                    let index = (row[bit / 8] >> (8 - bpp - bit % 8)) as usize & ((1 << bpp) - 1);
+                    let color = palette
+                        .get(index * 4..index * 4 + 3)
+                        .ok_or(IconEntryError::Truncated)?;
+                    [color[0], color[1], color[2], 255]
+                }
+            };
+            rgba.extend_from_slice(&[bgra[2], bgra[1], bgra[0], bgra[3]]);
+        }
+    }
+
+    let has_alpha = bpp == 32 && rgba.chunks_exact(4).any(|p| p[3] != 0);
+    if !has_alpha {
+        let mask = mask.ok_or(IconEntryError::Truncated)?;
+        for (i, pixel) in rgba.chunks_exact_mut(4).enumerate() {
+            let (x, y) = (i % width, height - 1 - i / width);
+            let transparent = mask[y * mask_stride + x / 8] & (0x80 >> (x % 8)) != 0;
+            pixel[3] = if transparent { 0 } else { 255 };
+        }
+    }
+
+    Ok(IcoImage {
+        width: width as u32,
+        height: height as u32,
+        rgba,
+    })
+}
+
+fn read_u16(buf: &[u8], at: usize) -> Option<u16> {
+    buf.get(at..at + 2)
+        .map(|b| u16::from_le_bytes([b[0], b[1]]))
+}
+
+fn read_u32(buf: &[u8], at: usize) -> Option<u32> {
+    buf.get(at..at + 4)
+        .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Single entry ICO of 2x2 image, `rows` are bottom-up like in the file
+    fn ico(bpp: u16, palette: &[[u8; 4]], rows: &[&[u8]], mask: [u8; 2]) -> Vec<u8> {
+        let mut dib = vec![];
+        dib.extend_from_slice(&40u32.to_le_bytes());
+        dib.extend_from_slice(&2u32.to_le_bytes());
+        dib.extend_from_slice(&4u32.to_le_bytes());
+        dib.extend_from_slice(&1u16.to_le_bytes());
+        dib.extend_from_slice(&bpp.to_le_bytes());
+        dib.extend_from_slice(&[0; 16]);
+        dib.extend_from_slice(&(palette.len() as u32).to_le_bytes());
+        dib.extend_from_slice(&[0; 4]);
+        palette.iter().for_each(|c| dib.extend_from_slice(c));
+        for row in rows {
+            dib.extend_from_slice(row);
+            dib.resize(dib.len() + 3 - (row.len() + 3) % 4, 0);
+        }
+        for m in &mask {
+            dib.extend_from_slice(&[*m, 0, 0, 0]);
+        }
+        ico_file(2, bpp, &dib)
+    }
+
+    /// Single entry ICO with the image data
+    fn ico_file(size: u8, bpp: u16, data: &[u8]) -> Vec<u8> {
+        let mut ico = vec![0, 0, 1, 0, 1, 0, size, size, 0, 0, 1, 0];
+        ico.extend_from_slice(&bpp.to_le_bytes());
+        ico.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        ico.extend_from_slice(&22u32.to_le_bytes());
+        ico.extend_from_slice(data);
+        ico
+    }
+
+    fn png(color: png::ColorType, pixels: &[u8]) -> Vec<u8> {
+        let mut data = vec![];
+        let mut encoder = png::Encoder::new(&mut data, 2, 2);
+        encoder.set_color(color);
+        let mut writer = encoder.write_header().unwrap();
+        writer.write_image_data(pixels).unwrap();
+        writer.finish().unwrap();
+        data
+    }
+
+    const RED: [u8; 4] = [0, 0, 255, 0];
+    const BLUE: [u8; 4] = [255, 0, 0, 0];
+
+    /// Top row red and blue, bottom row blue and transparent
+    fn expected() -> Vec<u8> {
+        vec![
+            255, 0, 0, 255, 0, 0, 255, 255, //
+            0, 0, 255, 255, 0, 0, 0, 0,
+        ]
+    }
+
+    fn decode(buffer: &[u8]) -> IcoImage {
+        let dir = IcoDir::parse(buffer).unwrap();
+        dir.decode(&dir.entries[0]).unwrap()
+    }
+
+    #[test]
+    fn test_decode_depths() {
+        // Bottom-right pixel is cleared by the AND mask
+        let mask = [0b0100_0000, 0];
+        let palette = [RED, BLUE];
+        let images = [
+            ico(1, &palette, &[&[0b1000_0000], &[0b0100_0000]], mask),
+            ico(4, &palette, &[&[0x10], &[0x01]], mask),
+            ico(8, &palette, &[&[1, 0], &[0, 1]], mask),
+            ico(
+                24,
+                &[],
+                &[&[255, 0, 0, 0, 0, 0], &[0, 0, 255, 255, 0, 0]],
+                mask,
+            ),
+        ];
+        for (i, image) in images.iter().enumerate() {
+            let decoded = decode(image);
+            assert_eq!((decoded.width, decoded.height), (2, 2), "image {}", i);
+            let mut rgba = decoded.rgba;
+
+            // Colour under the mask doesn't matter
+            rgba[12..15].copy_from_slice(&[0, 0, 0]);
+            assert_eq!(rgba, expected(), "image {}", i);
+        }
+    }
+
+    #[test]
+    fn test_decode_32bpp() {
+        let rows: &[&[u8]] = &[
+            &[255, 0, 0, 255, 0, 0, 0, 0],
+            &[0, 0, 255, 255, 255, 0, 0, 255],
+        ];
+        let decoded = decode(&ico(32, &[], rows, [0xff, 0xff]));
+        assert_eq!(decoded.rgba, expected());
+
+        // Without any alpha, AND mask decides
+        let rows: &[&[u8]] = &[&[255, 0, 0, 0, 0, 0, 0, 0], &[0, 0, 255, 0, 255, 0, 0, 0]];
+        let decoded = decode(&ico(32, &[], rows, [0b0100_0000, 0]));
+        assert_eq!(decoded.rgba, expected());
+    }
+
+    #[test]
+    fn test_best_size() {
+        let buffer = include_bytes!("../testresource/icon1.ico");
+        let dir = IcoDir::parse(buffer).unwrap();
+        let sizes = dir.entries.iter().map(|e| e.width).collect::<Vec<_>>();
+        assert_eq!(sizes, vec![16, 24, 32, 48, 64]);
+        assert_eq!(dir.decode_all().unwrap().len(), 5);
+
+        let best = |w, h| dir.best(w, h).unwrap().width;
+        assert_eq!(best(None, None), DEFAULT_SIZE);
+        assert_eq!(best(Some(30), Some(30)), 32);
+        assert_eq!(best(Some(20), None), 24);
+        assert_eq!(best(None, Some(56)), 64);
+        assert_eq!(best(Some(512), Some(512)), 64);
+
+        let image = dir.decode(dir.best(Some(48), Some(48)).unwrap()).unwrap();
+        assert_eq!(image.rgba.len(), 48 * 48 * 4);
+    }
+
+    #[test]
+    fn test_scaled() {
+        let image = IcoImage {
+            width: 2,
+            height: 2,
+            rgba: vec![
+                255, 0, 0, 255, 0, 0, 255, 255, //
+                0, 0, 0, 0, 0, 0, 255, 255,
+            ],
+        };
+
+        // Transparent pixels don't darken the average
+        let one = image.scaled(1, 1);
+        assert_eq!(one.rgba, vec![85, 0, 170, 191]);
+
+        let four = image.scaled(4, 4);
+        assert_eq!(four.rgba.len(), 4 * 4 * 4);
+        assert_eq!(&four.rgba[..8], &[255, 0, 0, 255, 255, 0, 0, 255]);
+        assert_eq!(&four.rgba[four.rgba.len() - 4..], &[0, 0, 255, 255]);
+        assert_eq!(image.scaled(2, 2), image);
+    }
+
+    #[test]
+    fn test_garbage() {
+        assert!(IcoDir::parse(b"not an icon").is_err());
+
+        // Entry pointing past the end
+        let mut buffer = ico(24, &[], &[&[0; 6], &[0; 6]], [0, 0]);
+        buffer.truncate(40);
+        assert_eq!(IcoDir::parse(&buffer).err(), Some(Error::IconLoadingFailed));
+    }
+
+    #[test]
+    fn test_decode_png() {
+        let rgba = expected();
+        let decoded = decode(&ico_file(0, 32, &png(png::ColorType::Rgba, &rgba)));
+        assert_eq!((decoded.width, decoded.height), (2, 2));
+        assert_eq!(decoded.rgba, rgba);
+
+        // Other color types become RGBA too
+        let gray = decode(&ico_file(
+            0,
+            8,
+            &png(png::ColorType::Grayscale, &[0, 255, 255, 0]),
+        ));
+        assert_eq!(&gray.rgba[..8], &[0, 0, 0, 255, 255, 255, 255, 255]);
+        let rgb = rgba
+            .chunks_exact(4)
+            .flat_map(|p| [p[0], p[1], p[2]])
+            .collect::<Vec<_>>();
+        let decoded = decode(&ico_file(0, 24, &png(png::ColorType::Rgb, &rgb)));
+        assert!(decoded.rgba.chunks_exact(4).all(|p| p[3] == 255));
+    }
+
+    #[test]
+    fn test_corrupt_entries() {
+        let corrupt = |buffer: Vec<u8>| {
+            let dir = IcoDir::parse(&buffer).unwrap();
+            dir.decode(&dir.entries[0]).err()
+        };
+        let error = |reason| Some(Error::IconEntryCorrupt { entry: 0, reason });
+
+        let mut broken_png = png(png::ColorType::Rgba, &expected());
+        broken_png[20] ^= 0xff;
+        assert_eq!(
+            corrupt(ico_file(0, 32, &broken_png)),
+            error(IconEntryError::InvalidPng)
+        );
+        let png = png(png::ColorType::Rgba, &expected());
+        assert_eq!(
+            corrupt(ico_file(0, 32, &png[..png.len() - 20])),
+            error(IconEntryError::Truncated)
+        );
+
+        let mut dib = ico(24, &[], &[&[0; 6], &[0; 6]], [0, 0]);
+        dib.truncate(dib.len() - 10);
+        let size = dib.len() as u32 - 22;
+        dib[14..18].copy_from_slice(&size.to_le_bytes());
+        assert_eq!(corrupt(dib), error(IconEntryError::Truncated));
+        assert_eq!(
+            corrupt(ico(16, &[], &[&[0; 4], &[0; 4]], [0, 0])),
+            error(IconEntryError::Unsupported)
+        );
+
+        // RLE compression, and a height that can't be negated
+        let mut rle = ico(4, &[RED, BLUE], &[&[0x10], &[0x01]], [0, 0]);
+        rle[38] = 2;
+        assert_eq!(corrupt(rle), error(IconEntryError::Unsupported));
+        let mut tall = ico(24, &[], &[&[0; 6], &[0; 6]], [0, 0]);
+        tall[30..34].copy_from_slice(&0x8000_0000u32.to_le_bytes());
+        assert_eq!(corrupt(tall), error(IconEntryError::Unsupported));
+
+        // Icon with no usable entries tells why
+        let dir_buffer = ico_file(0, 32, &broken_png);
+        let dir = IcoDir::parse(&dir_buffer).unwrap();
+        assert_eq!(dir.decode_all().err(), error(IconEntryError::InvalidPng));
+    }
+}
@@ -5,18 +5,41 @@
 #[path = "./sys/windows/mod.rs"]
 mod sys;
 
+#[cfg(target_os = "linux")]
+#[path = "./sys/linux/mod.rs"]
+mod sys;
+
 mod icon;
 mod menubuilder;
+mod menudiff;
+mod menulabel;
+mod menumacro;
+#[cfg(feature = "serde")]
+mod menuserde;
+mod menuvalidate;
+mod mock;
+mod trayevent;
 mod trayicon;
 mod trayiconbuilder;
 mod trayiconsender;
 
 // Public api
 pub use crate::icon::Icon;
-pub use crate::menubuilder::{MenuBuilder, MenuItem};
+pub use crate::menubuilder::{MenuBuilder, MenuItem, MenuProvider};
+pub use crate::menudiff::{MenuChange, MenuProperty};
+pub use crate::menulabel::MenuLabel;
+pub use crate::menuvalidate::{MenuDiagnostic, MenuProblem, MAX_MENU_DEPTH};
+pub use crate::mock::{MockCall, MockTrayIcon};
+pub use crate::trayevent::{
+    Capabilities, DeliveryStats, EventInfo, Modifiers, MouseButton, ScrollOrientation, TrayEvent,
+};
 pub use crate::trayicon::TrayIcon;
-pub use crate::trayiconbuilder::Error;
 pub use crate::trayiconbuilder::TrayIconBuilder;
+pub use crate::trayiconbuilder::{Error, IconEntryError};
+pub use crate::trayiconsender::{DeliveryFailure, DeliveryPolicy};
+
+// Bound of the ids for `TrayIconBuilder::build`, it differs by the OS
+pub use crate::sys::BackendId;
 
 // Each OS specific implementation must export following:
 pub(crate) use crate::sys::{
@@ -43,14 +66,36 @@ where
 {
     fn set_icon(&mut self, icon: &Icon) -> Result<(), Error>;
     fn set_menu(&mut self, menu: &MenuBuilder<T>) -> Result<(), Error>;
+
+    /// Update the shown menu to `menu`, `changes` lead to it from the
+    /// previous menu
+    ///
+    /// Implementations that can't change single items replace the menu.
+    fn update_menu(
+        &mut self,
+        menu: &MenuBuilder<T>,
+        changes: &[MenuChange<T>],
+    ) -> Result<(), Error> {
+        let _ = changes;
+        self.set_menu(menu)
+    }
     fn set_tooltip(&mut self, tooltip: &str) -> Result<(), Error>;
+
+    /// Events the host can send
+    fn capabilities(&self) -> Capabilities;
+
+    /// Counts of the events given to the senders
+    fn delivery_stats(&self) -> DeliveryStats;
 }
 
 /// IconSys must implement this
 pub(crate) trait IconBase {
     fn from_buffer(
-        buffer: &'static [u8],
+        buffer: &[u8],
         width: Option<u32>,
         height: Option<u32>,
     ) -> Result<IconSys, Error>;
+
+    /// Pixels are RGBA rows top to bottom, the length is already checked
+    fn from_rgba(width: u32, height: u32, rgba: &[u8]) -> Result<IconSys, Error>;
 }
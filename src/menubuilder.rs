@@ -1,4 +1,7 @@
-use crate::{Error, Icon};
+use crate::trayiconsender::catch_panic;
+use crate::{Error, Icon, MenuChange, MenuLabel};
+use std::fmt::Debug;
+use std::sync::Arc;
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum MenuItem<T>
@@ -8,24 +11,134 @@ where
     Separator,
     Item {
         id: T,
-        name: String,
+        name: MenuLabel,
         disabled: bool,
         icon: Option<Icon>,
     },
     Checkable {
         id: T,
-        name: String,
+        name: MenuLabel,
         is_checked: bool,
         disabled: bool,
         icon: Option<Icon>,
     },
+    /// One choice of a group, selecting it clears the others of the group
+    ///
+    /// Groups are named, items of the same group may be anywhere in the
+    /// menu.
+    Radio {
+        group: String,
+        id: T,
+        name: MenuLabel,
+        is_selected: bool,
+        disabled: bool,
+        icon: Option<Icon>,
+    },
     Submenu {
         id: Option<T>,
-        name: String,
+        name: MenuLabel,
         children: MenuBuilder<T>,
         disabled: bool,
         icon: Option<Icon>,
     },
+    /// Submenu whose items are made by the provider each time right before
+    /// it opens
+    LazySubmenu {
+        id: Option<T>,
+        name: MenuLabel,
+        provider: MenuProvider<T>,
+        disabled: bool,
+        icon: Option<Icon>,
+    },
+}
+
+/// Maker of the items of a lazy submenu
+///
+/// Backends call it from their event thread, so it must be `Send + Sync`.
+/// Providers are equal only if they are clones of each other.
+pub struct MenuProvider<T>(Arc<dyn Fn() -> MenuBuilder<T> + Send + Sync>)
+where
+    T: PartialEq + Clone + 'static;
+
+impl<T> MenuProvider<T>
+where
+    T: PartialEq + Clone + 'static,
+{
+    pub fn new<F>(provider: F) -> MenuProvider<T>
+    where
+        F: Fn() -> MenuBuilder<T> + Send + Sync + 'static,
+    {
+        MenuProvider(Arc::new(provider))
+    }
+
+    /// Items of the submenu as of now
+    pub fn provide(&self) -> MenuBuilder<T> {
+        (self.0)()
+    }
+
+    /// Items of the submenu for the backends, a panic of the provider is
+    /// given to `report` and leaves the submenu empty
+    pub(crate) fn provide_or_report(&self, report: &dyn Fn(&Error)) -> MenuBuilder<T> {
+        catch_panic(|| self.provide()).unwrap_or_else(|error| {
+            report(&error);
+            MenuBuilder::new()
+        })
+    }
+}
+
+impl<T> Clone for MenuProvider<T>
+where
+    T: PartialEq + Clone + 'static,
+{
+    fn clone(&self) -> Self {
+        MenuProvider(self.0.clone())
+    }
+}
+
+impl<T> PartialEq for MenuProvider<T>
+where
+    T: PartialEq + Clone + 'static,
+{
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl<T> Debug for MenuProvider<T>
+where
+    T: PartialEq + Clone + 'static,
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "MenuProvider")
+    }
+}
+
+impl<T> MenuItem<T>
+where
+    T: PartialEq + Clone + 'static,
+{
+    /// Id of the item, separators and submenus without one have none
+    pub fn id(&self) -> Option<&T> {
+        match self {
+            MenuItem::Separator => None,
+            MenuItem::Item { id, .. }
+            | MenuItem::Checkable { id, .. }
+            | MenuItem::Radio { id, .. } => Some(id),
+            MenuItem::Submenu { id, .. } | MenuItem::LazySubmenu { id, .. } => id.as_ref(),
+        }
+    }
+
+    /// Icon shown next to the item
+    pub(crate) fn icon(&self) -> Option<&Icon> {
+        match self {
+            MenuItem::Separator => None,
+            MenuItem::Item { icon, .. }
+            | MenuItem::Checkable { icon, .. }
+            | MenuItem::Radio { icon, .. }
+            | MenuItem::Submenu { icon, .. }
+            | MenuItem::LazySubmenu { icon, .. } => icon.as_ref(),
+        }
+    }
 }
 
 #[derive(Debug, Default, Clone, PartialEq)]
@@ -50,6 +163,8 @@ where
     }
 
     /// Conditionally include items, poor mans function composition
+    ///
+    /// The `if` entries of `menu!` read better for longer menus.
     pub fn when<F>(self, f: F) -> Self
     where
         F: FnOnce(Self) -> Self,
@@ -67,20 +182,39 @@ where
         self
     }
 
-    pub fn item(mut self, name: &str, id: T) -> Self {
+    pub fn item<L>(mut self, name: L, id: T) -> Self
+    where
+        L: Into<MenuLabel>,
+    {
         self.menu_items.push(MenuItem::Item {
             id,
-            name: name.to_string(),
+            name: name.into(),
             disabled: false,
             icon: None,
         });
         self
     }
 
-    pub fn checkable(mut self, name: &str, is_checked: bool, id: T) -> Self {
+    pub fn item_with_icon<L>(mut self, name: L, icon: Icon, id: T) -> Self
+    where
+        L: Into<MenuLabel>,
+    {
+        self.menu_items.push(MenuItem::Item {
+            id,
+            name: name.into(),
+            disabled: false,
+            icon: Some(icon),
+        });
+        self
+    }
+
+    pub fn checkable<L>(mut self, name: L, is_checked: bool, id: T) -> Self
+    where
+        L: Into<MenuLabel>,
+    {
         self.menu_items.push(MenuItem::Checkable {
             id,
-            name: name.to_string(),
+            name: name.into(),
             is_checked,
             disabled: false,
             icon: None,
@@ -88,12 +222,74 @@ where
         self
     }
 
-    pub fn submenu(mut self, name: &str, menu: MenuBuilder<T>) -> Self {
+    pub fn radio<L>(mut self, name: L, group: &str, is_selected: bool, id: T) -> Self
+    where
+        L: Into<MenuLabel>,
+    {
+        self.menu_items.push(MenuItem::Radio {
+            group: group.to_string(),
+            id,
+            name: name.into(),
+            is_selected,
+            disabled: false,
+            icon: None,
+        });
+        self
+    }
+
+    /// Radio items of a group, the one with id `selected` is selected
+    pub fn radio_group<L>(self, group: &str, items: &[(L, T)], selected: &T) -> Self
+    where
+        L: Into<MenuLabel> + Clone,
+    {
+        items.iter().fold(self, |menu, (name, id)| {
+            menu.radio(name.clone(), group, id == selected, id.clone())
+        })
+    }
+
+    pub fn submenu<L>(mut self, name: L, menu: MenuBuilder<T>) -> Self
+    where
+        L: Into<MenuLabel>,
+    {
+        self.menu_items.push(MenuItem::Submenu {
+            id: None,
+            name: name.into(),
+            children: menu,
+            disabled: false,
+            icon: None,
+        });
+        self
+    }
+
+    pub fn submenu_with_icon<L>(mut self, name: L, icon: Icon, menu: MenuBuilder<T>) -> Self
+    where
+        L: Into<MenuLabel>,
+    {
         self.menu_items.push(MenuItem::Submenu {
             id: None,
-            name: name.to_string(),
+            name: name.into(),
             children: menu,
             disabled: false,
+            icon: Some(icon),
+        });
+        self
+    }
+
+    /// Submenu with items from `provider`, made each time the submenu opens
+    ///
+    /// Use this for items that are expensive to make or change on their
+    /// own, like recent files or found devices. If `provider` panics the
+    /// submenu is empty and `on_error` gets `Error::CallbackPanicked`.
+    pub fn lazy_submenu<L, F>(mut self, name: L, provider: F) -> Self
+    where
+        L: Into<MenuLabel>,
+        F: Fn() -> MenuBuilder<T> + Send + Sync + 'static,
+    {
+        self.menu_items.push(MenuItem::LazySubmenu {
+            id: None,
+            name: name.into(),
+            provider: MenuProvider::new(provider),
+            disabled: false,
             icon: None,
         });
         self
@@ -103,96 +299,164 @@ where
         crate::build_menu(self)
     }
 
+    /// First item with the id, submenus included
+    pub fn find(&self, id: &T) -> Option<&MenuItem<T>> {
+        self.iter()
+            .find(|(_, item)| item.id() == Some(id))
+            .map(|(_, item)| item)
+    }
+
+    /// First item with the id for changing it, submenus included
+    pub fn find_mut(&mut self, id: &T) -> Option<&mut MenuItem<T>> {
+        let path = self.path_of(id)?;
+        self.item_at_mut(&path)
+    }
+
+    /// Path of the first item with the id
+    ///
+    /// Path has the index of the item in each menu from the top, see `iter`.
+    pub fn path_of(&self, id: &T) -> Option<Vec<usize>> {
+        self.iter()
+            .find(|(_, item)| item.id() == Some(id))
+            .map(|(path, _)| path)
+    }
+
+    /// All items depth first with their paths
+    ///
+    /// Item at path `[2, 0]` is the first item of the submenu that is the
+    /// third item of this menu. Submenus come before their items. Nested
+    /// menus are walked with a stack, not recursion.
+    pub fn iter(&self) -> impl Iterator<Item = (Vec<usize>, &MenuItem<T>)> + '_ {
+        let mut stack = vec![(vec![], self.menu_items.iter().enumerate())];
+        std::iter::from_fn(move || loop {
+            let (parent, items) = stack.last_mut()?;
+            match items.next() {
+                Some((i, item)) => {
+                    let mut path = parent.clone();
+                    path.push(i);
+                    if let MenuItem::Submenu { children, .. } = item {
+                        stack.push((path.clone(), children.menu_items.iter().enumerate()));
+                    }
+                    return Some((path, item));
+                }
+                None => {
+                    stack.pop();
+                }
+            }
+        })
+    }
+
+    /// Changes that turn this menu into `new`
+    ///
+    /// Backends use these to update only the changed items of a shown menu.
+    pub fn diff(&self, new: &MenuBuilder<T>) -> Vec<MenuChange<T>> {
+        crate::menudiff::diff(self, new)
+    }
+
+    #[cfg(windows)]
+    pub(crate) fn item_at(&self, path: &[usize]) -> Option<&MenuItem<T>> {
+        let (last, parents) = path.split_last()?;
+        let mut menu = self;
+        for &i in parents {
+            menu = match menu.menu_items.get(i)? {
+                MenuItem::Submenu { children, .. } => children,
+                _ => return None,
+            };
+        }
+        menu.menu_items.get(*last)
+    }
+
+    fn item_at_mut(&mut self, path: &[usize]) -> Option<&mut MenuItem<T>> {
+        let (last, parents) = path.split_last()?;
+        let mut menu = self;
+        for &i in parents {
+            menu = match menu.menu_items.get_mut(i)? {
+                MenuItem::Submenu { children, .. } => children,
+                _ => return None,
+            };
+        }
+        menu.menu_items.get_mut(*last)
+    }
+
     /// Get checkable state, if found.
     ///
     /// Prefer maintaining proper application state instead of getting checkable
     /// state with this method.
-    pub(crate) fn get_checkable(&mut self, find_id: T) -> Option<bool> {
-        let mut found_item = None;
-        let _ = self.mutate_item(find_id, |i| {
-            if let MenuItem::Checkable { is_checked, .. } = i {
-                found_item = Some(*is_checked);
-                Ok(())
-            } else {
-                Err(Error::MenuItemNotFound)
-            }
-        });
-        found_item
+    pub(crate) fn get_checkable(&self, find_id: T) -> Option<bool> {
+        match self.find(&find_id) {
+            Some(MenuItem::Checkable { is_checked, .. }) => Some(*is_checked),
+            _ => None,
+        }
     }
 
     /// Set checkable
     ///
     /// Prefer building a new menu instead of mutating it with this method.
-    pub(crate) fn set_checkable(&mut self, id: T, checked: bool) -> Result<(), Error> {
-        self.mutate_item(id, |i| {
-            if let MenuItem::Checkable { is_checked, .. } = i {
+    /// Returns false if the menu has no checkable item with the id.
+    pub(crate) fn set_checkable(&mut self, id: T, checked: bool) -> bool {
+        match self.find_mut(&id) {
+            Some(MenuItem::Checkable { is_checked, .. }) => {
                 *is_checked = checked;
-                Ok(())
-            } else {
-                Err(Error::MenuItemNotFound)
+                true
             }
-        })
+            _ => false,
+        }
     }
 
-    /// Set disabled state
-    ///
-    /// Prefer building a new menu instead of mutating it with this method.
-    pub(crate) fn set_disabled(&mut self, id: T, disabled: bool) -> Result<(), Error> {
-        self.mutate_item(id, |i| match i {
-            MenuItem::Item { disabled: d, .. } => {
-                *d = disabled;
-                Ok(())
-            }
-            MenuItem::Checkable { disabled: d, .. } => {
-                *d = disabled;
-                Ok(())
-            }
-            MenuItem::Submenu { disabled: d, .. } => {
-                *d = disabled;
-                Ok(())
-            }
-            MenuItem::Separator => Err(Error::MenuItemNotFound),
+    /// Selected id of the radio group, if any
+    pub(crate) fn get_radio(&self, find_group: &str) -> Option<T> {
+        self.iter().find_map(|(_, item)| match item {
+            MenuItem::Radio {
+                group,
+                id,
+                is_selected: true,
+                ..
+            } if group == find_group => Some(id.clone()),
+            _ => None,
         })
     }
 
-    /// Find item and optionally mutate
+    /// Select radio item, the others of its group are cleared
     ///
-    /// Recursively searches for item with id, and applies function f to item if
-    /// found. There is no recursion depth limitation and may cause stack
-    /// issues.
-    fn mutate_item<F>(&mut self, id: T, f: F) -> Result<(), Error>
-    where
-        F: FnOnce(&mut MenuItem<T>) -> Result<(), Error>,
-    {
-        self._mutate_item_recurse_ref(id, f)
+    /// Returns false if the menu has no radio item with the id.
+    pub(crate) fn set_radio(&mut self, find_id: T) -> bool {
+        let found_group = match self.find(&find_id) {
+            Some(MenuItem::Radio { group, .. }) => group.clone(),
+            _ => return false,
+        };
+        let paths = self
+            .iter()
+            .filter(
+                |(_, item)| matches!(item, MenuItem::Radio { group, .. } if *group == found_group),
+            )
+            .map(|(path, _)| path)
+            .collect::<Vec<_>>();
+        for path in paths {
+            if let Some(MenuItem::Radio {
+                id, is_selected, ..
+            }) = self.item_at_mut(&path)
+            {
+                *is_selected = *id == find_id;
+            }
+        }
+        true
     }
 
-    fn _mutate_item_recurse_ref<F>(&mut self, find_id: T, f: F) -> Result<(), Error>
-    where
-        F: FnOnce(&mut MenuItem<T>) -> Result<(), Error>,
-    {
-        let found_item = self.menu_items.iter_mut().find(|f| match f {
-            MenuItem::Item { id, .. } if id == &find_id => true,
-            MenuItem::Checkable { id, .. } if id == &find_id => true,
-            MenuItem::Submenu { id, .. } if id.as_ref() == Some(&find_id) => true,
-            _ => false,
-        });
-
-        if let Some(item) = found_item {
-            f(item)
-        } else {
-            // Try to find submenu
-            let maybe_found_submenu = self
-                .menu_items
-                .iter_mut()
-                .find(|i| matches!(i, MenuItem::Submenu { .. }));
-
-            // Reurse
-            if let Some(MenuItem::Submenu { children, .. }) = maybe_found_submenu {
-                return children._mutate_item_recurse_ref(find_id, f);
+    /// Set disabled state
+    ///
+    /// Prefer building a new menu instead of mutating it with this method.
+    /// Returns false if the menu has no item with the id.
+    pub(crate) fn set_disabled(&mut self, id: T, disabled: bool) -> bool {
+        match self.find_mut(&id) {
+            Some(MenuItem::Item { disabled: d, .. })
+            | Some(MenuItem::Checkable { disabled: d, .. })
+            | Some(MenuItem::Radio { disabled: d, .. })
+            | Some(MenuItem::Submenu { disabled: d, .. })
+            | Some(MenuItem::LazySubmenu { disabled: d, .. }) => {
+                *d = disabled;
+                true
             }
-
-            Err(Error::MenuItemNotFound)
+            _ => false,
         }
     }
 }
@@ -243,9 +507,119 @@ pub(crate) mod tests {
         };
 
         let mut old = menu_builder(false, false);
-        let _ = old.set_checkable(Events::CheckItem1, true);
-        let _ = old.set_disabled(Events::DisabledItem1, true);
-        let _ = old.set_checkable(Events::CheckItem2, true);
+        old.set_checkable(Events::CheckItem1, true);
+        old.set_disabled(Events::DisabledItem1, true);
+        old.set_checkable(Events::CheckItem2, true);
         assert_eq!(old, menu_builder(true, true));
     }
+
+    #[test]
+    fn test_radio_group() {
+        let modes = [
+            ("Eco", Events::Item1),
+            ("Balanced", Events::Item2),
+            ("Performance", Events::Item3),
+        ];
+        let mut menu = MenuBuilder::new()
+            .radio_group("mode", &modes[..2], &Events::Item2)
+            .submenu(
+                "More",
+                MenuBuilder::new().radio_group("mode", &modes[2..], &Events::Item1),
+            )
+            .radio("Other", "other", true, Events::Item4);
+        assert_eq!(menu.get_radio("mode"), Some(Events::Item2));
+
+        // Selection clears the group in submenus too, but not other groups
+        assert!(menu.set_radio(Events::Item3));
+        assert_eq!(menu.get_radio("mode"), Some(Events::Item3));
+        assert_eq!(menu.get_radio("other"), Some(Events::Item4));
+        assert_eq!(
+            menu,
+            MenuBuilder::new()
+                .radio_group("mode", &modes[..2], &Events::Item3)
+                .submenu(
+                    "More",
+                    MenuBuilder::new().radio_group("mode", &modes[2..], &Events::Item3),
+                )
+                .radio("Other", "other", true, Events::Item4)
+        );
+
+        assert!(!menu.set_radio(Events::CheckItem1));
+    }
+
+    #[test]
+    fn test_find_in_sibling_submenus() {
+        let mut menu = MenuBuilder::new()
+            .item("Item 1", Events::Item1)
+            .submenu(
+                "First",
+                MenuBuilder::new().checkable("Check 1", false, Events::CheckItem1),
+            )
+            .submenu(
+                "Second",
+                MenuBuilder::new().item("Sub 1", Events::SubItem1).submenu(
+                    "Third",
+                    MenuBuilder::new().checkable("Check 2", false, Events::CheckItem2),
+                ),
+            );
+        assert_eq!(menu.path_of(&Events::Item1), Some(vec![0]));
+        assert_eq!(menu.path_of(&Events::SubItem1), Some(vec![2, 0]));
+        assert_eq!(menu.path_of(&Events::CheckItem2), Some(vec![2, 1, 0]));
+        assert_eq!(menu.path_of(&Events::Item2), None);
+        assert_eq!(
+            menu.iter().map(|(path, _)| path).collect::<Vec<_>>(),
+            vec![
+                vec![0],
+                vec![1],
+                vec![1, 0],
+                vec![2],
+                vec![2, 0],
+                vec![2, 1],
+                vec![2, 1, 0]
+            ]
+        );
+
+        // Items after the first submenu are found too
+        assert!(menu.set_checkable(Events::CheckItem2, true));
+        assert!(menu.set_disabled(Events::SubItem1, true));
+        assert_eq!(menu.get_checkable(Events::CheckItem2), Some(true));
+        assert_eq!(menu.get_checkable(Events::CheckItem1), Some(false));
+        assert!(matches!(
+            menu.find(&Events::SubItem1),
+            Some(MenuItem::Item { disabled: true, .. })
+        ));
+        if let Some(MenuItem::Item { name, .. }) = menu.find_mut(&Events::Item1) {
+            *name = "Renamed".into();
+        }
+        assert_eq!(
+            menu.menu_items[0],
+            MenuItem::Item {
+                id: Events::Item1,
+                name: "Renamed".into(),
+                disabled: false,
+                icon: None,
+            }
+        );
+        assert!(!menu.set_checkable(Events::SubItem1, true));
+    }
+
+    #[test]
+    fn test_find_deeply_nested() {
+        let depth = 500;
+        let mut menu = MenuBuilder::new().item("Deepest", Events::SubItem3);
+        for _ in 0..depth {
+            menu = MenuBuilder::new()
+                .item("Sibling", Events::Item1)
+                .submenu("Deeper", menu);
+        }
+        let path = menu.path_of(&Events::SubItem3).unwrap();
+        assert_eq!(path.len(), depth + 1);
+        assert!(path[..depth].iter().all(|&i| i == 1));
+        assert_eq!(menu.iter().count(), depth * 2 + 1);
+        assert!(menu.set_disabled(Events::SubItem3, true));
+        assert!(matches!(
+            menu.find(&Events::SubItem3),
+            Some(MenuItem::Item { disabled: true, .. })
+        ));
+    }
 }
@@ -0,0 +1,443 @@
+use crate::{Icon, MenuBuilder, MenuItem, MenuLabel};
+
+/// Change that turns one menu into another, see `MenuBuilder::diff`
+///
+/// Paths are like the ones of `MenuBuilder::iter`. Paths of `Remove` and
+/// `Move::from` point into the old menu, all the others into the new menu.
+#[derive(Debug, Clone, PartialEq)]
+pub enum MenuChange<T>
+where
+    T: PartialEq + Clone + 'static,
+{
+    /// Item of the old menu is gone
+    Remove { path: Vec<usize> },
+
+    /// Item is new, with its submenu if it has one
+    Insert { path: Vec<usize>, item: MenuItem<T> },
+
+    /// Item moved within its menu
+    Move { from: Vec<usize>, to: Vec<usize> },
+
+    /// Property of an item changed
+    Update {
+        path: Vec<usize>,
+        property: MenuProperty,
+    },
+}
+
+/// Property of a menu item that can change in place
+#[derive(Debug, Clone, PartialEq)]
+pub enum MenuProperty {
+    Label(MenuLabel),
+
+    /// Checkmark of a checkable item, or selection of a radio item
+    Checked(bool),
+    Disabled(bool),
+    Icon(Option<Icon>),
+}
+
+impl<T> MenuChange<T>
+where
+    T: PartialEq + Clone + 'static,
+{
+    /// Whether the change leaves the items where they are
+    pub fn is_update(&self) -> bool {
+        matches!(self, MenuChange::Update { .. })
+    }
+}
+
+/// Changes between the menus
+///
+/// Items are paired by their ids, items without an id by their kind and
+/// label in order. Items whose kind, radio group or submenu provider changed
+/// are removed and inserted. Of the paired items the longest run already in
+/// order stays, the others are moved. Nested menus are walked with a stack.
+pub(crate) fn diff<T>(old: &MenuBuilder<T>, new: &MenuBuilder<T>) -> Vec<MenuChange<T>>
+where
+    T: PartialEq + Clone + 'static,
+{
+    let mut changes = vec![];
+    let mut stack = vec![(vec![], vec![], old, new)];
+    while let Some((old_path, new_path, old, new)) = stack.pop() {
+        let old_items = &old.menu_items;
+        let new_items = &new.menu_items;
+
+        // Pair new items with the first unpaired old item that matches
+        let mut paired = vec![false; old_items.len()];
+        let pairs = new_items
+            .iter()
+            .map(|n| {
+                let i = (0..old_items.len()).find(|&i| !paired[i] && same_item(&old_items[i], n));
+                if let Some(i) = i {
+                    paired[i] = true;
+                }
+                i
+            })
+            .collect::<Vec<_>>();
+        let staying = longest_increasing(&pairs.iter().flatten().copied().collect::<Vec<_>>());
+        let path = |parent: &[usize], i: usize| {
+            let mut path = parent.to_vec();
+            path.push(i);
+            path
+        };
+
+        changes.extend(
+            (0..old_items.len())
+                .filter(|&i| !paired[i])
+                .map(|i| MenuChange::Remove {
+                    path: path(&old_path, i),
+                }),
+        );
+        let mut submenus = vec![];
+        for (j, pair) in pairs.iter().enumerate() {
+            let i = match *pair {
+                Some(i) => i,
+                None => {
+                    changes.push(MenuChange::Insert {
+                        path: path(&new_path, j),
+                        item: new_items[j].clone(),
+                    });
+                    continue;
+                }
+            };
+            if !staying.contains(&i) {
+                changes.push(MenuChange::Move {
+                    from: path(&old_path, i),
+                    to: path(&new_path, j),
+                });
+            }
+            changes.extend(
+                changed_properties(&old_items[i], &new_items[j])
+                    .into_iter()
+                    .map(|property| MenuChange::Update {
+                        path: path(&new_path, j),
+                        property,
+                    }),
+            );
+            if let (MenuItem::Submenu { children: o, .. }, MenuItem::Submenu { children: n, .. }) =
+                (&old_items[i], &new_items[j])
+            {
+                submenus.push((path(&old_path, i), path(&new_path, j), o, n));
+            }
+        }
+
+        // Reversed so the first submenu is walked first
+        stack.extend(submenus.into_iter().rev());
+    }
+    changes
+}
+
+/// Same item, maybe with other properties
+fn same_item<T>(old: &MenuItem<T>, new: &MenuItem<T>) -> bool
+where
+    T: PartialEq + Clone + 'static,
+{
+    match (old, new) {
+        (MenuItem::Separator, MenuItem::Separator) => true,
+        (MenuItem::Item { id: a, .. }, MenuItem::Item { id: b, .. })
+        | (MenuItem::Checkable { id: a, .. }, MenuItem::Checkable { id: b, .. }) => a == b,
+        (
+            MenuItem::Radio {
+                id: a, group: ga, ..
+            },
+            MenuItem::Radio {
+                id: b, group: gb, ..
+            },
+        ) => a == b && ga == gb,
+        (MenuItem::Submenu { id: Some(a), .. }, MenuItem::Submenu { id: Some(b), .. }) => a == b,
+        (
+            MenuItem::Submenu {
+                id: None, name: a, ..
+            },
+            MenuItem::Submenu {
+                id: None, name: b, ..
+            },
+        ) => a.text() == b.text(),
+
+        // Other provider makes other items, so the submenu is replaced
+        (
+            MenuItem::LazySubmenu {
+                id: a,
+                name: na,
+                provider: pa,
+                ..
+            },
+            MenuItem::LazySubmenu {
+                id: b,
+                name: nb,
+                provider: pb,
+                ..
+            },
+        ) => pa == pb && a == b && (a.is_some() || na.text() == nb.text()),
+        _ => false,
+    }
+}
+
+fn changed_properties<T>(old: &MenuItem<T>, new: &MenuItem<T>) -> Vec<MenuProperty>
+where
+    T: PartialEq + Clone + 'static,
+{
+    let properties = |item: &MenuItem<T>| match item {
+        MenuItem::Separator => None,
+        MenuItem::Item {
+            name,
+            disabled,
+            icon,
+            ..
+        }
+        | MenuItem::Submenu {
+            name,
+            disabled,
+            icon,
+            ..
+        }
+        | MenuItem::LazySubmenu {
+            name,
+            disabled,
+            icon,
+            ..
+        } => Some((name.clone(), None, *disabled, icon.clone())),
+        MenuItem::Checkable {
+            name,
+            is_checked: checked,
+            disabled,
+            icon,
+            ..
+        }
+        | MenuItem::Radio {
+            name,
+            is_selected: checked,
+            disabled,
+            icon,
+            ..
+        } => Some((name.clone(), Some(*checked), *disabled, icon.clone())),
+    };
+    let (old, new) = match (properties(old), properties(new)) {
+        (Some(old), Some(new)) => (old, new),
+        _ => return vec![],
+    };
+    let mut changed = vec![];
+    if old.0 != new.0 {
+        changed.push(MenuProperty::Label(new.0));
+    }
+    if let (Some(old), Some(new)) = (old.1, new.1) {
+        if old != new {
+            changed.push(MenuProperty::Checked(new));
+        }
+    }
+    if old.2 != new.2 {
+        changed.push(MenuProperty::Disabled(new.2));
+    }
+    if old.3 != new.3 {
+        changed.push(MenuProperty::Icon(new.3));
+    }
+    changed
+}
+
+/// Values of the longest strictly increasing subsequence
+fn longest_increasing(values: &[usize]) -> Vec<usize> {
+    // tails[k] is the index of the smallest tail of increasing runs of
+    // length k + 1, previous[i] is the index before i in its run
+    let mut tails: Vec<usize> = vec![];
+    let mut previous = vec![None; values.len()];
+    for (i, &value) in values.iter().enumerate() {
+        let k = tails.partition_point(|&t| values[t] < value);
+        previous[i] = k.checked_sub(1).map(|k| tails[k]);
+        if k == tails.len() {
+            tails.push(i);
+        } else {
+            tails[k] = i;
+        }
+    }
+    let mut run = vec![];
+    let mut at = tails.last().copied();
+    while let Some(i) = at {
+        run.push(values[i]);
+        at = previous[i];
+    }
+    run.reverse();
+    run
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Copy, Clone, Eq, PartialEq, Debug)]
+    enum Events {
+        A,
+        B,
+        C,
+        D,
+        E,
+        Sub,
+        SubA,
+        SubB,
+    }
+
+    /// Apply the changes to the old menu, the way the paths are defined
+    fn apply(old: &MenuBuilder<Events>, changes: &[MenuChange<Events>]) -> MenuBuilder<Events> {
+        apply_level(old, &[], &[], changes)
+    }
+
+    fn apply_level(
+        old: &MenuBuilder<Events>,
+        old_path: &[usize],
+        new_path: &[usize],
+        changes: &[MenuChange<Events>],
+    ) -> MenuBuilder<Events> {
+        let child = |path: &[usize], parent: &[usize]| match path.split_last() {
+            Some((i, p)) if p == parent => Some(*i),
+            _ => None,
+        };
+        let mut removed = vec![];
+        let mut moved = vec![];
+        let mut inserted = vec![];
+        for change in changes {
+            match change {
+                MenuChange::Remove { path } => removed.extend(child(path, old_path)),
+                MenuChange::Move { from, to } => {
+                    if let (Some(i), Some(j)) = (child(from, old_path), child(to, new_path)) {
+                        moved.push((i, j));
+                    }
+                }
+                MenuChange::Insert { path, item } => {
+                    inserted.extend(child(path, new_path).map(|j| (j, item.clone())))
+                }
+                MenuChange::Update { .. } => {}
+            }
+        }
+        let mut staying = (0..old.menu_items.len())
+            .filter(|i| !removed.contains(i) && !moved.iter().any(|(m, _)| m == i));
+        let len = old.menu_items.len() - removed.len() + inserted.len();
+        let mut menu = MenuBuilder::new();
+        for j in 0..len {
+            if let Some((_, item)) = inserted.iter().find(|(k, _)| *k == j) {
+                menu = menu.with(item.clone());
+                continue;
+            }
+            let i = match moved.iter().find(|(_, k)| *k == j) {
+                Some((i, _)) => *i,
+                None => staying.next().unwrap(),
+            };
+            let mut item = old.menu_items[i].clone();
+            let mut path = new_path.to_vec();
+            path.push(j);
+            if let MenuItem::Submenu { children, .. } = &mut item {
+                let mut from = old_path.to_vec();
+                from.push(i);
+                *children = apply_level(children, &from, &path, changes);
+            }
+            for change in changes {
+                match change {
+                    MenuChange::Update { path: p, property } if *p == path => {
+                        set_property(&mut item, property.clone())
+                    }
+                    _ => {}
+                }
+            }
+            menu = menu.with(item);
+        }
+        menu
+    }
+
+    fn set_property(item: &mut MenuItem<Events>, property: MenuProperty) {
+        match (item, property) {
+            (MenuItem::Item { name, .. }, MenuProperty::Label(label))
+            | (MenuItem::Checkable { name, .. }, MenuProperty::Label(label))
+            | (MenuItem::Radio { name, .. }, MenuProperty::Label(label))
+            | (MenuItem::Submenu { name, .. }, MenuProperty::Label(label)) => *name = label,
+            (MenuItem::Checkable { is_checked: c, .. }, MenuProperty::Checked(checked))
+            | (MenuItem::Radio { is_selected: c, .. }, MenuProperty::Checked(checked)) => {
+                *c = checked
+            }
+            (MenuItem::Item { disabled: d, .. }, MenuProperty::Disabled(disabled))
+            | (MenuItem::Checkable { disabled: d, .. }, MenuProperty::Disabled(disabled))
+            | (MenuItem::Radio { disabled: d, .. }, MenuProperty::Disabled(disabled))
+            | (MenuItem::Submenu { disabled: d, .. }, MenuProperty::Disabled(disabled)) => {
+                *d = disabled
+            }
+            (MenuItem::Item { icon: i, .. }, MenuProperty::Icon(icon))
+            | (MenuItem::Checkable { icon: i, .. }, MenuProperty::Icon(icon))
+            | (MenuItem::Radio { icon: i, .. }, MenuProperty::Icon(icon))
+            | (MenuItem::Submenu { icon: i, .. }, MenuProperty::Icon(icon)) => *i = icon,
+            (_, property) => panic!("{:?} doesn't apply", property),
+        }
+    }
+
+    fn menu(order: &[Events], checked: bool) -> MenuBuilder<Events> {
+        order
+            .iter()
+            .fold(MenuBuilder::new(), |menu, e| match e {
+                Events::Sub => menu.submenu(
+                    "Sub",
+                    MenuBuilder::new()
+                        .item("Sub A", Events::SubA)
+                        .separator()
+                        .checkable("Sub B", checked, Events::SubB),
+                ),
+                e => menu.item(format!("{:?}", e), *e),
+            })
+            .separator()
+    }
+
+    #[test]
+    fn test_longest_increasing() {
+        assert_eq!(longest_increasing(&[]), Vec::<usize>::new());
+        assert_eq!(longest_increasing(&[1, 2, 3, 0]), vec![1, 2, 3]);
+        assert_eq!(longest_increasing(&[3, 0, 4, 1, 2]), vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn test_diff() {
+        use Events::*;
+        let old = menu(&[A, B, Sub, C, D], false);
+        assert_eq!(old.diff(&old), vec![]);
+
+        // Moving one item is one change
+        let new = menu(&[B, Sub, C, D, A], false);
+        assert_eq!(
+            old.diff(&new),
+            vec![MenuChange::Move {
+                from: vec![0],
+                to: vec![4]
+            }]
+        );
+
+        // Property changes keep the places
+        let mut new = menu(&[A, B, Sub, C, D], true);
+        assert!(new.set_disabled(A, true));
+        assert_eq!(
+            old.diff(&new),
+            vec![
+                MenuChange::Update {
+                    path: vec![0],
+                    property: MenuProperty::Disabled(true)
+                },
+                MenuChange::Update {
+                    path: vec![2, 2],
+                    property: MenuProperty::Checked(true)
+                },
+            ]
+        );
+        assert!(old.diff(&new).iter().all(MenuChange::is_update));
+
+        let pairs = [
+            (menu(&[A, B, C], false), menu(&[C, E, A], false)),
+            (menu(&[Sub, A, B], false), menu(&[B, A, Sub], true)),
+            (menu(&[A, Sub, D], false), menu(&[D, A], false)),
+            (menu(&[], false), menu(&[Sub, A, B, C], false)),
+            (
+                menu(&[A, B], false),
+                MenuBuilder::new()
+                    .checkable("A", true, A)
+                    .item(MenuLabel::new("B").with_mnemonic('b'), B)
+                    .separator(),
+            ),
+        ];
+        for (old, new) in pairs.iter() {
+            assert_eq!(apply(old, &old.diff(new)), *new);
+            assert_eq!(apply(new, &new.diff(old)), *old);
+        }
+    }
+}
@@ -0,0 +1,118 @@
+/// Label of a menu item
+///
+/// The text is shown as is, `&` and `_` in it are never taken as mnemonic
+/// markers. Mnemonic and accelerator are given separately, and each backend
+/// marks them the way its menus expect.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct MenuLabel {
+    text: String,
+
+    /// Byte offset of the mnemonic character in the text
+    mnemonic: Option<usize>,
+    accelerator: Option<String>,
+}
+
+impl MenuLabel {
+    pub fn new(text: &str) -> MenuLabel {
+        MenuLabel {
+            text: text.to_string(),
+            mnemonic: None,
+            accelerator: None,
+        }
+    }
+
+    /// Mnemonic is the first character of the text matching `c`, ignoring
+    /// case. Without a match there is no mnemonic.
+    pub fn with_mnemonic(mut self, c: char) -> Self {
+        let c = c.to_lowercase().collect::<String>();
+        self.mnemonic = self
+            .text
+            .char_indices()
+            .find(|(_, t)| t.to_lowercase().eq(c.chars()))
+            .map(|(i, _)| i);
+        self
+    }
+
+    /// Accelerator shown next to the text, e.g. "Ctrl+Q"
+    ///
+    /// This is only shown, the application handles the keys itself.
+    pub fn with_accelerator(mut self, accelerator: &str) -> Self {
+        self.accelerator = Some(accelerator.to_string());
+        self
+    }
+
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    pub fn mnemonic(&self) -> Option<char> {
+        self.mnemonic.and_then(|i| self.text[i..].chars().next())
+    }
+
+    pub fn accelerator(&self) -> Option<&str> {
+        self.accelerator.as_deref()
+    }
+
+    /// Byte offset of the mnemonic character in the text
+    pub(crate) fn mnemonic_index(&self) -> Option<usize> {
+        self.mnemonic
+    }
+
+    /// Text with `marker` before the mnemonic, and literal markers doubled
+    pub(crate) fn marked(&self, marker: char) -> String {
+        let mut marked = String::with_capacity(self.text.len() + 1);
+        for (i, c) in self.text.char_indices() {
+            if Some(i) == self.mnemonic || c == marker {
+                marked.push(marker);
+            }
+            marked.push(c);
+        }
+        marked
+    }
+}
+
+impl From<&str> for MenuLabel {
+    fn from(text: &str) -> Self {
+        MenuLabel::new(text)
+    }
+}
+
+impl From<String> for MenuLabel {
+    fn from(text: String) -> Self {
+        MenuLabel {
+            text,
+            mnemonic: None,
+            accelerator: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_marked() {
+        let exit = MenuLabel::new("Exit").with_mnemonic('X');
+        assert_eq!(exit.mnemonic(), Some('x'));
+        assert_eq!(exit.marked('&'), "E&xit");
+        assert_eq!(exit.marked('_'), "E_xit");
+
+        // Literal markers are escaped, also next to the mnemonic
+        let file = MenuLabel::new("R&D_notes.txt").with_mnemonic('d');
+        assert_eq!(file.marked('&'), "R&&&D_notes.txt");
+        assert_eq!(file.marked('_'), "R&_D__notes.txt");
+
+        let plain = MenuLabel::from("Äpple & päron".to_string()).with_mnemonic('q');
+        assert_eq!(plain.mnemonic(), None);
+        assert_eq!(plain.marked('&'), "Äpple && päron");
+        assert_eq!(
+            MenuLabel::new("Äpple").with_mnemonic('ä').mnemonic_index(),
+            Some(0)
+        );
+        assert_eq!(
+            MenuLabel::new("päron").with_mnemonic('r').mnemonic_index(),
+            Some(3)
+        );
+    }
+}
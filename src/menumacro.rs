@@ -0,0 +1,328 @@
+/// Build a `MenuBuilder` from a list of entries
+///
+/// Entries are named like the builder methods, and separated by commas:
+///
+/// - `item(label, id)`
+/// - `checkable(label, is_checked, id)`
+/// - `radio(label, group, is_selected, id)`
+/// - `separator`
+/// - `submenu(label) [entries]` or `submenu(label, id) [entries]`
+/// - `lazy_submenu(label, provider)` or `lazy_submenu(label, id, provider)`
+/// - `if (condition) [entries]`, optionally followed by `else [entries]`
+///
+/// Items and submenus take modifiers after them: `.disabled()`,
+/// `.disabled(bool)` and `.icon(icon)`.
+///
+/// ```
+/// # use trayicon::{menu, MenuBuilder};
+/// # #[derive(Clone, PartialEq)]
+/// # enum Events { Open, Save, Dark, About, LogOut }
+/// # let (saved, dark, logged_in) = (true, false, true);
+/// let menu: MenuBuilder<Events> = menu![
+///     item("Open", Events::Open),
+///     item("Save", Events::Save).disabled(saved),
+///     checkable("Dark mode", dark, Events::Dark),
+///     separator,
+///     submenu("Help") [
+///         item("About", Events::About),
+///     ],
+///     if (logged_in) [
+///         item("Log out", Events::LogOut),
+///     ],
+/// ];
+/// ```
+///
+/// Entries are expanded side by side, so long menus don't run into the
+/// recursion limit of macros, only deep nesting does.
+#[macro_export]
+macro_rules! menu {
+    ($($entries:tt)*) => {{
+        #[allow(unused_mut)]
+        let mut menu = $crate::MenuBuilder::new();
+        $crate::__menu_entries!(menu; $($entries)*);
+        menu
+    }};
+}
+
+/// Assign each entry to the menu in turn
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __menu_entries {
+    ($menu:ident;
+        $(
+            $kind:ident
+            $(( $($args:tt)* ))?
+            $(. $modifier:ident ( $($modifier_args:tt)* ))*
+            $([ $($entries:tt)* ])?
+            $(else [ $($else_entries:tt)* ])?
+        ),* $(,)?
+    ) => {
+        $(
+            $menu = $crate::__menu_entry!(
+                $menu;
+                $kind ($($($args)*)?)
+                [$($modifier ($($modifier_args)*))*]
+                [$($($entries)*)?]
+                [$($($else_entries)*)?]
+            );
+        )*
+    };
+}
+
+/// Menu with the entry added
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __menu_entry {
+    ($menu:ident; separator () [] [] []) => {
+        $menu.separator()
+    };
+    ($menu:ident; item ($name:expr, $id:expr $(,)?) $modifiers:tt [] []) => {
+        $crate::__menu_modifiers!(
+            $menu,
+            $crate::MenuItem::Item {
+                id: $id,
+                name: $crate::MenuLabel::from($name),
+                disabled: false,
+                icon: None,
+            },
+            $modifiers
+        )
+    };
+    ($menu:ident; checkable ($name:expr, $is_checked:expr, $id:expr $(,)?) $modifiers:tt [] []) => {
+        $crate::__menu_modifiers!(
+            $menu,
+            $crate::MenuItem::Checkable {
+                id: $id,
+                name: $crate::MenuLabel::from($name),
+                is_checked: $is_checked,
+                disabled: false,
+                icon: None,
+            },
+            $modifiers
+        )
+    };
+    ($menu:ident; radio ($name:expr, $group:expr, $is_selected:expr, $id:expr $(,)?) $modifiers:tt [] []) => {
+        $crate::__menu_modifiers!(
+            $menu,
+            $crate::MenuItem::Radio {
+                group: ::std::string::String::from($group),
+                id: $id,
+                name: $crate::MenuLabel::from($name),
+                is_selected: $is_selected,
+                disabled: false,
+                icon: None,
+            },
+            $modifiers
+        )
+    };
+    ($menu:ident; submenu ($name:expr $(, $id:expr)? $(,)?) $modifiers:tt [$($entries:tt)*] []) => {
+        $crate::__menu_modifiers!(
+            $menu,
+            $crate::MenuItem::Submenu {
+                id: $crate::__menu_option!($($id)?),
+                name: $crate::MenuLabel::from($name),
+                children: $crate::menu![$($entries)*],
+                disabled: false,
+                icon: None,
+            },
+            $modifiers
+        )
+    };
+    ($menu:ident; lazy_submenu ($name:expr, $provider:expr $(,)?) $modifiers:tt [] []) => {
+        $crate::__menu_entry!($menu; @lazy_submenu ($name, None, $provider) $modifiers [] [])
+    };
+    ($menu:ident; lazy_submenu ($name:expr, $id:expr, $provider:expr $(,)?) $modifiers:tt [] []) => {
+        $crate::__menu_entry!($menu; @lazy_submenu ($name, Some($id), $provider) $modifiers [] [])
+    };
+    ($menu:ident; @lazy_submenu ($name:expr, $id:expr, $provider:expr) $modifiers:tt [] []) => {
+        $crate::__menu_modifiers!(
+            $menu,
+            $crate::MenuItem::LazySubmenu {
+                id: $id,
+                name: $crate::MenuLabel::from($name),
+                provider: $crate::MenuProvider::new($provider),
+                disabled: false,
+                icon: None,
+            },
+            $modifiers
+        )
+    };
+    ($menu:ident; if ($condition:expr) [] [$($entries:tt)*] [$($else_entries:tt)*]) => {
+        if $condition {
+            let mut $menu = $menu;
+            $crate::__menu_entries!($menu; $($entries)*);
+            $menu
+        } else {
+            let mut $menu = $menu;
+            $crate::__menu_entries!($menu; $($else_entries)*);
+            $menu
+        }
+    };
+    ($menu:ident; $kind:ident $($rest:tt)*) => {
+        compile_error!(concat!(
+            "unknown menu entry or wrong arguments for `",
+            stringify!($kind),
+            "`"
+        ))
+    };
+}
+
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __menu_option {
+    () => {
+        None
+    };
+    ($id:expr) => {
+        Some($id)
+    };
+}
+
+/// Menu with the item added after the modifiers are applied to it
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __menu_modifiers {
+    ($menu:ident, $item:expr, [$($modifier:ident ($($args:tt)*))*]) => {{
+        #[allow(unused_mut)]
+        let mut item = $item;
+        $($crate::__menu_modifier!(item, $modifier ($($args)*));)*
+        $menu.with(item)
+    }};
+}
+
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __menu_modifier {
+    ($item:ident, disabled ()) => {
+        $crate::__menu_modifier!($item, disabled(true))
+    };
+    ($item:ident, disabled ($disabled:expr)) => {
+        match &mut $item {
+            $crate::MenuItem::Item { disabled, .. }
+            | $crate::MenuItem::Checkable { disabled, .. }
+            | $crate::MenuItem::Radio { disabled, .. }
+            | $crate::MenuItem::Submenu { disabled, .. }
+            | $crate::MenuItem::LazySubmenu { disabled, .. } => *disabled = $disabled,
+            $crate::MenuItem::Separator => {}
+        }
+    };
+    ($item:ident, icon ($icon:expr)) => {
+        match &mut $item {
+            $crate::MenuItem::Item { icon, .. }
+            | $crate::MenuItem::Checkable { icon, .. }
+            | $crate::MenuItem::Radio { icon, .. }
+            | $crate::MenuItem::Submenu { icon, .. }
+            | $crate::MenuItem::LazySubmenu { icon, .. } => *icon = Some($icon),
+            $crate::MenuItem::Separator => {}
+        }
+    };
+    ($item:ident, $modifier:ident $args:tt) => {
+        compile_error!(concat!(
+            "unknown menu modifier `",
+            stringify!($modifier),
+            "`"
+        ))
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Icon, MenuBuilder, MenuItem, MenuLabel};
+
+    #[derive(Copy, Clone, Eq, PartialEq, Debug)]
+    enum Events {
+        Open,
+        Save,
+        Dark,
+        Red,
+        Green,
+        Recent,
+        About,
+        LogIn,
+        LogOut,
+    }
+
+    #[test]
+    fn test_menu_macro() {
+        let icon =
+            Icon::from_buffer(include_bytes!("./testresource/icon1.ico"), None, None).unwrap();
+        let logged_in = false;
+        let menu = crate::menu![
+            item(MenuLabel::new("Open").with_mnemonic('o'), Events::Open).icon(icon.clone()),
+            item("Save", Events::Save).disabled(),
+            checkable("Dark", true, Events::Dark).disabled(false),
+            separator,
+            radio("Red", "color", false, Events::Red),
+            radio("Green", "color", true, Events::Green),
+            submenu("Recent", Events::Recent).disabled() [],
+            submenu("Help") [
+                item("About", Events::About),
+                if (logged_in) [
+                    item("Log out", Events::LogOut),
+                ] else [
+                    separator,
+                    item("Log in", Events::LogIn),
+                ]
+            ],
+        ];
+
+        let expected = MenuBuilder::new()
+            .item_with_icon(
+                MenuLabel::new("Open").with_mnemonic('o'),
+                icon,
+                Events::Open,
+            )
+            .with(MenuItem::Item {
+                id: Events::Save,
+                name: "Save".into(),
+                disabled: true,
+                icon: None,
+            })
+            .checkable("Dark", true, Events::Dark)
+            .separator()
+            .radio_group(
+                "color",
+                &[("Red", Events::Red), ("Green", Events::Green)],
+                &Events::Green,
+            )
+            .with(MenuItem::Submenu {
+                id: Some(Events::Recent),
+                name: "Recent".into(),
+                children: MenuBuilder::new(),
+                disabled: true,
+                icon: None,
+            })
+            .submenu(
+                "Help",
+                MenuBuilder::new()
+                    .item("About", Events::About)
+                    .separator()
+                    .item("Log in", Events::LogIn),
+            );
+        assert_eq!(menu, expected);
+
+        let lazy = crate::menu![
+            lazy_submenu("Recent", || MenuBuilder::new().item("About", Events::About)),
+            lazy_submenu("Open", Events::Open, MenuBuilder::new).disabled(),
+        ];
+        match &lazy.menu_items[..] {
+            [MenuItem::LazySubmenu {
+                id: None,
+                provider,
+                disabled: false,
+                ..
+            }, MenuItem::LazySubmenu {
+                id: Some(Events::Open),
+                disabled: true,
+                ..
+            }] => assert_eq!(
+                provider.provide(),
+                MenuBuilder::new().item("About", Events::About)
+            ),
+            items => panic!("unexpected {:?}", items),
+        }
+
+        let empty: MenuBuilder<Events> = crate::menu![];
+        assert_eq!(empty, MenuBuilder::new());
+    }
+}
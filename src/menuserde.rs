@@ -0,0 +1,482 @@
+//! Menus as data, with the `serde` feature
+//!
+//! Menu is a table with the list of `items`, each item is tagged by its
+//! `type`. Icons are referred to by their path or name, see `Icon::name`.
+//!
+//! ```toml
+//! [[items]]
+//! type = "item"
+//! label = "Exit"
+//! mnemonic = "x"
+//! accelerator = "Ctrl+Q"
+//! id = "Exit"
+//! icon = "icons/exit.png"
+//!
+//! [[items]]
+//! type = "separator"
+//!
+//! [[items]]
+//! type = "submenu"
+//! label = "Colors"
+//!
+//! [[items.items]]
+//! type = "radio"
+//! label = "Red"
+//! group = "color"
+//! selected = true
+//! id = "Red"
+//! ```
+
+use crate::{Error, Icon, MenuBuilder, MenuItem, MenuLabel};
+use serde::de::{DeserializeOwned, Deserializer, Error as _};
+use serde::ser::{Error as _, Serializer};
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize)]
+struct MenuDef<I> {
+    items: Vec<ItemDef<I>>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+#[serde(bound(deserialize = "I: Deserialize<'de>"))]
+enum ItemDef<I> {
+    Separator,
+    Item {
+        #[serde(flatten)]
+        label: LabelDef,
+        id: I,
+        #[serde(default, skip_serializing_if = "is_false")]
+        disabled: bool,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        icon: Option<String>,
+    },
+    Checkable {
+        #[serde(flatten)]
+        label: LabelDef,
+        #[serde(default)]
+        checked: bool,
+        id: I,
+        #[serde(default, skip_serializing_if = "is_false")]
+        disabled: bool,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        icon: Option<String>,
+    },
+    Radio {
+        #[serde(flatten)]
+        label: LabelDef,
+        group: String,
+        #[serde(default)]
+        selected: bool,
+        id: I,
+        #[serde(default, skip_serializing_if = "is_false")]
+        disabled: bool,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        icon: Option<String>,
+    },
+    Submenu {
+        #[serde(flatten)]
+        label: LabelDef,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<I>,
+        #[serde(default, skip_serializing_if = "is_false")]
+        disabled: bool,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        icon: Option<String>,
+
+        // Last, as TOML wants tables after the values
+        #[serde(default)]
+        items: Vec<ItemDef<I>>,
+    },
+}
+
+#[derive(Serialize, Deserialize)]
+struct LabelDef {
+    label: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    mnemonic: Option<char>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    accelerator: Option<String>,
+}
+
+fn is_false(b: &bool) -> bool {
+    !*b
+}
+
+impl From<&MenuLabel> for LabelDef {
+    fn from(label: &MenuLabel) -> Self {
+        LabelDef {
+            label: label.text().to_string(),
+            mnemonic: label.mnemonic(),
+            accelerator: label.accelerator().map(String::from),
+        }
+    }
+}
+
+impl From<LabelDef> for MenuLabel {
+    fn from(def: LabelDef) -> Self {
+        let mut label = MenuLabel::from(def.label);
+        if let Some(mnemonic) = def.mnemonic {
+            label = label.with_mnemonic(mnemonic);
+        }
+        if let Some(accelerator) = def.accelerator {
+            label = label.with_accelerator(&accelerator);
+        }
+        label
+    }
+}
+
+/// Reference of the icon, icons without a path or name can't be saved
+fn icon_def(icon: &Option<Icon>) -> Result<Option<String>, &'static str> {
+    match icon {
+        Some(icon) => icon
+            .name()
+            .map(|name| Some(name.to_string()))
+            .ok_or("icon has no path or name to save it by"),
+        None => Ok(None),
+    }
+}
+
+fn item_def<T>(item: &MenuItem<T>) -> Result<ItemDef<&T>, &'static str>
+where
+    T: PartialEq + Clone + 'static,
+{
+    Ok(match item {
+        MenuItem::Separator => ItemDef::Separator,
+        MenuItem::Item {
+            id,
+            name,
+            disabled,
+            icon,
+        } => ItemDef::Item {
+            label: name.into(),
+            id,
+            disabled: *disabled,
+            icon: icon_def(icon)?,
+        },
+        MenuItem::Checkable {
+            id,
+            name,
+            is_checked,
+            disabled,
+            icon,
+        } => ItemDef::Checkable {
+            label: name.into(),
+            checked: *is_checked,
+            id,
+            disabled: *disabled,
+            icon: icon_def(icon)?,
+        },
+        MenuItem::Radio {
+            group,
+            id,
+            name,
+            is_selected,
+            disabled,
+            icon,
+        } => ItemDef::Radio {
+            label: name.into(),
+            group: group.clone(),
+            selected: *is_selected,
+            id,
+            disabled: *disabled,
+            icon: icon_def(icon)?,
+        },
+        MenuItem::Submenu {
+            id,
+            name,
+            children,
+            disabled,
+            icon,
+        } => ItemDef::Submenu {
+            label: name.into(),
+            id: id.as_ref(),
+            disabled: *disabled,
+            icon: icon_def(icon)?,
+            items: menu_def(children)?.items,
+        },
+        MenuItem::LazySubmenu { .. } => return Err("lazy submenus can't be saved"),
+    })
+}
+
+fn menu_def<T>(menu: &MenuBuilder<T>) -> Result<MenuDef<&T>, &'static str>
+where
+    T: PartialEq + Clone + 'static,
+{
+    Ok(MenuDef {
+        items: menu
+            .menu_items
+            .iter()
+            .map(item_def)
+            .collect::<Result<_, _>>()?,
+    })
+}
+
+/// Item from its definition, icon references are loaded with `icons`
+///
+/// Loaded icons are named by their reference, so saving the menu again
+/// gives the same references.
+fn item<T, F>(def: ItemDef<T>, icons: &mut F) -> Result<MenuItem<T>, Error>
+where
+    T: PartialEq + Clone + 'static,
+    F: FnMut(&str) -> Result<Icon, Error>,
+{
+    let mut icon = |icon: Option<String>| {
+        icon.map(|name| icons(&name).map(|icon| icon.with_name(&name)))
+            .transpose()
+    };
+    Ok(match def {
+        ItemDef::Separator => MenuItem::Separator,
+        ItemDef::Item {
+            label,
+            id,
+            disabled,
+            icon: i,
+        } => MenuItem::Item {
+            id,
+            name: label.into(),
+            disabled,
+            icon: icon(i)?,
+        },
+        ItemDef::Checkable {
+            label,
+            checked,
+            id,
+            disabled,
+            icon: i,
+        } => MenuItem::Checkable {
+            id,
+            name: label.into(),
+            is_checked: checked,
+            disabled,
+            icon: icon(i)?,
+        },
+        ItemDef::Radio {
+            label,
+            group,
+            selected,
+            id,
+            disabled,
+            icon: i,
+        } => MenuItem::Radio {
+            group,
+            id,
+            name: label.into(),
+            is_selected: selected,
+            disabled,
+            icon: icon(i)?,
+        },
+        ItemDef::Submenu {
+            label,
+            id,
+            disabled,
+            icon: i,
+            items,
+        } => {
+            let icon = icon(i)?;
+            MenuItem::Submenu {
+                id,
+                name: label.into(),
+                children: menu(MenuDef { items }, icons)?,
+                disabled,
+                icon,
+            }
+        }
+    })
+}
+
+fn menu<T, F>(def: MenuDef<T>, icons: &mut F) -> Result<MenuBuilder<T>, Error>
+where
+    T: PartialEq + Clone + 'static,
+    F: FnMut(&str) -> Result<Icon, Error>,
+{
+    def.items
+        .into_iter()
+        .try_fold(MenuBuilder::new(), |menu, def| {
+            Ok(menu.with(item(def, icons)?))
+        })
+}
+
+/// Icons are loaded from the referred files
+fn icon_from_path(path: &str) -> Result<Icon, Error> {
+    Icon::from_path(path, None, None)
+}
+
+impl<T> MenuBuilder<T>
+where
+    T: PartialEq + Clone + DeserializeOwned + 'static,
+{
+    /// Deserialize the menu, icon references are loaded with `icons`
+    ///
+    /// Plain `deserialize` loads them as paths of ICO or PNG files, this can
+    /// map names to icons the application has.
+    pub fn deserialize_with_icons<'de, D, F>(
+        deserializer: D,
+        mut icons: F,
+    ) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+        F: FnMut(&str) -> Result<Icon, Error>,
+    {
+        let def = MenuDef::deserialize(deserializer)?;
+        menu(def, &mut icons).map_err(D::Error::custom)
+    }
+}
+
+impl<T> Serialize for MenuBuilder<T>
+where
+    T: PartialEq + Clone + Serialize + 'static,
+{
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        menu_def(self)
+            .map_err(S::Error::custom)?
+            .serialize(serializer)
+    }
+}
+
+impl<'de, T> Deserialize<'de> for MenuBuilder<T>
+where
+    T: PartialEq + Clone + DeserializeOwned + 'static,
+{
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        MenuBuilder::deserialize_with_icons(deserializer, icon_from_path)
+    }
+}
+
+impl<T> Serialize for MenuItem<T>
+where
+    T: PartialEq + Clone + Serialize + 'static,
+{
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        item_def(self)
+            .map_err(S::Error::custom)?
+            .serialize(serializer)
+    }
+}
+
+impl<'de, T> Deserialize<'de> for MenuItem<T>
+where
+    T: PartialEq + Clone + DeserializeOwned + 'static,
+{
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let def = ItemDef::deserialize(deserializer)?;
+        item(def, &mut icon_from_path).map_err(D::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Copy, Clone, Eq, PartialEq, Debug, Serialize, Deserialize)]
+    enum Events {
+        Exit,
+        Dark,
+        Red,
+        Green,
+        Settings,
+    }
+
+    fn icon() -> Icon {
+        Icon::from_buffer(include_bytes!("./testresource/icon1.ico"), None, None)
+            .unwrap()
+            .with_name("app")
+    }
+
+    fn menu() -> MenuBuilder<Events> {
+        MenuBuilder::new()
+            .item(
+                MenuLabel::new("Exit")
+                    .with_mnemonic('x')
+                    .with_accelerator("Ctrl+Q"),
+                Events::Exit,
+            )
+            .separator()
+            .checkable("Dark", true, Events::Dark)
+            .submenu_with_icon(
+                "Colors",
+                icon(),
+                MenuBuilder::new().radio_group(
+                    "color",
+                    &[("Red", Events::Red), ("Green", Events::Green)],
+                    &Events::Green,
+                ),
+            )
+            .with(MenuItem::Submenu {
+                id: Some(Events::Settings),
+                name: "Settings".into(),
+                children: MenuBuilder::new(),
+                disabled: true,
+                icon: None,
+            })
+    }
+
+    fn icons(name: &str) -> Result<Icon, Error> {
+        assert_eq!(name, "app");
+        Ok(icon())
+    }
+
+    #[test]
+    fn test_json_round_trip() {
+        let json = serde_json::to_string(&menu()).unwrap();
+        let mut deserializer = serde_json::Deserializer::from_str(&json);
+        let menu2 = MenuBuilder::deserialize_with_icons(&mut deserializer, icons).unwrap();
+        assert_eq!(menu2, menu());
+
+        let value = serde_json::to_value(menu()).unwrap();
+        assert_eq!(
+            value["items"][0],
+            serde_json::json!({
+                "type": "item",
+                "label": "Exit",
+                "mnemonic": "x",
+                "accelerator": "Ctrl+Q",
+                "id": "Exit"
+            })
+        );
+        assert_eq!(value["items"][3]["icon"], "app");
+    }
+
+    #[test]
+    fn test_toml_round_trip() {
+        let toml = toml::to_string(&menu()).unwrap();
+        let menu2 =
+            MenuBuilder::deserialize_with_icons(toml::Deserializer::new(&toml), icons).unwrap();
+        assert_eq!(menu2, menu());
+
+        let item: MenuItem<Events> =
+            toml::from_str("type = 'checkable'\nlabel = 'Dark'\nid = 'Dark'").unwrap();
+        assert_eq!(
+            item,
+            MenuBuilder::new()
+                .checkable("Dark", false, Events::Dark)
+                .menu_items[0]
+        );
+    }
+
+    #[test]
+    fn test_icon_references() {
+        // Icons without a name have nothing to refer to
+        let icon = icon();
+        let unnamed = MenuBuilder::new().item_with_icon(
+            "Exit",
+            Icon::from_rgba(1, 1, vec![0; 4]).unwrap(),
+            Events::Exit,
+        );
+        assert!(serde_json::to_string(&unnamed).is_err());
+
+        // Plain deserialize reads the files
+        let path = "src/testresource/icon1.ico";
+        let json = format!(
+            r#"{{"items": [{{"type": "item", "label": "Exit", "id": "Exit", "icon": "{}"}}]}}"#,
+            path
+        );
+        let menu: MenuBuilder<Events> = serde_json::from_str(&json).unwrap();
+        let loaded = menu.find(&Events::Exit).unwrap().icon().unwrap();
+        assert_eq!(*loaded, icon);
+        assert_eq!(loaded.name(), Some(path));
+
+        let missing = json.replace(path, "no/such/icon.ico");
+        assert!(serde_json::from_str::<MenuBuilder<Events>>(&missing).is_err());
+    }
+}
@@ -0,0 +1,224 @@
+use crate::{MenuBuilder, MenuItem};
+use std::fmt;
+
+/// Deepest nesting of submenus a valid menu has
+///
+/// Native menus are built recursively, far deeper menus could overflow the
+/// stack. No one navigates menus this deep anyway.
+pub const MAX_MENU_DEPTH: usize = 32;
+
+/// Problem of a menu found by `MenuBuilder::validate`
+#[derive(Debug, Clone, PartialEq)]
+pub struct MenuDiagnostic {
+    /// Path of the item, like the ones of `MenuBuilder::iter`
+    pub path: Vec<usize>,
+    pub problem: MenuProblem,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum MenuProblem {
+    /// Item has the same id as the item at path `first`, so their clicks
+    /// can't be told apart
+    DuplicateId { first: Vec<usize> },
+
+    /// Submenu has no items, most hosts show it as a dead item
+    EmptySubmenu,
+
+    /// Separator is the first item of its menu
+    LeadingSeparator,
+
+    /// Separator is the last item of its menu
+    TrailingSeparator,
+
+    /// Separator follows another separator
+    DoubleSeparator,
+
+    /// Submenu's items would be deeper than `MAX_MENU_DEPTH`, they are not
+    /// checked
+    TooDeep,
+}
+
+impl fmt::Display for MenuDiagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "menu item at {:?} ", self.path)?;
+        match &self.problem {
+            MenuProblem::DuplicateId { first } => {
+                write!(f, "has the same id as the item at {:?}", first)
+            }
+            MenuProblem::EmptySubmenu => write!(f, "is an empty submenu"),
+            MenuProblem::LeadingSeparator => write!(f, "is a leading separator"),
+            MenuProblem::TrailingSeparator => write!(f, "is a trailing separator"),
+            MenuProblem::DoubleSeparator => write!(f, "is a double separator"),
+            MenuProblem::TooDeep => write!(
+                f,
+                "is a submenu nested deeper than {} levels",
+                MAX_MENU_DEPTH
+            ),
+        }
+    }
+}
+
+impl<T> MenuBuilder<T>
+where
+    T: PartialEq + Clone + 'static,
+{
+    /// Problems of the menu, in the order of the items
+    ///
+    /// Empty root menu is fine, it means no menu at all. Nested menus are
+    /// walked with a stack, so this works for any menu.
+    pub fn validate(&self) -> Vec<MenuDiagnostic> {
+        let mut diagnostics = vec![];
+        let mut ids: Vec<(Vec<usize>, &T)> = vec![];
+        let mut stack = vec![(vec![], self)];
+        while let Some((parent, menu)) = stack.pop() {
+            let items = &menu.menu_items;
+            for (i, item) in items.iter().enumerate() {
+                let mut path = parent.clone();
+                path.push(i);
+                let mut problem = |problem| {
+                    diagnostics.push(MenuDiagnostic {
+                        path: path.clone(),
+                        problem,
+                    })
+                };
+                match item {
+                    MenuItem::Separator if i == 0 => problem(MenuProblem::LeadingSeparator),
+                    MenuItem::Separator if matches!(items[i - 1], MenuItem::Separator) => {
+                        problem(MenuProblem::DoubleSeparator)
+                    }
+                    MenuItem::Separator if i == items.len() - 1 => {
+                        problem(MenuProblem::TrailingSeparator)
+                    }
+                    MenuItem::Submenu { children, .. } if children.menu_items.is_empty() => {
+                        problem(MenuProblem::EmptySubmenu)
+                    }
+                    MenuItem::Submenu { .. } if path.len() >= MAX_MENU_DEPTH => {
+                        problem(MenuProblem::TooDeep)
+                    }
+                    _ => {}
+                }
+                if let Some(id) = item.id() {
+                    ids.push((path.clone(), id));
+                }
+                if let MenuItem::Submenu { children, .. } = item {
+                    if path.len() < MAX_MENU_DEPTH {
+                        stack.push((path, children));
+                    }
+                }
+            }
+        }
+
+        // Paths sort in the order of the items, so the first one of the
+        // duplicates is found first
+        ids.sort_by(|a, b| a.0.cmp(&b.0));
+        for (i, (path, id)) in ids.iter().enumerate() {
+            if let Some((first, _)) = ids[..i].iter().find(|(_, other)| other == id) {
+                diagnostics.push(MenuDiagnostic {
+                    path: path.clone(),
+                    problem: MenuProblem::DuplicateId {
+                        first: first.clone(),
+                    },
+                });
+            }
+        }
+        diagnostics.sort_by(|a, b| a.path.cmp(&b.path));
+        diagnostics
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Error, TrayIconBuilder};
+
+    #[derive(Copy, Clone, Eq, PartialEq, Debug)]
+    enum Events {
+        Item1,
+        Item2,
+        Item3,
+    }
+
+    #[test]
+    fn test_validate() {
+        let valid = MenuBuilder::new()
+            .item("Item 1", Events::Item1)
+            .separator()
+            .submenu("Sub", MenuBuilder::new().item("Item 2", Events::Item2));
+        assert_eq!(valid.validate(), vec![]);
+        assert_eq!(MenuBuilder::<Events>::new().validate(), vec![]);
+
+        let invalid = MenuBuilder::new()
+            .separator()
+            .item("Item 1", Events::Item1)
+            .separator()
+            .separator()
+            .submenu(
+                "Sub",
+                MenuBuilder::new()
+                    .checkable("Item 1", false, Events::Item1)
+                    .submenu("Empty", MenuBuilder::new())
+                    .separator(),
+            )
+            .item("Item 3", Events::Item3);
+        let problems = invalid
+            .validate()
+            .into_iter()
+            .map(|d| (d.path, d.problem))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            problems,
+            vec![
+                (vec![0], MenuProblem::LeadingSeparator),
+                (vec![3], MenuProblem::DoubleSeparator),
+                (vec![4, 0], MenuProblem::DuplicateId { first: vec![1] }),
+                (vec![4, 1], MenuProblem::EmptySubmenu),
+                (vec![4, 2], MenuProblem::TrailingSeparator),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_validate_deep() {
+        let deep = (0..100).fold(
+            MenuBuilder::new().item("Item 1", Events::Item1),
+            |menu, _| MenuBuilder::new().submenu("Sub", menu),
+        );
+        let problems = deep.validate();
+        assert_eq!(problems.len(), 1);
+        assert_eq!(problems[0].path, vec![0; MAX_MENU_DEPTH]);
+        assert_eq!(problems[0].problem, MenuProblem::TooDeep);
+        assert_eq!(
+            problems[0].to_string(),
+            format!(
+                "menu item at {:?} is a submenu nested deeper than 32 levels",
+                vec![0; 32]
+            )
+        );
+    }
+
+    #[test]
+    fn test_reject_invalid_menus() {
+        let (s, _r) = std::sync::mpsc::channel::<Events>();
+        let invalid = MenuBuilder::new().separator();
+        let builder = TrayIconBuilder::new()
+            .sender(s)
+            .icon_from_buffer(include_bytes!("./testresource/icon1.ico"))
+            .menu(invalid.clone());
+        assert!(builder.clone().build_mock().is_ok());
+
+        // Release builds don't check
+        let builder = builder.reject_invalid_menus(true);
+        let expected = if cfg!(debug_assertions) {
+            Err(Error::InvalidMenu(invalid.validate()))
+        } else {
+            Ok(())
+        };
+        assert_eq!(builder.clone().build_mock().map(|_| ()), expected);
+
+        let (mut trayicon, _mock) = builder
+            .menu(MenuBuilder::new().item("Item 1", Events::Item1))
+            .build_mock()
+            .unwrap();
+        assert_eq!(trayicon.set_menu(&invalid), expected);
+    }
+}
@@ -0,0 +1,432 @@
+use crate::trayevent::{EventDispatcher, EventInfo, MouseButton, ScrollOrientation, TrayEvent};
+use crate::{
+    Capabilities, DeliveryStats, Error, Icon, MenuBuilder, MenuItem, TrayIconBase, TrayIconBuilder,
+};
+use std::borrow::Cow;
+use std::fmt::Debug;
+use std::sync::{Arc, Mutex};
+
+/// Change made to a mock tray icon
+#[derive(Debug, Clone, PartialEq)]
+pub enum MockCall<T>
+where
+    T: PartialEq + Clone + 'static,
+{
+    SetIcon(Icon),
+    SetMenu(MenuBuilder<T>),
+    SetTooltip(String),
+}
+
+#[derive(Debug)]
+struct MockState<T>
+where
+    T: PartialEq + Clone + 'static,
+{
+    icon: Icon,
+    menu: Option<MenuBuilder<T>>,
+    tooltip: Option<String>,
+    calls: Vec<MockCall<T>>,
+    tooltips: Vec<String>,
+}
+
+/// Handle to a tray icon built with `TrayIconBuilder::build_mock`
+///
+/// Records every `set_icon`, `set_menu` and `set_tooltip` call and simulates
+/// the user. The simulated events are sent with the builder's sender, exactly
+/// like the OS backends do.
+#[derive(Debug, Clone)]
+pub struct MockTrayIcon<T>
+where
+    T: PartialEq + Clone + 'static,
+{
+    /// Outside of the lock, the receiver may call the tray icon
+    events: EventDispatcher<T>,
+    state: Arc<Mutex<MockState<T>>>,
+}
+
+impl<T> MockTrayIcon<T>
+where
+    T: PartialEq + Clone + 'static,
+{
+    pub(crate) fn new(builder: &TrayIconBuilder<T>) -> Result<MockTrayIcon<T>, Error> {
+        let state = MockState {
+            icon: builder.icon.clone()?,
+            menu: builder.menu.clone(),
+            tooltip: builder.tooltip.clone(),
+            calls: vec![],
+            tooltips: builder.tooltip.iter().cloned().collect(),
+        };
+        Ok(MockTrayIcon {
+            events: EventDispatcher::new(builder)?,
+            state: Arc::new(Mutex::new(state)),
+        })
+    }
+
+    fn send(&self, event: TrayEvent<T>) {
+        self.events.send(event);
+    }
+
+    fn with<F, R>(&self, f: F) -> R
+    where
+        F: FnOnce(&mut MockState<T>) -> R,
+    {
+        f(&mut self.state.lock().unwrap_or_else(|e| e.into_inner()))
+    }
+
+    /// Calls made through `TrayIcon`, oldest first
+    ///
+    /// The initial values given to the builder are not calls.
+    pub fn calls(&self) -> Vec<MockCall<T>> {
+        self.with(|s| s.calls.clone())
+    }
+
+    /// Current icon
+    pub fn icon(&self) -> Icon {
+        self.with(|s| s.icon.clone())
+    }
+
+    /// Current menu
+    pub fn menu(&self) -> Option<MenuBuilder<T>> {
+        self.with(|s| s.menu.clone())
+    }
+
+    /// Current tooltip
+    pub fn tooltip(&self) -> Option<String> {
+        self.with(|s| s.tooltip.clone())
+    }
+
+    /// All tooltips the tray icon has had, the initial one first
+    pub fn tooltips(&self) -> Vec<String> {
+        self.with(|s| s.tooltips.clone())
+    }
+
+    /// Simulate left click
+    pub fn click(&self) {
+        self.send(TrayEvent::Click(EventInfo::now(Some(MouseButton::Left))));
+    }
+
+    /// Simulate double click
+    ///
+    /// Like on the OS backends, the click event comes first.
+    pub fn double_click(&self) {
+        self.send(TrayEvent::Click(EventInfo::now(Some(MouseButton::Left))));
+        self.send(TrayEvent::DoubleClick(EventInfo::now(Some(
+            MouseButton::Left,
+        ))));
+    }
+
+    /// Simulate right click
+    pub fn right_click(&self) {
+        self.send(TrayEvent::RightClick(EventInfo::now(Some(
+            MouseButton::Right,
+        ))));
+    }
+
+    /// Simulate middle click
+    pub fn middle_click(&self) {
+        self.send(TrayEvent::MiddleClick(EventInfo::now(Some(
+            MouseButton::Middle,
+        ))));
+    }
+
+    /// Simulate the mouse wheel, `delta` is positive up or right
+    pub fn scroll(&self, delta: i32, orientation: ScrollOrientation) {
+        self.send(TrayEvent::Scroll {
+            delta,
+            orientation,
+            info: EventInfo::now(None),
+        });
+    }
+
+    /// Simulate showing the menu
+    pub fn open_menu(&self) {
+        self.send(TrayEvent::MenuOpen(EventInfo::now(None)));
+    }
+
+    /// Simulate hiding the menu, e.g. dismissing it without a choice
+    pub fn close_menu(&self) {
+        self.send(TrayEvent::MenuClose(EventInfo::now(None)));
+    }
+
+    /// Simulate choosing a menu item
+    ///
+    /// Returns `Error::MenuItemNotFound` if the current menu has no enabled
+    /// item with the id, the user couldn't choose it either.
+    pub fn activate_menu_item(&self, id: &T) -> Result<(), Error>
+    where
+        T: Debug,
+    {
+        // Searched without the lock, lazy submenu providers may call the
+        // tray icon
+        let menu = self.menu();
+        let report = |e: &Error| self.events.report(e);
+        if !menu
+            .as_ref()
+            .is_some_and(|m| is_enabled_item(m, id, &report))
+        {
+            return Err(Error::menu_item_not_found(id));
+        }
+        self.send(TrayEvent::MenuItem {
+            id: id.clone(),
+            info: EventInfo::now(Some(MouseButton::Left)),
+        });
+        Ok(())
+    }
+}
+
+/// Find enabled item, disabled submenus hide their children
+///
+/// Enabled lazy submenus are opened, like the user would to reach the item.
+/// Panic of their provider is given to `report`, like the backends do.
+fn is_enabled_item<T>(menu: &MenuBuilder<T>, find_id: &T, report: &dyn Fn(&Error)) -> bool
+where
+    T: PartialEq + Clone + 'static,
+{
+    let mut stack = vec![Cow::Borrowed(menu)];
+    while let Some(menu) = stack.pop() {
+        for item in menu.menu_items.iter() {
+            match item {
+                MenuItem::Item { id, disabled, .. }
+                | MenuItem::Checkable { id, disabled, .. }
+                | MenuItem::Radio { id, disabled, .. }
+                    if id == find_id =>
+                {
+                    return !disabled;
+                }
+                MenuItem::Submenu {
+                    children,
+                    disabled: false,
+                    ..
+                } => stack.push(Cow::Owned(children.clone())),
+                MenuItem::LazySubmenu {
+                    provider,
+                    disabled: false,
+                    ..
+                } => stack.push(Cow::Owned(provider.provide_or_report(report))),
+                _ => {}
+            }
+        }
+    }
+    false
+}
+
+impl<T> TrayIconBase<T> for MockTrayIcon<T>
+where
+    T: PartialEq + Clone + 'static,
+{
+    fn set_icon(&mut self, icon: &Icon) -> Result<(), Error> {
+        self.with(|s| {
+            s.icon = icon.clone();
+            s.calls.push(MockCall::SetIcon(icon.clone()));
+        });
+        Ok(())
+    }
+
+    fn set_menu(&mut self, menu: &MenuBuilder<T>) -> Result<(), Error> {
+        self.with(|s| {
+            s.menu = Some(menu.clone());
+            s.calls.push(MockCall::SetMenu(menu.clone()));
+        });
+        Ok(())
+    }
+
+    fn set_tooltip(&mut self, tooltip: &str) -> Result<(), Error> {
+        self.with(|s| {
+            s.tooltip = Some(tooltip.to_string());
+            s.tooltips.push(tooltip.to_string());
+            s.calls.push(MockCall::SetTooltip(tooltip.to_string()));
+        });
+        Ok(())
+    }
+
+    fn delivery_stats(&self) -> DeliveryStats {
+        self.events.stats()
+    }
+
+    /// Mock can simulate every event
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            middle_click: true,
+            scroll: true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MenuProvider;
+    use std::sync::mpsc::Receiver;
+
+    #[derive(Copy, Clone, Eq, PartialEq, Debug)]
+    enum Events {
+        Click,
+        DoubleClick,
+        RightClick,
+        MenuOpen,
+        MenuClose,
+        MiddleClick,
+        Scroll(i32),
+        Item1,
+        SubItem1,
+        DisabledItem1,
+    }
+
+    fn build() -> (
+        crate::TrayIcon<Events>,
+        MockTrayIcon<Events>,
+        Receiver<Events>,
+    ) {
+        let (s, r) = std::sync::mpsc::channel();
+        let (trayicon, mock) = TrayIconBuilder::new()
+            .sender(s)
+            .icon_from_buffer(include_bytes!("./testresource/icon1.ico"))
+            .tooltip("Initial")
+            .on_click(Events::Click)
+            .on_double_click(Events::DoubleClick)
+            .on_right_click(Events::RightClick)
+            .on_menu_open(Events::MenuOpen)
+            .on_menu_close(Events::MenuClose)
+            .on_middle_click(Events::MiddleClick)
+            .on_scroll(|delta, orientation| match orientation {
+                ScrollOrientation::Vertical => Some(Events::Scroll(delta)),
+                ScrollOrientation::Horizontal => None,
+            })
+            .menu(
+                MenuBuilder::new()
+                    .item("Item 1", Events::Item1)
+                    .submenu("Sub", MenuBuilder::new().item("Sub 1", Events::SubItem1))
+                    .with(MenuItem::Item {
+                        id: Events::DisabledItem1,
+                        name: "Disabled".into(),
+                        disabled: true,
+                        icon: None,
+                    }),
+            )
+            .build_mock()
+            .unwrap();
+        (trayicon, mock, r)
+    }
+
+    #[test]
+    fn test_mock_records_calls() {
+        let (mut trayicon, mock, _r) = build();
+        trayicon.set_tooltip("Second").unwrap();
+        trayicon.set_tooltip("Second").unwrap();
+        trayicon.set_tooltip("Third").unwrap();
+        let menu = MenuBuilder::new().item("New", Events::Item1);
+        trayicon.set_menu(&menu).unwrap();
+
+        assert_eq!(mock.tooltips(), vec!["Initial", "Second", "Third"]);
+        assert_eq!(mock.tooltip().as_deref(), Some("Third"));
+        assert_eq!(mock.menu(), Some(menu.clone()));
+        assert_eq!(
+            mock.calls(),
+            vec![
+                MockCall::SetTooltip("Second".into()),
+                MockCall::SetTooltip("Third".into()),
+                MockCall::SetMenu(menu),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_mock_events() {
+        let (_trayicon, mock, r) = build();
+        mock.click();
+        mock.double_click();
+        mock.right_click();
+        mock.open_menu();
+        mock.close_menu();
+        mock.middle_click();
+        mock.scroll(-2, ScrollOrientation::Vertical);
+        mock.scroll(1, ScrollOrientation::Horizontal);
+        mock.activate_menu_item(&Events::SubItem1).unwrap();
+        assert_eq!(
+            r.try_iter().collect::<Vec<_>>(),
+            vec![
+                Events::Click,
+                Events::Click,
+                Events::DoubleClick,
+                Events::RightClick,
+                Events::MenuOpen,
+                Events::MenuClose,
+                Events::MiddleClick,
+                Events::Scroll(-2),
+                Events::SubItem1
+            ]
+        );
+
+        assert_eq!(
+            mock.activate_menu_item(&Events::DisabledItem1),
+            Err(Error::MenuItemNotFound {
+                id: "DisabledItem1".into()
+            })
+        );
+        assert_eq!(
+            mock.activate_menu_item(&Events::Click)
+                .map_err(|e| e.to_string()),
+            Err("menu item Click not found".into())
+        );
+        assert!(r.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_mock_lazy_submenu() {
+        let (mut trayicon, mock, r) = build();
+        let menu = MenuBuilder::new()
+            .lazy_submenu("Lazy", || {
+                MenuBuilder::new().item("Sub 1", Events::SubItem1)
+            })
+            .with(MenuItem::LazySubmenu {
+                id: None,
+                name: "Disabled".into(),
+                provider: MenuProvider::new(|| MenuBuilder::new().item("Item 1", Events::Item1)),
+                disabled: true,
+                icon: None,
+            });
+        trayicon.set_menu(&menu).unwrap();
+        mock.activate_menu_item(&Events::SubItem1).unwrap();
+        assert!(mock.activate_menu_item(&Events::Item1).is_err());
+        assert_eq!(r.try_iter().collect::<Vec<_>>(), vec![Events::SubItem1]);
+    }
+
+    #[test]
+    fn test_mock_is_send() {
+        fn assert_send<S: Send + Sync>(_: &S) {}
+        let (_trayicon, mock, _r) = build();
+        assert_send(&mock);
+        std::thread::spawn(move || mock.click()).join().unwrap();
+    }
+
+    #[test]
+    fn test_mock_callback_calls_trayicon() {
+        let trayicon = Arc::new(Mutex::new(None::<crate::TrayIcon<Events>>));
+        let (built, mock) = {
+            let trayicon = trayicon.clone();
+            TrayIconBuilder::new()
+                .on_event(move |e: &Events| {
+                    if let Some(trayicon) = trayicon.lock().unwrap().as_mut() {
+                        trayicon.set_tooltip(&format!("{:?}", e)).unwrap();
+                    }
+                })
+                .icon_from_buffer(include_bytes!("./testresource/icon1.ico"))
+                .on_click(Events::Click)
+                .menu(MenuBuilder::new().item("Item 1", Events::Item1))
+                .build_mock()
+                .unwrap()
+        };
+        *trayicon.lock().unwrap() = Some(built);
+        mock.click();
+        mock.activate_menu_item(&Events::Item1).unwrap();
+        assert_eq!(mock.tooltips(), vec!["Click", "Item1"]);
+    }
+
+    #[test]
+    fn test_mock_requires_sender() {
+        let result = TrayIconBuilder::<Events>::new()
+            .icon_from_buffer(include_bytes!("./testresource/icon1.ico"))
+            .build_mock();
+        assert_eq!(result.err(), Some(Error::SenderMissing));
+    }
+}
@@ -0,0 +1,522 @@
+use super::menunode::{MenuNode, MenuNodeKind};
+use crate::icon::ico::IcoImage;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use zbus::{
+    interface,
+    object_server::SignalEmitter,
+    zvariant::{OwnedValue, Str, Value},
+};
+
+pub const MENU_PATH: &str = "/MenuBar";
+
+type Properties = HashMap<String, OwnedValue>;
+type Layout = (i32, Properties, Vec<OwnedValue>);
+
+impl MenuNode {
+    /// Properties of the node, default values are left out
+    ///
+    /// Empty `names` means all properties.
+    fn properties(&self, names: &[String]) -> Properties {
+        let mut props = Properties::new();
+        let mut insert = |name: &str, value: Value<'_>| {
+            if names.is_empty() || names.iter().any(|n| n == name) {
+                if let Ok(value) = OwnedValue::try_from(value) {
+                    props.insert(name.to_string(), value);
+                }
+            }
+        };
+        match self.kind {
+            MenuNodeKind::Separator => {
+                insert("type", Value::from(Str::from("separator")));
+                return props;
+            }
+            MenuNodeKind::Checkable(checked) => {
+                insert("toggle-type", Value::from(Str::from("checkmark")));
+                insert("toggle-state", Value::from(checked as i32));
+            }
+            MenuNodeKind::Radio(selected) => {
+                insert("toggle-type", Value::from(Str::from("radio")));
+                insert("toggle-state", Value::from(selected as i32));
+            }
+            MenuNodeKind::Submenu => {
+                insert("children-display", Value::from(Str::from("submenu")));
+            }
+            MenuNodeKind::Standard => {}
+        }
+        if self.id != 0 {
+            insert("label", Value::from(Str::from(self.label.marked('_'))));
+        }
+        if let Some(shortcut) = self.label.accelerator().and_then(shortcut) {
+            insert("shortcut", Value::from(vec![shortcut]));
+        }
+        if self.disabled {
+            insert("enabled", Value::from(false));
+        }
+        if let Some(png) = self.icon.as_ref().and_then(encode_png) {
+            insert("icon-data", Value::from(png));
+        }
+        props
+    }
+
+    /// Layout of the node, children are included up to `depth` levels, -1
+    /// means all levels
+    ///
+    /// Recursion depth is bounded by the depth of the menu, which is the same
+    /// for the recursive menu builders.
+    fn layout(&self, depth: i32, names: &[String]) -> Layout {
+        let children = if depth == 0 {
+            vec![]
+        } else {
+            self.children
+                .iter()
+                .filter_map(|c| OwnedValue::try_from(Value::from(c.layout(depth - 1, names))).ok())
+                .collect()
+        };
+        (self.id, self.properties(names), children)
+    }
+}
+
+/// PNG stream of the `icon-data` property
+fn encode_png(image: &IcoImage) -> Option<Vec<u8>> {
+    let mut png = vec![];
+    let mut encoder = png::Encoder::new(&mut png, image.width, image.height);
+    encoder.set_color(png::ColorType::Rgba);
+    let mut writer = encoder.write_header().ok()?;
+    writer.write_image_data(&image.rgba).ok()?;
+    writer.finish().ok()?;
+    Some(png)
+}
+
+/// Key chord of the `shortcut` property from accelerator text like "Ctrl+Q"
+///
+/// Modifiers are named like dbusmenu expects, unknown accelerators are not
+/// shown.
+fn shortcut(accelerator: &str) -> Option<Vec<String>> {
+    let mut keys = accelerator.split('+').map(str::trim).collect::<Vec<_>>();
+    let key = keys.pop().filter(|k| !k.is_empty())?;
+    let mut chord = keys
+        .into_iter()
+        .map(|m| match m.to_lowercase().as_str() {
+            "ctrl" | "control" => Some("Control"),
+            "alt" => Some("Alt"),
+            "shift" => Some("Shift"),
+            "super" | "win" | "meta" | "cmd" => Some("Super"),
+            _ => None,
+        })
+        .map(|m| m.map(String::from))
+        .collect::<Option<Vec<_>>>()?;
+    chord.push(if key.chars().count() == 1 {
+        key.to_lowercase()
+    } else {
+        key.to_string()
+    });
+    Some(chord)
+}
+
+/// The `com.canonical.dbusmenu` D-Bus object
+///
+/// Like the StatusNotifierItem object it doesn't know about the event type,
+/// clicked item ids are given to the handler.
+pub struct DBusMenu {
+    revision: u32,
+    root: MenuNode,
+    handler: Box<dyn DBusMenuHandler>,
+}
+
+/// Receiver of the menu events
+///
+/// Plain closures get the events and have no lazy submenus.
+pub trait DBusMenuHandler: Send + Sync {
+    fn event(&self, event: DBusMenuEvent);
+
+    /// Fill the lazy submenu `id` of `root`, returns false if there is none
+    fn populate(&mut self, _root: &mut MenuNode, _id: i32) -> bool {
+        false
+    }
+}
+
+impl<F> DBusMenuHandler for F
+where
+    F: Fn(DBusMenuEvent) + Send + Sync,
+{
+    fn event(&self, event: DBusMenuEvent) {
+        self(event)
+    }
+}
+
+/// Menu events from the host
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DBusMenuEvent {
+    /// Menu item with id was clicked
+    Clicked(i32),
+
+    /// Root menu is about to be shown
+    AboutToShow,
+
+    /// Root menu was shown
+    Opened,
+
+    /// Root menu was hidden, with or without a click
+    Closed,
+}
+
+impl DBusMenu {
+    pub fn new() -> DBusMenu {
+        DBusMenu {
+            revision: 0,
+            root: MenuNode::root(vec![]),
+            handler: Box::new(|_| {}),
+        }
+    }
+
+    /// Replace the menu, returns the new revision
+    pub fn set<H>(&mut self, root: MenuNode, handler: H) -> u32
+    where
+        H: DBusMenuHandler + 'static,
+    {
+        self.root = root;
+        self.handler = Box::new(handler);
+        self.revision += 1;
+        self.revision
+    }
+
+    /// Replace the menu with one of the same layout, returns the changed
+    /// and the removed properties of each node
+    ///
+    /// The layout and its revision stay, only `ItemsPropertiesUpdated` is
+    /// needed.
+    #[allow(clippy::type_complexity)]
+    pub fn update<H>(
+        &mut self,
+        root: MenuNode,
+        handler: H,
+    ) -> (Vec<(i32, Properties)>, Vec<(i32, Vec<String>)>)
+    where
+        H: DBusMenuHandler + 'static,
+    {
+        let mut updated = vec![];
+        let mut removed = vec![];
+        let mut stack = vec![(&self.root, &root)];
+        while let Some((old, new)) = stack.pop() {
+            let old_props = old.properties(&[]);
+            let new_props = new.properties(&[]);
+            if old_props != new_props {
+                let gone = old_props
+                    .keys()
+                    .filter(|k| !new_props.contains_key(*k))
+                    .cloned()
+                    .collect::<Vec<_>>();
+                if !gone.is_empty() {
+                    removed.push((new.id, gone));
+                }
+                updated.push((new.id, new_props));
+            }
+            stack.extend(old.children.iter().zip(new.children.iter()));
+        }
+        self.root = root;
+        self.handler = Box::new(handler);
+        (updated, removed)
+    }
+
+    fn dispatch(&self, id: i32, event_id: &str) -> bool {
+        if self.root.find(id).is_none() {
+            return false;
+        }
+        match event_id {
+            "clicked" => self.handler.event(DBusMenuEvent::Clicked(id)),
+            "opened" if id == 0 => self.handler.event(DBusMenuEvent::Opened),
+            "closed" if id == 0 => self.handler.event(DBusMenuEvent::Closed),
+            _ => {}
+        }
+        true
+    }
+
+    /// Returns true if the node was a lazy submenu, its children were
+    /// replaced and the revision bumped
+    fn about_to_show_id(&mut self, id: i32) -> bool {
+        if id == 0 {
+            self.handler.event(DBusMenuEvent::AboutToShow);
+        }
+        if self.handler.populate(&mut self.root, id) {
+            self.revision += 1;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[interface(name = "com.canonical.dbusmenu")]
+impl DBusMenu {
+    #[zbus(out_args("revision", "layout"))]
+    fn get_layout(
+        &self,
+        parent_id: i32,
+        recursion_depth: i32,
+        property_names: Vec<String>,
+    ) -> zbus::fdo::Result<(u32, Layout)> {
+        let parent = self.root.find(parent_id).ok_or_else(|| {
+            zbus::fdo::Error::InvalidArgs(format!("Unknown menu item {}", parent_id))
+        })?;
+        Ok((
+            self.revision,
+            parent.layout(recursion_depth, &property_names),
+        ))
+    }
+
+    fn get_group_properties(
+        &self,
+        ids: Vec<i32>,
+        property_names: Vec<String>,
+    ) -> Vec<(i32, Properties)> {
+        ids.into_iter()
+            .filter_map(|id| self.root.find(id))
+            .map(|node| (node.id, node.properties(&property_names)))
+            .collect()
+    }
+
+    fn get_property(&self, id: i32, name: String) -> zbus::fdo::Result<OwnedValue> {
+        self.root
+            .find(id)
+            .and_then(|node| node.properties(std::slice::from_ref(&name)).remove(&name))
+            .ok_or_else(|| zbus::fdo::Error::InvalidArgs(format!("No {} on {}", name, id)))
+    }
+
+    fn event(&self, id: i32, event_id: &str, _data: Value<'_>, _timestamp: u32) {
+        self.dispatch(id, event_id);
+    }
+
+    /// Returns ids that were not found
+    fn event_group(&self, events: Vec<(i32, String, OwnedValue, u32)>) -> Vec<i32> {
+        events
+            .into_iter()
+            .filter(|(id, event_id, ..)| !self.dispatch(*id, event_id))
+            .map(|(id, ..)| id)
+            .collect()
+    }
+
+    /// Only lazy submenus change on show, the host is told to fetch their
+    /// layout again
+    async fn about_to_show(
+        &mut self,
+        id: i32,
+        #[zbus(signal_emitter)] emitter: SignalEmitter<'_>,
+    ) -> bool {
+        let populated = self.about_to_show_id(id);
+        if populated {
+            let _ = Self::layout_updated(&emitter, self.revision, id).await;
+        }
+        populated
+    }
+
+    #[zbus(out_args("updatesNeeded", "idErrors"))]
+    async fn about_to_show_group(
+        &mut self,
+        ids: Vec<i32>,
+        #[zbus(signal_emitter)] emitter: SignalEmitter<'_>,
+    ) -> (Vec<i32>, Vec<i32>) {
+        let (updates, errors) = ids
+            .into_iter()
+            .partition::<Vec<_>, _>(|id| self.root.find(*id).is_some());
+        let updates = updates
+            .into_iter()
+            .filter(|id| self.about_to_show_id(*id))
+            .collect::<Vec<_>>();
+        for id in &updates {
+            let _ = Self::layout_updated(&emitter, self.revision, *id).await;
+        }
+        (updates, errors)
+    }
+
+    #[zbus(property)]
+    fn version(&self) -> u32 {
+        3
+    }
+
+    #[zbus(property)]
+    fn text_direction(&self) -> String {
+        "ltr".into()
+    }
+
+    #[zbus(property)]
+    fn status(&self) -> String {
+        "normal".into()
+    }
+
+    #[zbus(property)]
+    fn icon_theme_path(&self) -> Vec<String> {
+        vec![]
+    }
+
+    #[zbus(signal)]
+    pub async fn layout_updated(
+        emitter: &SignalEmitter<'_>,
+        revision: u32,
+        parent: i32,
+    ) -> zbus::Result<()>;
+
+    #[zbus(signal)]
+    pub async fn items_properties_updated(
+        emitter: &SignalEmitter<'_>,
+        updated_props: Vec<(i32, Properties)>,
+        removed_props: Vec<(i32, Vec<String>)>,
+    ) -> zbus::Result<()>;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::icon::ico;
+    use crate::MenuLabel;
+
+    fn menu() -> MenuNode {
+        let node = |id, kind, label: &str, children| MenuNode {
+            id,
+            kind,
+            label: label.into(),
+            disabled: id == 3,
+            icon: None,
+            children,
+        };
+        MenuNode::root(vec![
+            node(1, MenuNodeKind::Checkable(true), "Check", vec![]),
+            node(
+                2,
+                MenuNodeKind::Submenu,
+                "Sub",
+                vec![node(3, MenuNodeKind::Standard, "Disabled", vec![])],
+            ),
+            node(4, MenuNodeKind::Separator, "", vec![]),
+            MenuNode {
+                label: MenuLabel::new("Save_as")
+                    .with_mnemonic('v')
+                    .with_accelerator("Ctrl+Shift+S"),
+                ..node(5, MenuNodeKind::Radio(false), "", vec![])
+            },
+        ])
+    }
+
+    #[test]
+    fn test_properties() {
+        let root = menu();
+        let check = root.find(1).unwrap().properties(&[]);
+        assert_eq!(check.len(), 3);
+        assert_eq!(check["toggle-state"], OwnedValue::from(1i32));
+        assert_eq!(
+            root.find(3).unwrap().properties(&[])["enabled"],
+            OwnedValue::from(false)
+        );
+        let separator = root.find(4).unwrap().properties(&[]);
+        assert_eq!(separator.keys().collect::<Vec<_>>(), vec!["type"]);
+        let radio = root.find(5).unwrap().properties(&[]);
+        assert_eq!(radio["toggle-type"], OwnedValue::from(Str::from("radio")));
+        assert_eq!(radio["toggle-state"], OwnedValue::from(0i32));
+        assert_eq!(radio["label"], OwnedValue::from(Str::from("Sa_ve__as")));
+        assert_eq!(
+            <Vec<Vec<String>>>::try_from(radio["shortcut"].try_clone().unwrap()).unwrap(),
+            vec![vec!["Control", "Shift", "s"]]
+        );
+        assert_eq!(shortcut("F5"), Some(vec!["F5".to_string()]));
+
+        // Icons are sent as PNG
+        let mut icon = root.find(1).unwrap().clone();
+        let dot = IcoImage {
+            width: 2,
+            height: 1,
+            rgba: vec![0, 255, 0, 255, 0, 0, 0, 0],
+        };
+        icon.icon = Some(dot.clone());
+        let png = Vec::<u8>::try_from(icon.properties(&[])["icon-data"].try_clone().unwrap());
+        assert_eq!(ico::decode_png(&png.unwrap()), Ok(dot));
+        assert_eq!(shortcut("Hyper+Q"), None);
+
+        // Only asked properties
+        let label = root.find(2).unwrap().properties(&["label".into()]);
+        assert_eq!(label.keys().collect::<Vec<_>>(), vec!["label"]);
+    }
+
+    #[test]
+    fn test_layout_depth() {
+        let root = menu();
+        assert_eq!(root.layout(0, &[]).2.len(), 0);
+        assert_eq!(root.layout(1, &[]).2.len(), 4);
+
+        let (id, _, children) = root.find(2).unwrap().layout(-1, &[]);
+        assert_eq!(id, 2);
+        assert_eq!(children.len(), 1);
+        let (child_id, _, _) = <Layout>::try_from(children[0].try_clone().unwrap()).unwrap();
+        assert_eq!(child_id, 3);
+    }
+
+    #[test]
+    fn test_events() {
+        let clicked = std::sync::Arc::new(std::sync::Mutex::new(vec![]));
+        let mut dbusmenu = DBusMenu::new();
+        let c = clicked.clone();
+        assert_eq!(dbusmenu.set(menu(), move |e| c.lock().unwrap().push(e)), 1);
+
+        assert!(dbusmenu.dispatch(3, "clicked"));
+        assert!(dbusmenu.dispatch(1, "hovered"));
+        assert!(!dbusmenu.dispatch(42, "clicked"));
+        dbusmenu.about_to_show_id(0);
+        assert!(dbusmenu.dispatch(0, "opened"));
+        assert!(dbusmenu.dispatch(2, "closed"));
+        assert!(dbusmenu.dispatch(0, "closed"));
+        assert_eq!(
+            *clicked.lock().unwrap(),
+            vec![
+                DBusMenuEvent::Clicked(3),
+                DBusMenuEvent::AboutToShow,
+                DBusMenuEvent::Opened,
+                DBusMenuEvent::Closed
+            ]
+        );
+
+        // Same layout with other properties keeps the revision
+        let mut root = menu();
+        root.children[0].kind = MenuNodeKind::Checkable(false);
+        root.children[1].children[0].disabled = false;
+        let (mut updated, removed) = dbusmenu.update(root, |_| {});
+        updated.sort_by_key(|(id, _)| *id);
+        assert_eq!(
+            updated.iter().map(|(id, _)| *id).collect::<Vec<_>>(),
+            vec![1, 3]
+        );
+        assert_eq!(updated[0].1["toggle-state"], OwnedValue::from(0i32));
+        assert_eq!(removed, vec![(3, vec!["enabled".to_string()])]);
+        assert_eq!(dbusmenu.revision, 1);
+    }
+
+    #[test]
+    fn test_about_to_show_populates() {
+        struct Lazy;
+        impl DBusMenuHandler for Lazy {
+            fn event(&self, _event: DBusMenuEvent) {}
+
+            fn populate(&mut self, root: &mut MenuNode, id: i32) -> bool {
+                match root.find_mut(id).filter(|_| id == 2) {
+                    Some(node) => {
+                        node.children = vec![MenuNode {
+                            id: 6,
+                            ..node.children[0].clone()
+                        }];
+                        true
+                    }
+                    None => false,
+                }
+            }
+        }
+
+        let mut dbusmenu = DBusMenu::new();
+        assert_eq!(dbusmenu.set(menu(), Lazy), 1);
+        assert!(!dbusmenu.about_to_show_id(0));
+        assert_eq!(dbusmenu.revision, 1);
+
+        // Host must fetch the new layout of the submenu
+        assert!(dbusmenu.about_to_show_id(2));
+        assert_eq!(dbusmenu.revision, 2);
+        assert!(dbusmenu.root.find(3).is_none());
+        assert_eq!(dbusmenu.root.find(6).unwrap().label.text(), "Disabled");
+    }
+}
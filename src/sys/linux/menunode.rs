@@ -0,0 +1,69 @@
+use crate::icon::ico::IcoImage;
+use crate::MenuLabel;
+
+/// Width of the item icons, hosts scale them to their own size
+pub const MENU_ICON_SIZE: u32 = 16;
+
+/// Single node of the menu, shared by the dbusmenu export and the XEmbed
+/// popup menu
+///
+/// Id 0 is the root, other ids are numbered depth first. Each node gets an
+/// id, separators and submenus included, so the same menu always gets the
+/// same ids.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MenuNode {
+    pub id: i32,
+    pub kind: MenuNodeKind,
+    pub label: MenuLabel,
+    pub disabled: bool,
+    pub icon: Option<IcoImage>,
+    pub children: Vec<MenuNode>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MenuNodeKind {
+    Standard,
+    Checkable(bool),
+    Radio(bool),
+    Submenu,
+    Separator,
+}
+
+impl MenuNode {
+    pub fn root(children: Vec<MenuNode>) -> MenuNode {
+        MenuNode {
+            id: 0,
+            kind: MenuNodeKind::Submenu,
+            label: MenuLabel::default(),
+            disabled: false,
+            icon: None,
+            children,
+        }
+    }
+
+    /// Find node by id
+    ///
+    /// Nested menus are searched with a stack, not recursion.
+    pub fn find(&self, id: i32) -> Option<&MenuNode> {
+        let mut stack = vec![self];
+        while let Some(node) = stack.pop() {
+            if node.id == id {
+                return Some(node);
+            }
+            stack.extend(node.children.iter());
+        }
+        None
+    }
+
+    /// Find node by id for changing it
+    pub fn find_mut(&mut self, id: i32) -> Option<&mut MenuNode> {
+        let mut stack = vec![self];
+        while let Some(node) = stack.pop() {
+            if node.id == id {
+                return Some(node);
+            }
+            stack.extend(node.children.iter_mut());
+        }
+        None
+    }
+}
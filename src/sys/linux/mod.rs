@@ -0,0 +1,549 @@
+mod dbusmenu;
+mod menunode;
+mod sniicon;
+mod sniitem;
+mod snitrayicon;
+mod xembedmenu;
+mod xembedtrayicon;
+
+use std::borrow::Cow;
+use std::collections::HashMap;
+use zbus::{blocking::fdo::DBusProxy, blocking::Connection, names::BusName};
+
+use crate::trayevent::EventDispatcher;
+use crate::{
+    Capabilities, DeliveryStats, Error, Icon, MenuBuilder, MenuChange, MenuItem, MenuLabel,
+    MenuProvider, TrayIconBase, TrayIconBuilder,
+};
+use menunode::{MenuNode, MenuNodeKind, MENU_ICON_SIZE};
+use snitrayicon::SniTrayIcon;
+use xembedtrayicon::XEmbedTrayIcon;
+
+// Linux implementations of Icon, TrayIcon, and Menu
+pub use self::LinuxTrayIcon as TrayIconSys;
+pub use sniicon::SniIcon as IconSys;
+
+/// Ids the tray icon can send, see `TrayIconBuilder::build`
+///
+/// The backends send the events from threads of their own, e.g. the D-Bus
+/// connection's executor, so the ids must be `Send + Sync` on Linux.
+pub trait BackendId: Send + Sync {}
+
+impl<T> BackendId for T where T: Send + Sync {}
+
+/// Tray icon on whichever tray the desktop has
+///
+/// StatusNotifierItem is used when a watcher is running, the XEmbed system
+/// tray of X11 is the fallback.
+#[derive(Debug)]
+pub enum LinuxTrayIcon<T>
+where
+    T: PartialEq + Clone + Send + Sync + 'static,
+{
+    Sni(SniTrayIcon<T>),
+    XEmbed(XEmbedTrayIcon<T>),
+}
+
+impl<T> TrayIconBase<T> for LinuxTrayIcon<T>
+where
+    T: PartialEq + Clone + Send + Sync + 'static,
+{
+    fn set_icon(&mut self, icon: &Icon) -> Result<(), Error> {
+        match self {
+            LinuxTrayIcon::Sni(t) => t.set_icon(icon),
+            LinuxTrayIcon::XEmbed(t) => t.set_icon(icon),
+        }
+    }
+
+    fn set_menu(&mut self, menu: &MenuBuilder<T>) -> Result<(), Error> {
+        match self {
+            LinuxTrayIcon::Sni(t) => t.set_menu(menu),
+            LinuxTrayIcon::XEmbed(t) => t.set_menu(menu),
+        }
+    }
+
+    fn update_menu(
+        &mut self,
+        menu: &MenuBuilder<T>,
+        changes: &[MenuChange<T>],
+    ) -> Result<(), Error> {
+        match self {
+            LinuxTrayIcon::Sni(t) => t.update_menu(menu, changes),
+            LinuxTrayIcon::XEmbed(t) => t.update_menu(menu, changes),
+        }
+    }
+
+    fn set_tooltip(&mut self, tooltip: &str) -> Result<(), Error> {
+        match self {
+            LinuxTrayIcon::Sni(t) => t.set_tooltip(tooltip),
+            LinuxTrayIcon::XEmbed(t) => t.set_tooltip(tooltip),
+        }
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        match self {
+            LinuxTrayIcon::Sni(t) => t.capabilities(),
+            LinuxTrayIcon::XEmbed(t) => t.capabilities(),
+        }
+    }
+
+    fn delivery_stats(&self) -> DeliveryStats {
+        match self {
+            LinuxTrayIcon::Sni(t) => t.delivery_stats(),
+            LinuxTrayIcon::XEmbed(t) => t.delivery_stats(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct MenuSys<T>
+where
+    T: PartialEq + Clone + 'static,
+{
+    ids: MenuIds<T>,
+    root: MenuNode,
+}
+
+/// What the node ids of a menu stand for
+#[derive(Debug)]
+pub struct MenuIds<T>
+where
+    T: PartialEq + Clone + 'static,
+{
+    /// Ids of the items with `T`
+    events: HashMap<i32, T>,
+
+    /// Providers of the lazy submenus
+    lazy: HashMap<i32, MenuProvider<T>>,
+
+    /// Last id taken
+    last: i32,
+}
+
+impl<T> MenuIds<T>
+where
+    T: PartialEq + Clone + 'static,
+{
+    pub fn get(&self, id: i32) -> Option<&T> {
+        self.events.get(&id)
+    }
+
+    /// Fill the lazy submenu `id` of `root` from its provider, returns false
+    /// if the node is not a lazy submenu
+    ///
+    /// The items of the previous opening are forgotten, the new ones take
+    /// fresh ids so the host doesn't mix them up. Panic of the provider is
+    /// given to `report`.
+    pub fn populate(&mut self, root: &mut MenuNode, id: i32, report: &dyn Fn(&Error)) -> bool {
+        let provider = match self.lazy.get(&id) {
+            Some(provider) => provider.clone(),
+            None => return false,
+        };
+        let node = match root.find_mut(id) {
+            Some(node) => node,
+            None => return false,
+        };
+        let mut stack = node.children.iter().collect::<Vec<_>>();
+        while let Some(child) = stack.pop() {
+            self.events.remove(&child.id);
+            self.lazy.remove(&child.id);
+            stack.extend(child.children.iter());
+        }
+        node.children = build_menu_inner(self, &provider.provide_or_report(report));
+        true
+    }
+}
+
+/// Build the tray icon
+pub fn build_trayicon<T>(builder: &TrayIconBuilder<T>) -> Result<TrayIconSys<T>, Error>
+where
+    T: PartialEq + Clone + Send + Sync + 'static,
+{
+    if let Ok(connection) = Connection::session() {
+        if has_watcher(&connection) {
+            return build_trayicon_on(connection, builder).map(LinuxTrayIcon::Sni);
+        }
+    }
+    build_xembed(None, builder).map(LinuxTrayIcon::XEmbed)
+}
+
+fn has_watcher(connection: &Connection) -> bool {
+    let name = BusName::from_static_str(sniitem::WATCHER_NAME).ok();
+    DBusProxy::new(connection)
+        .ok()
+        .zip(name)
+        .and_then(|(p, name)| p.name_has_owner(name).ok())
+        .unwrap_or(false)
+}
+
+/// Build the tray icon as StatusNotifierItem on given D-Bus connection
+fn build_trayicon_on<T>(
+    connection: Connection,
+    builder: &TrayIconBuilder<T>,
+) -> Result<SniTrayIcon<T>, Error>
+where
+    T: PartialEq + Clone + Send + Sync + 'static,
+{
+    let mut menu: Option<MenuSys<T>> = None;
+    let icon = &builder.icon.as_ref()?.sys;
+    let events = EventDispatcher::new(builder)?;
+
+    // Try to get a popup menu
+    if let Some(rmenu) = &builder.menu {
+        menu = Some(rmenu.build()?);
+    }
+
+    SniTrayIcon::new(connection, events, menu, icon, &builder.tooltip)
+}
+
+/// Build the tray icon as XEmbed window on given X display
+fn build_xembed<T>(
+    display: Option<&str>,
+    builder: &TrayIconBuilder<T>,
+) -> Result<XEmbedTrayIcon<T>, Error>
+where
+    T: PartialEq + Clone + Send + 'static,
+{
+    let mut menu: Option<MenuSys<T>> = None;
+    let icon = &builder.icon.as_ref()?.sys;
+    let events = EventDispatcher::new(builder)?;
+
+    // Try to get a popup menu
+    if let Some(rmenu) = &builder.menu {
+        menu = Some(rmenu.build()?);
+    }
+
+    XEmbedTrayIcon::new(display, events, menu, icon, &builder.tooltip)
+}
+
+/// Build the menu as a tree of numbered nodes
+pub fn build_menu<T>(builder: &MenuBuilder<T>) -> Result<MenuSys<T>, Error>
+where
+    T: PartialEq + Clone + 'static,
+{
+    let mut ids = MenuIds {
+        events: HashMap::new(),
+        lazy: HashMap::new(),
+        last: 0,
+    };
+    let children = build_menu_inner(&mut ids, builder);
+    Ok(MenuSys {
+        ids,
+        root: MenuNode::root(children),
+    })
+}
+
+/// Recursive menu builder
+///
+/// Taking the next id from `ids` it's capable of handling nested submenus.
+/// Every node takes an id, but only the ones with `T` are mapped. Lazy
+/// submenus are left empty until they are populated.
+fn build_menu_inner<T>(ids: &mut MenuIds<T>, builder: &MenuBuilder<T>) -> Vec<MenuNode>
+where
+    T: PartialEq + Clone + 'static,
+{
+    builder
+        .menu_items
+        .iter()
+        .map(|item| {
+            ids.last += 1;
+            let node_id = ids.last;
+            let (kind, label, disabled, children) = match item {
+                MenuItem::Submenu {
+                    id,
+                    name,
+                    children,
+                    disabled,
+                    ..
+                } => {
+                    if let Some(id) = id {
+                        ids.events.insert(node_id, id.clone());
+                    }
+                    let children = build_menu_inner(ids, children);
+                    (MenuNodeKind::Submenu, name.clone(), *disabled, children)
+                }
+
+                MenuItem::LazySubmenu {
+                    id,
+                    name,
+                    provider,
+                    disabled,
+                    ..
+                } => {
+                    if let Some(id) = id {
+                        ids.events.insert(node_id, id.clone());
+                    }
+                    ids.lazy.insert(node_id, provider.clone());
+                    (MenuNodeKind::Submenu, name.clone(), *disabled, vec![])
+                }
+
+                MenuItem::Checkable {
+                    name,
+                    is_checked,
+                    id,
+                    disabled,
+                    ..
+                } => {
+                    ids.events.insert(node_id, id.clone());
+                    let kind = MenuNodeKind::Checkable(*is_checked);
+                    (kind, name.clone(), *disabled, vec![])
+                }
+
+                MenuItem::Radio {
+                    name,
+                    is_selected,
+                    id,
+                    disabled,
+                    ..
+                } => {
+                    ids.events.insert(node_id, id.clone());
+                    let kind = MenuNodeKind::Radio(*is_selected);
+                    (kind, name.clone(), *disabled, vec![])
+                }
+
+                MenuItem::Item {
+                    name, id, disabled, ..
+                } => {
+                    ids.events.insert(node_id, id.clone());
+                    (MenuNodeKind::Standard, name.clone(), *disabled, vec![])
+                }
+
+                MenuItem::Separator => {
+                    (MenuNodeKind::Separator, MenuLabel::default(), false, vec![])
+                }
+            };
+            MenuNode {
+                id: node_id,
+                kind,
+                label,
+                disabled,
+                icon: item
+                    .icon()
+                    .and_then(|i| i.image_sized(MENU_ICON_SIZE))
+                    .map(Cow::into_owned),
+                children,
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::{
+        io::{BufRead, BufReader},
+        process::{Child, Command, Stdio},
+        sync::{Arc, Mutex},
+    };
+    use zbus::interface;
+
+    #[derive(Copy, Clone, Eq, PartialEq, Debug)]
+    enum Events {
+        CheckableItem1,
+        Item1,
+        SubItem1,
+        SubItem2,
+        SubItem3,
+        SubItem4,
+        SubSubItem1,
+        SubSubItem2,
+        SubSubItem3,
+    }
+
+    /// Private `dbus-daemon`, killed on drop
+    pub(crate) struct TestBus {
+        daemon: Child,
+        address: String,
+    }
+
+    impl TestBus {
+        /// Start a bus, the tests using it are ignored by default as they
+        /// need `dbus-daemon`
+        pub(crate) fn start() -> TestBus {
+            let mut daemon = Command::new("dbus-daemon")
+                .args(["--session", "--nofork", "--print-address"])
+                .stdout(Stdio::piped())
+                .stderr(Stdio::null())
+                .spawn()
+                .expect("dbus-daemon is needed, run the ignored tests only where it's installed");
+            let mut address = String::new();
+            BufReader::new(daemon.stdout.take().unwrap())
+                .read_line(&mut address)
+                .unwrap();
+            TestBus {
+                daemon,
+                address: address.trim().to_string(),
+            }
+        }
+
+        pub(crate) fn connect(&self) -> Connection {
+            zbus::blocking::connection::Builder::address(self.address.as_str())
+                .unwrap()
+                .build()
+                .unwrap()
+        }
+    }
+
+    impl Drop for TestBus {
+        fn drop(&mut self) {
+            let _ = self.daemon.kill();
+            let _ = self.daemon.wait();
+        }
+    }
+
+    /// Stand-in for the panel's `org.kde.StatusNotifierWatcher`
+    pub(crate) struct TestWatcher {
+        pub(crate) items: Arc<Mutex<Vec<String>>>,
+    }
+
+    #[interface(name = "org.kde.StatusNotifierWatcher")]
+    impl TestWatcher {
+        fn register_status_notifier_item(&self, service: String) {
+            self.items.lock().unwrap().push(service);
+        }
+    }
+
+    impl TestWatcher {
+        /// Own the watcher name on the bus, returns the registered items
+        pub(crate) fn serve(bus: &TestBus) -> (Connection, Arc<Mutex<Vec<String>>>) {
+            let items = Arc::new(Mutex::new(vec![]));
+            let connection = bus.connect();
+            connection
+                .object_server()
+                .at(
+                    sniitem::WATCHER_PATH,
+                    TestWatcher {
+                        items: items.clone(),
+                    },
+                )
+                .unwrap();
+            connection.request_name(sniitem::WATCHER_NAME).unwrap();
+            (connection, items)
+        }
+    }
+
+    #[test]
+    fn test_menu_build() {
+        let cond = false;
+        let builder = MenuBuilder::new()
+            .checkable("This is checkable", true, Events::CheckableItem1)
+            .submenu(
+                "Sub Menu",
+                MenuBuilder::new()
+                    .item("Sub item 1", Events::SubItem1)
+                    .item("Sub Item 2", Events::SubItem2)
+                    .item("Sub Item 3", Events::SubItem3)
+                    .submenu(
+                        "Sub Sub menu",
+                        MenuBuilder::new()
+                            .item("Sub Sub item 1", Events::SubSubItem1)
+                            .item("Sub Sub Item 2", Events::SubSubItem2)
+                            .item("Sub Sub Item 3", Events::SubSubItem3),
+                    )
+                    .when(|f| {
+                        if cond {
+                            f.item("Foo", Events::Item1)
+                        } else {
+                            f
+                        }
+                    })
+                    .item("Sub Item 4", Events::SubItem4),
+            )
+            .item("Item 1", Events::Item1);
+
+        let menusys = build_menu(&builder).unwrap();
+        assert_eq!(menusys.ids.events.len(), 9);
+        assert_eq!(menusys.ids.get(8), Some(&Events::SubSubItem2));
+
+        // Submenus and separators take ids too
+        let subsub = menusys.root.find(6).unwrap();
+        assert_eq!(subsub.kind, MenuNodeKind::Submenu);
+        assert_eq!(subsub.label.text(), "Sub Sub menu");
+        assert_eq!(subsub.children.len(), 3);
+        assert_eq!(menusys.root.find(11).unwrap().label.text(), "Item 1");
+        assert_eq!(build_menu(&builder).unwrap().root, menusys.root);
+    }
+
+    #[test]
+    fn test_menu_populate() {
+        let opened = Arc::new(Mutex::new(0));
+        let o = opened.clone();
+        let builder = MenuBuilder::new()
+            .lazy_submenu("Lazy", move || {
+                let mut opened = o.lock().unwrap();
+                *opened += 1;
+                let sub = MenuBuilder::new().item("Sub Sub item 1", Events::SubSubItem1);
+                MenuBuilder::new()
+                    .item("Sub item 1", Events::SubItem1)
+                    .when(|m| {
+                        if *opened > 1 {
+                            m.lazy_submenu("Nested", move || sub.clone())
+                        } else {
+                            m
+                        }
+                    })
+            })
+            .item("Item 1", Events::Item1);
+
+        let mut menusys = build_menu(&builder).unwrap();
+        let MenuSys { ids, root } = &mut menusys;
+        assert_eq!(root.find(1).unwrap().kind, MenuNodeKind::Submenu);
+        assert!(root.find(1).unwrap().children.is_empty());
+        assert_eq!(*opened.lock().unwrap(), 0);
+        assert!(!ids.populate(root, 2, &|_| {}));
+
+        // Each opening makes the items again with fresh ids
+        assert!(ids.populate(root, 1, &|_| {}));
+        assert_eq!(root.find(3).unwrap().label.text(), "Sub item 1");
+        assert!(ids.populate(root, 1, &|_| {}));
+        assert_eq!(*opened.lock().unwrap(), 2);
+        assert!(root.find(3).is_none());
+        assert_eq!(ids.get(3), None);
+        assert_eq!(ids.get(4), Some(&Events::SubItem1));
+        assert!(ids.populate(root, 5, &|_| {}));
+        assert_eq!(ids.get(6), Some(&Events::SubSubItem1));
+
+        // Nested lazy submenus are forgotten with their parent's items
+        assert!(ids.populate(root, 1, &|_| {}));
+        assert!(!ids.populate(root, 5, &|_| {}));
+        assert_eq!(ids.get(6), None);
+        assert_eq!(ids.get(2), Some(&Events::Item1));
+    }
+
+    #[test]
+    fn test_menu_populate_panic() {
+        let builder = MenuBuilder::new()
+            .lazy_submenu("Lazy", || -> MenuBuilder<Events> { panic!("No items") });
+        let mut menusys = build_menu(&builder).unwrap();
+        let MenuSys { ids, root } = &mut menusys;
+        let errors = Mutex::new(vec![]);
+        assert!(ids.populate(root, 1, &|e| errors.lock().unwrap().push(e.clone())));
+        assert!(root.find(1).unwrap().children.is_empty());
+        assert_eq!(
+            *errors.lock().unwrap(),
+            vec![Error::CallbackPanicked {
+                message: "No items".into()
+            }]
+        );
+    }
+
+    #[test]
+    #[ignore = "needs dbus-daemon"]
+    fn test_build_without_watcher() {
+        let bus = TestBus::start();
+        let (s, _r) = std::sync::mpsc::channel::<Events>();
+        let builder = TrayIconBuilder::new()
+            .sender(s)
+            .icon_from_buffer(include_bytes!("../../testresource/icon1.ico"));
+        match build_trayicon_on(bus.connect(), &builder).err() {
+            Some(Error::DBus { call, name, .. }) => {
+                assert_eq!(call, "RegisterStatusNotifierItem");
+                assert_eq!(
+                    name.as_deref(),
+                    Some("org.freedesktop.DBus.Error.ServiceUnknown")
+                );
+            }
+            e => panic!("unexpected {:?}", e),
+        }
+    }
+}
@@ -0,0 +1,113 @@
+use crate::icon::ico::IcoDir;
+use crate::{Error, IconBase};
+
+/// Single ARGB32 image, as StatusNotifierItem expects it
+///
+/// Pixels are in network byte order (A, R, G, B), rows top to bottom.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SniPixmap {
+    pub width: i32,
+    pub height: i32,
+    pub argb: Vec<u8>,
+}
+
+/// Purpose of this struct is to keep the decoded icon images in the form the
+/// `IconPixmap` property of StatusNotifierItem is sent over D-Bus
+#[derive(Debug, Clone, PartialEq)]
+pub struct SniIcon {
+    pub pixmaps: Vec<SniPixmap>,
+}
+
+impl IconBase for SniIcon {
+    fn from_buffer(
+        buffer: &[u8],
+        width: Option<u32>,
+        height: Option<u32>,
+    ) -> Result<SniIcon, Error> {
+        // Without explicit size all sizes are offered to the host
+        let images = IcoDir::parse(buffer)?.images(width, height)?;
+        Ok(SniIcon {
+            pixmaps: images
+                .iter()
+                .map(|i| SniPixmap::from_rgba(i.width, i.height, &i.rgba))
+                .collect(),
+        })
+    }
+
+    fn from_rgba(width: u32, height: u32, rgba: &[u8]) -> Result<SniIcon, Error> {
+        Ok(SniIcon {
+            pixmaps: vec![SniPixmap::from_rgba(width, height, rgba)],
+        })
+    }
+}
+
+impl SniPixmap {
+    pub fn from_rgba(width: u32, height: u32, rgba: &[u8]) -> SniPixmap {
+        let argb = rgba
+            .chunks_exact(4)
+            .flat_map(|p| [p[3], p[0], p[1], p[2]])
+            .collect();
+        SniPixmap {
+            width: width as i32,
+            height: height as i32,
+            argb,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_icon_all_sizes() {
+        let icon = SniIcon::from_buffer(include_bytes!("../../testresource/icon1.ico"), None, None)
+            .unwrap();
+        let sizes = icon
+            .pixmaps
+            .iter()
+            .map(|p| (p.width, p.height))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            sizes,
+            vec![(16, 16), (24, 24), (32, 32), (48, 48), (64, 64)]
+        );
+        assert!(icon
+            .pixmaps
+            .iter()
+            .all(|p| p.argb.len() == (p.width * p.height * 4) as usize));
+    }
+
+    #[test]
+    fn test_icon_closest_size() {
+        let icon = SniIcon::from_buffer(
+            include_bytes!("../../testresource/icon1.ico"),
+            Some(30),
+            Some(30),
+        )
+        .unwrap();
+        assert_eq!(icon.pixmaps.len(), 1);
+        assert_eq!(icon.pixmaps[0].width, 32);
+    }
+
+    #[test]
+    fn test_icon_garbage() {
+        assert_eq!(
+            SniIcon::from_buffer(b"not an icon", None, None),
+            Err(Error::IconLoadingFailed)
+        );
+    }
+
+    #[test]
+    fn test_icon_from_rgba() {
+        let icon = SniIcon::from_rgba(1, 2, &[1, 2, 3, 4, 5, 6, 7, 8]).unwrap();
+        assert_eq!(
+            icon.pixmaps,
+            vec![SniPixmap {
+                width: 1,
+                height: 2,
+                argb: vec![4, 1, 2, 3, 8, 5, 6, 7]
+            }]
+        );
+    }
+}
@@ -0,0 +1,197 @@
+use super::{dbusmenu::MENU_PATH, sniicon::SniIcon};
+use crate::ScrollOrientation;
+use std::time::{Duration, Instant};
+use zbus::{
+    interface,
+    object_server::SignalEmitter,
+    zvariant::{ObjectPath, OwnedObjectPath},
+};
+
+pub const ITEM_PATH: &str = "/StatusNotifierItem";
+pub const WATCHER_NAME: &str = "org.kde.StatusNotifierWatcher";
+pub const WATCHER_PATH: &str = "/StatusNotifierWatcher";
+pub const WATCHER_INTERFACE: &str = "org.kde.StatusNotifierWatcher";
+
+/// Two activations within this time are reported as a double click
+///
+/// StatusNotifierItem hosts only call `Activate`, double click must be
+/// deduced from the timing.
+const DOUBLE_CLICK_TIME: Duration = Duration::from_millis(500);
+
+type IconPixmaps = Vec<(i32, i32, Vec<u8>)>;
+
+/// Activation requested by the StatusNotifierItem host, at the screen
+/// coordinates the host gave
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SniEvent {
+    Click(i32, i32),
+    DoubleClick(i32, i32),
+    RightClick(i32, i32),
+    MiddleClick(i32, i32),
+
+    /// Wheel turned by the delta, positive up or right
+    Scroll(i32, ScrollOrientation),
+}
+
+/// The `org.kde.StatusNotifierItem` D-Bus object
+///
+/// It's owned by the object server and called from the connection's executor
+/// thread, so it doesn't know about the event type `T`, the events are given
+/// to the handler instead.
+pub struct SniItem {
+    pub id: String,
+    pub tooltip: String,
+    pub icon: SniIcon,
+    pub has_menu: bool,
+    handler: Box<dyn Fn(SniEvent) + Send + Sync>,
+    last_activate: Option<Instant>,
+}
+
+impl SniItem {
+    pub fn new<F>(id: String, tooltip: String, icon: SniIcon, has_menu: bool, handler: F) -> SniItem
+    where
+        F: Fn(SniEvent) + Send + Sync + 'static,
+    {
+        SniItem {
+            id,
+            tooltip,
+            icon,
+            has_menu,
+            handler: Box::new(handler),
+            last_activate: None,
+        }
+    }
+
+    fn pixmaps(&self) -> IconPixmaps {
+        self.icon
+            .pixmaps
+            .iter()
+            .map(|p| (p.width, p.height, p.argb.clone()))
+            .collect()
+    }
+}
+
+#[interface(name = "org.kde.StatusNotifierItem")]
+impl SniItem {
+    fn activate(&mut self, x: i32, y: i32) {
+        (self.handler)(SniEvent::Click(x, y));
+        let now = Instant::now();
+        match self.last_activate {
+            Some(last) if now.duration_since(last) < DOUBLE_CLICK_TIME => {
+                (self.handler)(SniEvent::DoubleClick(x, y));
+                self.last_activate = None;
+            }
+            _ => self.last_activate = Some(now),
+        }
+    }
+
+    fn secondary_activate(&self, x: i32, y: i32) {
+        (self.handler)(SniEvent::MiddleClick(x, y));
+    }
+
+    fn context_menu(&self, x: i32, y: i32) {
+        (self.handler)(SniEvent::RightClick(x, y));
+    }
+
+    fn scroll(&self, delta: i32, orientation: &str) {
+        let orientation = if orientation.eq_ignore_ascii_case("horizontal") {
+            ScrollOrientation::Horizontal
+        } else if orientation.eq_ignore_ascii_case("vertical") {
+            ScrollOrientation::Vertical
+        } else {
+            return;
+        };
+        (self.handler)(SniEvent::Scroll(delta, orientation));
+    }
+
+    #[zbus(property)]
+    fn category(&self) -> String {
+        "ApplicationStatus".into()
+    }
+
+    #[zbus(property)]
+    fn id(&self) -> String {
+        self.id.clone()
+    }
+
+    #[zbus(property)]
+    fn title(&self) -> String {
+        if self.tooltip.is_empty() {
+            self.id.clone()
+        } else {
+            self.tooltip.clone()
+        }
+    }
+
+    #[zbus(property)]
+    fn status(&self) -> String {
+        "Active".into()
+    }
+
+    #[zbus(property)]
+    fn window_id(&self) -> i32 {
+        0
+    }
+
+    #[zbus(property)]
+    fn icon_name(&self) -> String {
+        String::new()
+    }
+
+    #[zbus(property)]
+    fn icon_pixmap(&self) -> IconPixmaps {
+        self.pixmaps()
+    }
+
+    #[zbus(property)]
+    fn overlay_icon_name(&self) -> String {
+        String::new()
+    }
+
+    #[zbus(property)]
+    fn overlay_icon_pixmap(&self) -> IconPixmaps {
+        vec![]
+    }
+
+    #[zbus(property)]
+    fn attention_icon_name(&self) -> String {
+        String::new()
+    }
+
+    #[zbus(property)]
+    fn attention_icon_pixmap(&self) -> IconPixmaps {
+        vec![]
+    }
+
+    #[zbus(property)]
+    fn attention_movie_name(&self) -> String {
+        String::new()
+    }
+
+    #[zbus(property)]
+    fn tool_tip(&self) -> (String, IconPixmaps, String, String) {
+        (String::new(), vec![], self.tooltip.clone(), String::new())
+    }
+
+    #[zbus(property)]
+    fn item_is_menu(&self) -> bool {
+        false
+    }
+
+    /// Root path means there is no exported menu, the host calls
+    /// `ContextMenu` instead
+    #[zbus(property)]
+    fn menu(&self) -> OwnedObjectPath {
+        let path = if self.has_menu { MENU_PATH } else { "/" };
+        ObjectPath::from_static_str_unchecked(path).into()
+    }
+
+    #[zbus(signal)]
+    pub async fn new_title(emitter: &SignalEmitter<'_>) -> zbus::Result<()>;
+
+    #[zbus(signal)]
+    pub async fn new_icon(emitter: &SignalEmitter<'_>) -> zbus::Result<()>;
+
+    #[zbus(signal)]
+    pub async fn new_tool_tip(emitter: &SignalEmitter<'_>) -> zbus::Result<()>;
+}
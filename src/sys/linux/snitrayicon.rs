@@ -0,0 +1,682 @@
+use super::dbusmenu::{DBusMenu, DBusMenuEvent, DBusMenuHandler, MENU_PATH};
+use super::menunode::MenuNode;
+use super::sniicon::SniIcon;
+use super::sniitem::{SniEvent, SniItem, ITEM_PATH, WATCHER_INTERFACE, WATCHER_NAME, WATCHER_PATH};
+use super::{MenuIds, MenuSys};
+use crate::trayevent::EventDispatcher;
+use crate::{
+    Capabilities, DeliveryStats, Error, EventInfo, Icon, MenuBuilder, MenuChange, MouseButton,
+    TrayEvent, TrayIconBase,
+};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use zbus::blocking::{fdo::DBusProxy, object_server::InterfaceRef, Connection};
+use zbus::DBusError;
+
+/// Events of a single tray icon
+///
+/// The D-Bus object calls this from the connection's executor thread.
+struct SniEvents<T>
+where
+    T: PartialEq + Clone + Send + Sync + 'static,
+{
+    events: EventDispatcher<T>,
+}
+
+impl<T> SniEvents<T>
+where
+    T: PartialEq + Clone + Send + Sync + 'static,
+{
+    fn dispatch(&self, event: SniEvent) {
+        let e = match event {
+            SniEvent::Click(x, y) => {
+                TrayEvent::Click(EventInfo::now(Some(MouseButton::Left)).at(x, y))
+            }
+            SniEvent::DoubleClick(x, y) => {
+                TrayEvent::DoubleClick(EventInfo::now(Some(MouseButton::Left)).at(x, y))
+            }
+            SniEvent::RightClick(x, y) => {
+                TrayEvent::RightClick(EventInfo::now(Some(MouseButton::Right)).at(x, y))
+            }
+            SniEvent::MiddleClick(x, y) => {
+                TrayEvent::MiddleClick(EventInfo::now(Some(MouseButton::Middle)).at(x, y))
+            }
+            SniEvent::Scroll(delta, orientation) => TrayEvent::Scroll {
+                delta,
+                orientation,
+                info: EventInfo::now(None),
+            },
+        };
+        self.events.send(e);
+    }
+}
+
+/// Events of the menu, the ids are replaced with the menu
+struct SniMenuEvents<T>
+where
+    T: PartialEq + Clone + Send + Sync + 'static,
+{
+    events: EventDispatcher<T>,
+    ids: MenuIds<T>,
+}
+
+impl<T> DBusMenuHandler for SniMenuEvents<T>
+where
+    T: PartialEq + Clone + Send + Sync + 'static,
+{
+    fn event(&self, event: DBusMenuEvent) {
+        // The host doesn't tell the button or the position of menu events
+        let info = EventInfo::now(None);
+        let e = match event {
+            DBusMenuEvent::Clicked(id) => match self.ids.get(id) {
+                Some(id) => TrayEvent::MenuItem {
+                    id: id.clone(),
+                    info,
+                },
+                None => return,
+            },
+
+            // Host shows the menu by itself, instead of calling `ContextMenu`
+            DBusMenuEvent::AboutToShow => {
+                TrayEvent::RightClick(EventInfo::now(Some(MouseButton::Right)))
+            }
+            DBusMenuEvent::Opened => TrayEvent::MenuOpen(info),
+            DBusMenuEvent::Closed => TrayEvent::MenuClose(info),
+        };
+        self.events.send(e);
+    }
+
+    fn populate(&mut self, root: &mut MenuNode, id: i32) -> bool {
+        let events = &self.events;
+        self.ids.populate(root, id, &|e| events.report(e))
+    }
+}
+
+/// Tray Icon as a StatusNotifierItem
+///
+/// Each tray icon has its own D-Bus connection, it owns the item's bus name
+/// and closing it removes the item from the host.
+#[derive(Debug)]
+pub struct SniTrayIcon<T>
+where
+    T: PartialEq + Clone + Send + Sync + 'static,
+{
+    connection: Connection,
+    events: EventDispatcher<T>,
+}
+
+impl<T> SniTrayIcon<T>
+where
+    T: PartialEq + Clone + Send + Sync + 'static,
+{
+    pub(crate) fn new(
+        connection: Connection,
+        events: EventDispatcher<T>,
+        menu: Option<MenuSys<T>>,
+        icon: &SniIcon,
+        tooltip: &Option<String>,
+    ) -> Result<SniTrayIcon<T>, Error> {
+        static ITEM_ID: AtomicUsize = AtomicUsize::new(0);
+        let name = format!(
+            "org.kde.StatusNotifierItem-{}-{}",
+            std::process::id(),
+            ITEM_ID.fetch_add(1, Ordering::Relaxed) + 1
+        );
+
+        let item_events = SniEvents {
+            events: events.clone(),
+        };
+        let item = SniItem::new(
+            app_id(),
+            tooltip.clone().unwrap_or_default(),
+            icon.clone(),
+            false,
+            move |e| item_events.dispatch(e),
+        );
+        connection
+            .object_server()
+            .at(ITEM_PATH, item)
+            .map_err(dbus_error("ObjectServer::at"))?;
+        connection
+            .object_server()
+            .at(MENU_PATH, DBusMenu::new())
+            .map_err(dbus_error("ObjectServer::at"))?;
+        let trayicon = SniTrayIcon { connection, events };
+        if let Some(menu) = menu {
+            trayicon.export_menu(menu)?;
+        }
+        let connection = &trayicon.connection;
+        connection
+            .request_name(name.as_str())
+            .map_err(dbus_error("RequestName"))?;
+
+        // Register again each time the watcher appears, e.g. when the panel
+        // restarts. Subscription is made before the first registration so no
+        // restart gets lost in between.
+        let owner_changes = DBusProxy::new(connection)
+            .and_then(|p| p.receive_name_owner_changed_with_args(&[(0, WATCHER_NAME)]))
+            .map_err(dbus_error("AddMatch"))?;
+        register(connection, &name)?;
+        {
+            let connection = connection.clone();
+            std::thread::spawn(move || {
+                for signal in owner_changes {
+                    let appeared = signal
+                        .args()
+                        .map(|a| a.new_owner().is_some())
+                        .unwrap_or(false);
+                    if appeared {
+                        let _ = register(&connection, &name);
+                    }
+                }
+            });
+        }
+
+        Ok(trayicon)
+    }
+
+    /// Replace the exported menu and tell the host to fetch it again
+    fn export_menu(&self, menu: MenuSys<T>) -> Result<(), Error> {
+        let has_menu = !menu.root.children.is_empty();
+        let events = self.menu_events(menu.ids);
+        let dbusmenu = self.dbusmenu()?;
+        let revision = dbusmenu.get_mut().set(menu.root, events);
+        zbus::block_on(DBusMenu::layout_updated(
+            dbusmenu.signal_emitter(),
+            revision,
+            0,
+        ))
+        .map_err(dbus_error("LayoutUpdated"))?;
+
+        let item = self.item()?;
+        if item.get().has_menu != has_menu {
+            item.get_mut().has_menu = has_menu;
+            zbus::block_on(item.get().menu_changed(item.signal_emitter()))
+                .map_err(dbus_error("PropertiesChanged"))?;
+        }
+        Ok(())
+    }
+
+    /// Replace the exported menu that has the same layout, only changed
+    /// properties are sent to the host
+    fn update_menu_properties(&self, menu: MenuSys<T>) -> Result<(), Error> {
+        let events = self.menu_events(menu.ids);
+        let dbusmenu = self.dbusmenu()?;
+        let (updated, removed) = dbusmenu.get_mut().update(menu.root, events);
+        if updated.is_empty() && removed.is_empty() {
+            return Ok(());
+        }
+        zbus::block_on(DBusMenu::items_properties_updated(
+            dbusmenu.signal_emitter(),
+            updated,
+            removed,
+        ))
+        .map_err(dbus_error("ItemsPropertiesUpdated"))
+    }
+
+    fn menu_events(&self, ids: MenuIds<T>) -> SniMenuEvents<T> {
+        SniMenuEvents {
+            events: self.events.clone(),
+            ids,
+        }
+    }
+
+    fn dbusmenu(&self) -> Result<InterfaceRef<DBusMenu>, Error> {
+        self.connection
+            .object_server()
+            .interface::<_, DBusMenu>(MENU_PATH)
+            .map_err(dbus_error("ObjectServer::interface"))
+    }
+
+    fn item(&self) -> Result<InterfaceRef<SniItem>, Error> {
+        self.connection
+            .object_server()
+            .interface::<_, SniItem>(ITEM_PATH)
+            .map_err(dbus_error("ObjectServer::interface"))
+    }
+}
+
+impl<T> TrayIconBase<T> for SniTrayIcon<T>
+where
+    T: PartialEq + Clone + Send + Sync + 'static,
+{
+    /// Hosts call `SecondaryActivate` and `Scroll` as they like, they can't
+    /// be asked
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            middle_click: true,
+            scroll: true,
+        }
+    }
+
+    fn delivery_stats(&self) -> DeliveryStats {
+        self.events.stats()
+    }
+
+    /// Set the tooltip
+    fn set_tooltip(&mut self, tooltip: &str) -> Result<(), Error> {
+        let item = self.item()?;
+        item.get_mut().tooltip = tooltip.to_string();
+        zbus::block_on(SniItem::new_tool_tip(item.signal_emitter()))
+            .map_err(dbus_error("NewToolTip"))?;
+        zbus::block_on(SniItem::new_title(item.signal_emitter())).map_err(dbus_error("NewTitle"))
+    }
+
+    /// Set icon
+    fn set_icon(&mut self, icon: &Icon) -> Result<(), Error> {
+        let item = self.item()?;
+        item.get_mut().icon = icon.sys.clone();
+        zbus::block_on(SniItem::new_icon(item.signal_emitter())).map_err(dbus_error("NewIcon"))
+    }
+
+    /// Set menu
+    fn set_menu(&mut self, menu: &MenuBuilder<T>) -> Result<(), Error> {
+        self.export_menu(menu.build()?)
+    }
+
+    fn update_menu(
+        &mut self,
+        menu: &MenuBuilder<T>,
+        changes: &[MenuChange<T>],
+    ) -> Result<(), Error> {
+        if changes.iter().all(MenuChange::is_update) {
+            self.update_menu_properties(menu.build()?)
+        } else {
+            self.set_menu(menu)
+        }
+    }
+}
+
+impl<T> Drop for SniTrayIcon<T>
+where
+    T: PartialEq + Clone + Send + Sync + 'static,
+{
+    fn drop(&mut self) {
+        // Closing releases the bus name, which unregisters the item and stops
+        // the watcher thread
+        let _ = self.connection.clone().close();
+    }
+}
+
+fn register(connection: &Connection, name: &str) -> Result<(), Error> {
+    connection
+        .call_method(
+            Some(WATCHER_NAME),
+            WATCHER_PATH,
+            Some(WATCHER_INTERFACE),
+            "RegisterStatusNotifierItem",
+            &(name,),
+        )
+        .map(|_| ())
+        .map_err(dbus_error("RegisterStatusNotifierItem"))
+}
+
+/// Error of failed D-Bus call, the error name is kept if the peer sent one
+fn dbus_error(call: &'static str) -> impl Fn(zbus::Error) -> Error {
+    move |e| {
+        let name = match &e {
+            zbus::Error::MethodError(name, ..) => Some(name.to_string()),
+            zbus::Error::FDO(e) => Some(e.name().to_string()),
+            _ => None,
+        };
+        Error::DBus {
+            call,
+            name,
+            message: e.to_string(),
+        }
+    }
+}
+
+/// Application id shown by hosts, the executable name
+fn app_id() -> String {
+    std::env::current_exe()
+        .ok()
+        .and_then(|p| p.file_stem().map(|s| s.to_string_lossy().into_owned()))
+        .unwrap_or_else(|| "trayicon".into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::{TestBus, TestWatcher};
+    use super::*;
+    use crate::{ScrollOrientation, TrayIconBuilder};
+    use std::{collections::HashMap, convert::TryFrom, time::Duration};
+    use zbus::{blocking::fdo::PropertiesProxy, names::InterfaceName};
+
+    #[derive(Copy, Clone, Eq, PartialEq, Debug)]
+    enum Events {
+        Click,
+        DoubleClick,
+        RightClick,
+        MenuOpen,
+        MenuClose,
+        MiddleClick,
+        Scroll(i32, ScrollOrientation),
+        Item1,
+        Item2,
+    }
+
+    fn build(bus: &TestBus) -> (SniTrayIcon<Events>, std::sync::mpsc::Receiver<Events>) {
+        let (s, r) = std::sync::mpsc::channel();
+        let builder = TrayIconBuilder::new()
+            .sender(s)
+            .icon_from_buffer(include_bytes!("../../testresource/icon1.ico"))
+            .tooltip("Cool Tray Icon")
+            .on_click(Events::Click)
+            .on_double_click(Events::DoubleClick)
+            .on_right_click(Events::RightClick)
+            .on_menu_open(Events::MenuOpen)
+            .on_menu_close(Events::MenuClose)
+            .on_middle_click(Events::MiddleClick)
+            .on_scroll(|delta, orientation| Some(Events::Scroll(delta, orientation)))
+            .menu(
+                MenuBuilder::new()
+                    .item("Item 1", Events::Item1)
+                    .separator()
+                    .submenu("Sub Menu", MenuBuilder::new().item("Item 2", Events::Item2)),
+            );
+        let trayicon = super::super::build_trayicon_on(bus.connect(), &builder).unwrap();
+        (trayicon, r)
+    }
+
+    fn call(connection: &Connection, name: &str, method: &str) {
+        connection
+            .call_method(
+                Some(name),
+                ITEM_PATH,
+                Some("org.kde.StatusNotifierItem"),
+                method,
+                &(0i32, 0i32),
+            )
+            .unwrap();
+    }
+
+    fn property(
+        connection: &Connection,
+        name: &str,
+        path: &'static str,
+        interface: &'static str,
+        property: &str,
+    ) -> zbus::zvariant::OwnedValue {
+        PropertiesProxy::builder(connection)
+            .destination(name.to_string())
+            .unwrap()
+            .path(path)
+            .unwrap()
+            .build()
+            .unwrap()
+            .get(
+                InterfaceName::from_static_str_unchecked(interface),
+                property,
+            )
+            .unwrap()
+    }
+
+    fn item_property(connection: &Connection, name: &str, p: &str) -> zbus::zvariant::OwnedValue {
+        property(connection, name, ITEM_PATH, "org.kde.StatusNotifierItem", p)
+    }
+
+    fn call_menu<B, R>(connection: &Connection, name: &str, method: &str, body: &B) -> R
+    where
+        B: zbus::export::serde::ser::Serialize + zbus::zvariant::DynamicType,
+        R: for<'d> zbus::zvariant::DynamicDeserialize<'d>,
+    {
+        connection
+            .call_method(
+                Some(name),
+                MENU_PATH,
+                Some("com.canonical.dbusmenu"),
+                method,
+                body,
+            )
+            .unwrap()
+            .body()
+            .deserialize()
+            .unwrap()
+    }
+
+    #[test]
+    #[ignore = "needs dbus-daemon"]
+    fn test_register_and_activate() {
+        let bus = TestBus::start();
+        let (_watcher, items) = TestWatcher::serve(&bus);
+        let (_trayicon, r) = build(&bus);
+        let name = items.lock().unwrap()[0].clone();
+        assert!(name.starts_with("org.kde.StatusNotifierItem-"));
+
+        let host = bus.connect();
+        call(&host, &name, "Activate");
+        call(&host, &name, "Activate");
+        call(&host, &name, "ContextMenu");
+        call(&host, &name, "SecondaryActivate");
+        for orientation in &["vertical", "Horizontal", "diagonal"] {
+            host.call_method(
+                Some(name.as_str()),
+                ITEM_PATH,
+                Some("org.kde.StatusNotifierItem"),
+                "Scroll",
+                &(-120i32, *orientation),
+            )
+            .unwrap();
+        }
+        let timeout = Duration::from_secs(5);
+        assert_eq!(r.recv_timeout(timeout), Ok(Events::Click));
+        assert_eq!(r.recv_timeout(timeout), Ok(Events::Click));
+        assert_eq!(r.recv_timeout(timeout), Ok(Events::DoubleClick));
+        assert_eq!(r.recv_timeout(timeout), Ok(Events::RightClick));
+        assert_eq!(r.recv_timeout(timeout), Ok(Events::MiddleClick));
+        assert_eq!(
+            r.recv_timeout(timeout),
+            Ok(Events::Scroll(-120, ScrollOrientation::Vertical))
+        );
+        assert_eq!(
+            r.recv_timeout(timeout),
+            Ok(Events::Scroll(-120, ScrollOrientation::Horizontal))
+        );
+        assert!(r.recv_timeout(Duration::from_millis(100)).is_err());
+    }
+
+    #[cfg(feature = "async")]
+    #[test]
+    #[ignore = "needs dbus-daemon"]
+    fn test_async_stream() {
+        use futures_lite::{future::block_on, StreamExt};
+
+        let bus = TestBus::start();
+        let (_watcher, items) = TestWatcher::serve(&bus);
+        let (s, stream) = async_channel::unbounded();
+        let builder = TrayIconBuilder::new()
+            .sender_async(s)
+            .icon_from_buffer(include_bytes!("../../testresource/icon1.ico"))
+            .on_click(Events::Click)
+            .on_right_click(Events::RightClick)
+            .on_middle_click(Events::MiddleClick);
+        let trayicon = super::super::build_trayicon_on(bus.connect(), &builder).unwrap();
+        drop(builder);
+        let name = items.lock().unwrap()[0].clone();
+
+        // Each call returns after the event is sent
+        futures_lite::pin!(stream);
+        let host = bus.connect();
+        call(&host, &name, "ContextMenu");
+        call(&host, &name, "SecondaryActivate");
+        call(&host, &name, "Activate");
+        let events = block_on(async {
+            vec![
+                stream.next().await,
+                stream.next().await,
+                stream.next().await,
+            ]
+        });
+        assert_eq!(
+            events,
+            vec![
+                Some(Events::RightClick),
+                Some(Events::MiddleClick),
+                Some(Events::Click)
+            ]
+        );
+
+        drop(trayicon);
+        assert_eq!(block_on(stream.next()), None);
+    }
+
+    #[test]
+    #[ignore = "needs dbus-daemon"]
+    fn test_rich_events() {
+        let bus = TestBus::start();
+        let (_watcher, items) = TestWatcher::serve(&bus);
+        let (s, r) = std::sync::mpsc::channel();
+        let builder = TrayIconBuilder::<Events>::new()
+            .event_sender(s)
+            .icon_from_buffer(include_bytes!("../../testresource/icon1.ico"));
+        let _trayicon = super::super::build_trayicon_on(bus.connect(), &builder).unwrap();
+        let name = items.lock().unwrap()[0].clone();
+
+        let host = bus.connect();
+        host.call_method(
+            Some(name.as_str()),
+            ITEM_PATH,
+            Some("org.kde.StatusNotifierItem"),
+            "ContextMenu",
+            &(12i32, 34i32),
+        )
+        .unwrap();
+        let e = r.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert!(matches!(e, TrayEvent::RightClick(_)));
+        assert_eq!(e.info().button, Some(MouseButton::Right));
+        assert_eq!(e.info().position, Some((12, 34)));
+    }
+
+    #[test]
+    #[ignore = "needs dbus-daemon"]
+    fn test_properties() {
+        let bus = TestBus::start();
+        let (_watcher, items) = TestWatcher::serve(&bus);
+        let (mut trayicon, _r) = build(&bus);
+        let name = items.lock().unwrap()[0].clone();
+        let host = bus.connect();
+
+        let title = item_property(&host, &name, "Title");
+        assert_eq!(String::try_from(title).unwrap(), "Cool Tray Icon");
+        trayicon.set_tooltip("Changed").unwrap();
+        let title = item_property(&host, &name, "Title");
+        assert_eq!(String::try_from(title).unwrap(), "Changed");
+
+        let pixmaps = item_property(&host, &name, "IconPixmap");
+        let pixmaps = <Vec<(i32, i32, Vec<u8>)>>::try_from(pixmaps).unwrap();
+        assert_eq!(pixmaps.len(), 5);
+        assert_eq!(pixmaps[0].2.len(), 16 * 16 * 4);
+    }
+
+    #[test]
+    #[ignore = "needs dbus-daemon"]
+    fn test_menu() {
+        let bus = TestBus::start();
+        let (_watcher, items) = TestWatcher::serve(&bus);
+        let (mut trayicon, r) = build(&bus);
+        let name = items.lock().unwrap()[0].clone();
+        let host = bus.connect();
+
+        let path = item_property(&host, &name, "Menu");
+        let path = zbus::zvariant::OwnedObjectPath::try_from(path).unwrap();
+        assert_eq!(path.as_str(), MENU_PATH);
+        let version = property(&host, &name, MENU_PATH, "com.canonical.dbusmenu", "Version");
+        assert_eq!(u32::try_from(version).unwrap(), 3);
+
+        type Layout = (
+            i32,
+            HashMap<String, zbus::zvariant::OwnedValue>,
+            Vec<zbus::zvariant::OwnedValue>,
+        );
+        let no_props: Vec<String> = vec![];
+        let (revision, (_, _, children)): (u32, Layout) =
+            call_menu(&host, &name, "GetLayout", &(0i32, -1i32, no_props.clone()));
+        assert_eq!(revision, 1);
+        assert_eq!(children.len(), 3);
+
+        let props: Vec<(i32, HashMap<String, zbus::zvariant::OwnedValue>)> = call_menu(
+            &host,
+            &name,
+            "GetGroupProperties",
+            &(vec![1i32, 4], vec!["label".to_string()]),
+        );
+        let labels = props
+            .into_iter()
+            .map(|(id, mut p)| (id, String::try_from(p.remove("label").unwrap()).unwrap()))
+            .collect::<Vec<_>>();
+        assert_eq!(labels, vec![(1, "Item 1".into()), (4, "Item 2".into())]);
+
+        let needs_update: bool = call_menu(&host, &name, "AboutToShow", &(0i32,));
+        assert!(!needs_update);
+        let event = |id: i32, event_id: &str| {
+            let data = zbus::zvariant::Value::from(0i32);
+            let () = call_menu(&host, &name, "Event", &(id, event_id, data, 0u32));
+        };
+        event(0, "opened");
+        event(4, "clicked");
+        event(0, "closed");
+        let timeout = Duration::from_secs(5);
+        assert_eq!(r.recv_timeout(timeout), Ok(Events::RightClick));
+        assert_eq!(r.recv_timeout(timeout), Ok(Events::MenuOpen));
+        assert_eq!(r.recv_timeout(timeout), Ok(Events::Item2));
+        assert_eq!(r.recv_timeout(timeout), Ok(Events::MenuClose));
+
+        // Changed properties keep the revision
+        let menu = |label| {
+            MenuBuilder::new()
+                .item("Item 1", Events::Item1)
+                .separator()
+                .submenu("Sub Menu", MenuBuilder::new().item(label, Events::Item2))
+        };
+        let changes = menu("Item 2").diff(&menu("Renamed"));
+        trayicon.update_menu(&menu("Renamed"), &changes).unwrap();
+        let (revision, _): (u32, Layout) =
+            call_menu(&host, &name, "GetLayout", &(0i32, -1i32, no_props.clone()));
+        assert_eq!(revision, 1);
+        let label: zbus::zvariant::OwnedValue =
+            call_menu(&host, &name, "GetProperty", &(4i32, "label"));
+        assert_eq!(
+            String::try_from(label.try_clone().unwrap()).unwrap(),
+            "Renamed"
+        );
+
+        // Replaced menu has new revision, and empty menu is not advertised
+        trayicon
+            .set_menu(&MenuBuilder::new().item("Item 2", Events::Item2))
+            .unwrap();
+        let (revision, (_, _, children)): (u32, Layout) =
+            call_menu(&host, &name, "GetLayout", &(0i32, -1i32, no_props));
+        assert_eq!(revision, 2);
+        assert_eq!(children.len(), 1);
+        trayicon.set_menu(&MenuBuilder::new()).unwrap();
+        let path = item_property(&host, &name, "Menu");
+        let path = zbus::zvariant::OwnedObjectPath::try_from(path).unwrap();
+        assert_eq!(path.as_str(), "/");
+    }
+
+    #[test]
+    #[ignore = "needs dbus-daemon"]
+    fn test_register_again_and_drop() {
+        let bus = TestBus::start();
+        let (watcher, items) = TestWatcher::serve(&bus);
+        let (trayicon, _r) = build(&bus);
+        let name = items.lock().unwrap()[0].clone();
+
+        // Panel restarts
+        watcher.close().unwrap();
+        let (_watcher, items) = TestWatcher::serve(&bus);
+        for _ in 0..50 {
+            if !items.lock().unwrap().is_empty() {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(100));
+        }
+        assert_eq!(*items.lock().unwrap(), vec![name.clone()]);
+
+        // Dropping the tray icon releases the name
+        drop(trayicon);
+        let dbus = DBusProxy::new(&bus.connect()).unwrap();
+        let name = zbus::names::BusName::try_from(name).unwrap();
+        assert!(!dbus.name_has_owner(name).unwrap());
+    }
+}
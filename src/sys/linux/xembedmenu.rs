@@ -0,0 +1,1047 @@
+use super::menunode::{MenuNode, MenuNodeKind, MENU_ICON_SIZE};
+use super::sniicon::SniPixmap;
+use super::xembedtrayicon::{compose, encode_pixels};
+use crate::icon::ico::IcoImage;
+use x11rb::{
+    connection::Connection,
+    errors::{ConnectionError, ReplyOrIdError},
+    protocol::xproto::*,
+    rust_connection::RustConnection,
+    COPY_DEPTH_FROM_PARENT, COPY_FROM_PARENT, CURRENT_TIME, NONE,
+};
+
+/// Space above and below the rows
+const MENU_PADDING: u16 = 2;
+
+/// Space above and below the label of an item
+const ITEM_PADDING: u16 = 4;
+
+/// Column left of the labels, for the checkmarks
+const CHECK_WIDTH: u16 = 22;
+
+/// Column between the checkmarks and the labels, for the item icons. Only
+/// menus with icons have it.
+const ICON_WIDTH: u16 = MENU_ICON_SIZE as u16 + 4;
+
+/// Column right of the labels, for the submenu arrows
+const ARROW_WIDTH: u16 = 22;
+
+/// Space between the label and the accelerator
+const ACCELERATOR_GAP: u16 = 16;
+
+const SEPARATOR_HEIGHT: u16 = 7;
+const MIN_WIDTH: u16 = 120;
+
+// Keysyms the menu reacts to
+const XK_ESCAPE: Keysym = 0xff1b;
+const XK_RETURN: Keysym = 0xff0d;
+const XK_KP_ENTER: Keysym = 0xff8d;
+const XK_LEFT: Keysym = 0xff51;
+const XK_UP: Keysym = 0xff52;
+const XK_RIGHT: Keysym = 0xff53;
+const XK_DOWN: Keysym = 0xff54;
+
+// Latin-1 keysyms are the same as the characters, so they may be mnemonics
+const MNEMONIC_FIRST: Keysym = 0x21;
+const MNEMONIC_LAST: Keysym = 0xff;
+
+/// Colors as pixel values of the root visual
+#[derive(Debug, Clone, Copy)]
+pub struct Palette {
+    pub background: u32,
+    pub text: u32,
+    pub disabled: u32,
+    pub highlight: u32,
+    pub highlight_text: u32,
+    pub border: u32,
+    pub tooltip: u32,
+}
+
+impl Palette {
+    fn new(visual: Option<&Visualtype>) -> Palette {
+        let pixel = |rgb| pixel(visual, rgb);
+        Palette {
+            background: pixel(0xf0f0f0),
+            text: pixel(0x000000),
+            disabled: pixel(0x8c8c8c),
+            highlight: pixel(0x3584e4),
+            highlight_text: pixel(0xffffff),
+            border: pixel(0xa0a0a0),
+            tooltip: pixel(0xffffe1),
+        }
+    }
+}
+
+/// Convert 0xRRGGBB to a pixel value of TrueColor visual
+///
+/// Other visual classes get only black and white.
+fn pixel(visual: Option<&Visualtype>, rgb: u32) -> u32 {
+    let channel = |mask: u32, value: u32| {
+        let bits = mask.count_ones().min(8);
+        ((value & 0xff) >> (8 - bits)) << mask.trailing_zeros()
+    };
+    match visual {
+        Some(v) if v.class == VisualClass::TRUE_COLOR => {
+            channel(v.red_mask, rgb >> 16)
+                | channel(v.green_mask, rgb >> 8)
+                | channel(v.blue_mask, rgb)
+        }
+        _ if rgb >= 0x808080 => 0xffffff,
+        _ => 0,
+    }
+}
+
+/// Text and shapes on the root visual, with the core "fixed" font
+///
+/// Core fonts need no extra libraries, and "fixed" is always there.
+#[derive(Debug)]
+pub struct Painter {
+    font: Font,
+    gc: Gcontext,
+    ascent: i16,
+    pub line_height: u16,
+    pub palette: Palette,
+
+    /// Depth and byte order of images, if the visual has the pixel format of
+    /// the icons
+    image_format: Option<(u8, ImageOrder)>,
+}
+
+impl Painter {
+    pub fn new(conn: &RustConnection, screen: &Screen) -> Result<Painter, ReplyOrIdError> {
+        let font = conn.generate_id()?;
+        conn.open_font(font, b"fixed")?;
+        let info = conn.query_font(font)?.reply()?;
+        let gc = conn.generate_id()?;
+        conn.create_gc(
+            gc,
+            screen.root,
+            &CreateGCAux::new()
+                .font(font)
+                .line_width(2)
+                .graphics_exposures(0),
+        )?;
+        let visual = screen
+            .allowed_depths
+            .iter()
+            .flat_map(|d| d.visuals.iter())
+            .find(|v| v.visual_id == screen.root_visual);
+        let image_format = visual
+            .filter(|v| {
+                v.class == VisualClass::TRUE_COLOR
+                    && (v.red_mask, v.green_mask, v.blue_mask) == (0xff0000, 0xff00, 0xff)
+                    && screen.root_depth >= 24
+            })
+            .map(|_| (screen.root_depth, conn.setup().image_byte_order));
+        Ok(Painter {
+            font,
+            gc,
+            ascent: info.font_ascent,
+            line_height: (info.font_ascent + info.font_descent).max(1) as u16,
+            palette: Palette::new(visual),
+            image_format,
+        })
+    }
+
+    /// Width of the text in pixels
+    pub fn text_width(&self, conn: &RustConnection, text: &str) -> u16 {
+        conn.query_text_extents(self.font, &char2b(text))
+            .ok()
+            .and_then(|c| c.reply().ok())
+            .map(|r| r.overall_width.clamp(0, u16::MAX as i32) as u16)
+            .unwrap_or(0)
+    }
+
+    /// Draw text with its top left corner at x, y
+    pub fn text(
+        &self,
+        conn: &RustConnection,
+        drawable: Drawable,
+        (color, background): (u32, u32),
+        x: i16,
+        y: i16,
+        text: &str,
+    ) -> Result<(), ConnectionError> {
+        conn.change_gc(
+            self.gc,
+            &ChangeGCAux::new().foreground(color).background(background),
+        )?;
+        conn.image_text16(drawable, self.gc, x, y + self.ascent, &char2b(text))?;
+        Ok(())
+    }
+
+    pub fn fill(
+        &self,
+        conn: &RustConnection,
+        drawable: Drawable,
+        color: u32,
+        rectangle: Rectangle,
+    ) -> Result<(), ConnectionError> {
+        conn.change_gc(self.gc, &ChangeGCAux::new().foreground(color))?;
+        conn.poly_fill_rectangle(drawable, self.gc, &[rectangle])?;
+        Ok(())
+    }
+
+    /// Draw the image scaled to `size` over the background, with its top
+    /// left corner at x, y
+    ///
+    /// Nothing is drawn on visuals with other pixel formats.
+    pub fn image(
+        &self,
+        conn: &RustConnection,
+        drawable: Drawable,
+        background: u32,
+        (x, y): (i16, i16),
+        size: u16,
+        image: &IcoImage,
+    ) -> Result<(), ConnectionError> {
+        let (depth, order) = match self.image_format {
+            Some(format) => format,
+            None => return Ok(()),
+        };
+        let pixmap = SniPixmap::from_rgba(image.width, image.height, &image.rgba);
+        let background = vec![background | 0xff00_0000; size as usize * size as usize];
+        let pixels = compose(&pixmap, size, size, background);
+        conn.put_image(
+            ImageFormat::Z_PIXMAP,
+            drawable,
+            self.gc,
+            size,
+            size,
+            x,
+            y,
+            0,
+            depth,
+            &encode_pixels(&pixels, order),
+        )?;
+        Ok(())
+    }
+
+    fn lines(
+        &self,
+        conn: &RustConnection,
+        drawable: Drawable,
+        color: u32,
+        points: &[Point],
+    ) -> Result<(), ConnectionError> {
+        conn.change_gc(self.gc, &ChangeGCAux::new().foreground(color))?;
+        conn.poly_line(CoordMode::ORIGIN, drawable, self.gc, points)?;
+        Ok(())
+    }
+
+    fn polygon(
+        &self,
+        conn: &RustConnection,
+        drawable: Drawable,
+        color: u32,
+        points: &[Point],
+    ) -> Result<(), ConnectionError> {
+        conn.change_gc(self.gc, &ChangeGCAux::new().foreground(color))?;
+        conn.fill_poly(
+            drawable,
+            self.gc,
+            PolyShape::CONVEX,
+            CoordMode::ORIGIN,
+            points,
+        )?;
+        Ok(())
+    }
+}
+
+/// Encode text for the 16 bit core text requests
+///
+/// Characters outside the Basic Multilingual Plane become '?', and the text
+/// is cut to the 255 characters a single request can draw.
+fn char2b(text: &str) -> Vec<Char2b> {
+    text.chars()
+        .take(255)
+        .map(|c| {
+            let c = if (c as u32) > 0xffff {
+                '?' as u32
+            } else {
+                c as u32
+            };
+            Char2b {
+                byte1: (c >> 8) as u8,
+                byte2: c as u8,
+            }
+        })
+        .collect()
+}
+
+/// Row of a menu window, in window coordinates
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MenuRow {
+    pub y: i16,
+    pub height: u16,
+}
+
+/// Size of a menu window and its rows, one row for each child of the node
+#[derive(Debug, Clone, PartialEq)]
+pub struct MenuGeometry {
+    pub width: u16,
+    pub height: u16,
+    pub rows: Vec<MenuRow>,
+
+    /// Left edge of the labels
+    pub label_x: u16,
+}
+
+impl MenuGeometry {
+    /// Measure the children of the node, `text_width` gives the width of a
+    /// label in pixels
+    pub fn measure<F>(node: &MenuNode, line_height: u16, text_width: F) -> MenuGeometry
+    where
+        F: Fn(&str) -> u16,
+    {
+        let has_icons = node.children.iter().any(|c| c.icon.is_some());
+        let (label_x, line_height) = if has_icons {
+            (
+                CHECK_WIDTH + ICON_WIDTH,
+                line_height.max(MENU_ICON_SIZE as u16),
+            )
+        } else {
+            (CHECK_WIDTH, line_height)
+        };
+        let mut width = MIN_WIDTH;
+        let mut y = MENU_PADDING as i16;
+        let rows = node
+            .children
+            .iter()
+            .map(|child| {
+                let height = if child.kind == MenuNodeKind::Separator {
+                    SEPARATOR_HEIGHT
+                } else {
+                    let accelerator = child
+                        .label
+                        .accelerator()
+                        .map_or(0, |a| ACCELERATOR_GAP + text_width(a));
+                    let label =
+                        label_x + text_width(child.label.text()) + accelerator + ARROW_WIDTH;
+                    width = width.max(label);
+                    line_height + ITEM_PADDING * 2
+                };
+                let row = MenuRow { y, height };
+                y += height as i16;
+                row
+            })
+            .collect();
+        MenuGeometry {
+            width,
+            height: y as u16 + MENU_PADDING,
+            rows,
+            label_x,
+        }
+    }
+
+    /// Index of the row at y
+    pub fn row_at(&self, y: i16) -> Option<usize> {
+        self.rows
+            .iter()
+            .position(|r| y >= r.y && (y as i32) < r.y as i32 + r.height as i32)
+    }
+}
+
+/// Separators and disabled items can't be highlighted or chosen
+fn selectable(node: &MenuNode) -> bool {
+    node.kind != MenuNodeKind::Separator && !node.disabled
+}
+
+/// Selectable child with the mnemonic of the Latin-1 keysym
+fn mnemonic_row(node: &MenuNode, keysym: Keysym) -> Option<usize> {
+    if !(MNEMONIC_FIRST..=MNEMONIC_LAST).contains(&keysym) {
+        return None;
+    }
+    let key = char::from_u32(keysym)?.to_lowercase().collect::<String>();
+    node.children.iter().position(|child| {
+        let mnemonic = child
+            .label
+            .mnemonic()
+            .map(|c| c.to_lowercase().collect::<String>());
+        selectable(child) && mnemonic.as_ref() == Some(&key)
+    })
+}
+
+/// Next selectable child after `from`, wrapping around
+fn next_selectable(node: &MenuNode, from: Option<usize>, forward: bool) -> Option<usize> {
+    let count = node.children.len();
+    (1..=count)
+        .map(|step| match (from, forward) {
+            (None, true) => step - 1,
+            (None, false) => count - step,
+            (Some(i), true) => (i + step) % count,
+            (Some(i), false) => (i + count * 2 - step) % count,
+        })
+        .find(|&i| selectable(&node.children[i]))
+}
+
+/// Position of the top level menu opened at the pointer
+///
+/// Menu opens down and right of the pointer, or up and left when there is
+/// no room on the screen.
+pub fn place_menu(pointer: (i16, i16), size: (u16, u16), screen: (u16, u16)) -> (i16, i16) {
+    let (x, y) = (pointer.0 as i32, pointer.1 as i32);
+    let (w, h) = (size.0 as i32, size.1 as i32);
+    let (sw, sh) = (screen.0 as i32, screen.1 as i32);
+    let x = if x + w > sw { sw - w } else { x };
+    let y = if y + h > sh { y - h } else { y };
+    (x.max(0) as i16, y.max(0) as i16)
+}
+
+/// Position of a submenu next to the row of its parent
+///
+/// Submenu opens right of the parent, or left when there is no room.
+pub fn place_submenu(
+    parent: (i16, i16, u16),
+    row_y: i16,
+    size: (u16, u16),
+    screen: (u16, u16),
+) -> (i16, i16) {
+    let (px, py, pw) = (parent.0 as i32, parent.1 as i32, parent.2 as i32);
+    let (w, h) = (size.0 as i32, size.1 as i32);
+    let (sw, sh) = (screen.0 as i32, screen.1 as i32);
+    let x = if px + pw + w > sw { px - w } else { px + pw };
+    let y = (py + row_y as i32).min(sh - h);
+    (x.max(0) as i16, y.max(0) as i16)
+}
+
+/// What the tray icon should do after an event on the menu
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MenuAction {
+    Nothing,
+
+    /// Item with id was chosen, the menu must be closed
+    Activate(i32),
+
+    /// Menu was dismissed
+    Close,
+}
+
+/// Single open menu window, the top level menu or a submenu
+#[derive(Debug)]
+struct Level {
+    window: Window,
+    node: i32,
+    x: i16,
+    y: i16,
+    geometry: MenuGeometry,
+    highlighted: Option<usize>,
+}
+
+impl Level {
+    /// Row at the root coordinates, outer option is `None` if the point is
+    /// not in this menu
+    fn row_at(&self, x: i16, y: i16) -> Option<Option<usize>> {
+        let (x, y) = (x as i32 - self.x as i32, y as i32 - self.y as i32);
+        let inside =
+            x >= 0 && y >= 0 && x < self.geometry.width as i32 && y < self.geometry.height as i32;
+        if inside {
+            Some(self.geometry.row_at(y as i16))
+        } else {
+            None
+        }
+    }
+}
+
+/// Fills the lazy submenu with the id before it opens, returns false if the
+/// node is not a lazy submenu
+pub type Populate<'a> = dyn FnMut(&mut MenuNode, i32) -> bool + 'a;
+
+/// Popup menu drawn with override-redirect windows
+///
+/// The top level menu grabs the pointer and keyboard, so every event comes
+/// here until the menu is closed. Positions are handled in root coordinates,
+/// the window of the event doesn't matter.
+#[derive(Debug)]
+pub struct PopupMenu {
+    levels: Vec<Level>,
+    screen: (u16, u16),
+    root: Window,
+}
+
+impl PopupMenu {
+    /// Open the menu at the pointer
+    pub fn open(
+        conn: &RustConnection,
+        painter: &Painter,
+        screen: &Screen,
+        menu: &MenuNode,
+        pointer: (i16, i16),
+    ) -> Result<PopupMenu, ReplyOrIdError> {
+        let mut popup = PopupMenu {
+            levels: vec![],
+            screen: (screen.width_in_pixels, screen.height_in_pixels),
+            root: screen.root,
+        };
+        let geometry =
+            MenuGeometry::measure(menu, painter.line_height, |t| painter.text_width(conn, t));
+        let (x, y) = place_menu(pointer, (geometry.width, geometry.height), popup.screen);
+        popup.push(conn, painter, menu.id, (x, y), geometry)?;
+
+        let window = popup.levels[0].window;
+        conn.grab_pointer(
+            true,
+            window,
+            EventMask::BUTTON_PRESS | EventMask::BUTTON_RELEASE | EventMask::POINTER_MOTION,
+            GrabMode::ASYNC,
+            GrabMode::ASYNC,
+            NONE,
+            NONE,
+            CURRENT_TIME,
+        )?;
+        conn.grab_keyboard(true, window, CURRENT_TIME, GrabMode::ASYNC, GrabMode::ASYNC)?;
+        Ok(popup)
+    }
+
+    /// Close all menu windows and release the grabs
+    pub fn close(&mut self, conn: &RustConnection) {
+        let _ = conn.ungrab_pointer(CURRENT_TIME);
+        let _ = conn.ungrab_keyboard(CURRENT_TIME);
+        self.close_from(conn, 0);
+    }
+
+    /// Redraw the menu window
+    pub fn expose(
+        &self,
+        conn: &RustConnection,
+        painter: &Painter,
+        menu: &MenuNode,
+        window: Window,
+    ) {
+        if let Some(level) = self.levels.iter().find(|l| l.window == window) {
+            let _ = draw(conn, painter, menu, level);
+        }
+    }
+
+    /// Highlight the row under the pointer, and open its submenu
+    pub fn motion(
+        &mut self,
+        conn: &RustConnection,
+        painter: &Painter,
+        menu: &mut MenuNode,
+        populate: &mut Populate<'_>,
+        pointer: (i16, i16),
+    ) {
+        let (l, row) = match self.hit(pointer) {
+            Some(hit) => hit,
+            None => return,
+        };
+        let node = match menu.find(self.levels[l].node) {
+            Some(node) => node,
+            None => return,
+        };
+
+        // Leaving the rows keeps the submenu open, the pointer may be on the
+        // way there
+        let row = row.filter(|&r| selectable(&node.children[r]));
+        if let Some(r) = row {
+            let is_submenu = node.children[r].kind == MenuNodeKind::Submenu;
+            self.highlight(conn, painter, menu, l, Some(r));
+            self.close_from(conn, l + 1);
+            if is_submenu {
+                let _ = self.open_submenu(conn, painter, menu, populate, l, r);
+            }
+        }
+    }
+
+    /// Button press outside of the menus dismisses them
+    pub fn button_press(&self, pointer: (i16, i16)) -> MenuAction {
+        match self.hit(pointer) {
+            Some(_) => MenuAction::Nothing,
+            None => MenuAction::Close,
+        }
+    }
+
+    /// Choose the row under the pointer
+    pub fn button_release(
+        &mut self,
+        conn: &RustConnection,
+        painter: &Painter,
+        menu: &mut MenuNode,
+        populate: &mut Populate<'_>,
+        pointer: (i16, i16),
+    ) -> MenuAction {
+        match self.hit(pointer) {
+            Some((l, Some(r))) => self.choose(conn, painter, menu, populate, l, r),
+            _ => MenuAction::Nothing,
+        }
+    }
+
+    /// Keyboard navigation of the innermost menu
+    pub fn key(
+        &mut self,
+        conn: &RustConnection,
+        painter: &Painter,
+        menu: &mut MenuNode,
+        populate: &mut Populate<'_>,
+        keysym: Keysym,
+    ) -> MenuAction {
+        let l = self.levels.len() - 1;
+        let node = match menu.find(self.levels[l].node) {
+            Some(node) => node,
+            None => return MenuAction::Close,
+        };
+        let highlighted = self.levels[l].highlighted;
+        match keysym {
+            XK_ESCAPE | XK_LEFT if l > 0 => self.close_from(conn, l),
+            XK_ESCAPE => return MenuAction::Close,
+            XK_UP | XK_DOWN => {
+                let row = next_selectable(node, highlighted, keysym == XK_DOWN);
+                self.highlight(conn, painter, menu, l, row);
+            }
+            XK_RIGHT | XK_RETURN | XK_KP_ENTER | MNEMONIC_FIRST..=MNEMONIC_LAST => {
+                let row = match keysym {
+                    XK_RIGHT | XK_RETURN | XK_KP_ENTER => highlighted,
+                    _ => mnemonic_row(node, keysym),
+                };
+                let r = match row {
+                    Some(r) => r,
+                    None => return MenuAction::Nothing,
+                };
+                let is_submenu = node.children[r].kind == MenuNodeKind::Submenu;
+                self.highlight(conn, painter, menu, l, Some(r));
+                if is_submenu {
+                    self.choose(conn, painter, menu, populate, l, r);
+                    if let Some(sub) = self.levels.get(l + 1) {
+                        let row = menu
+                            .find(sub.node)
+                            .and_then(|c| next_selectable(c, None, true));
+                        self.highlight(conn, painter, menu, l + 1, row);
+                    }
+                } else if keysym != XK_RIGHT {
+                    return self.choose(conn, painter, menu, populate, l, r);
+                }
+            }
+            _ => {}
+        }
+        MenuAction::Nothing
+    }
+
+    /// Innermost menu and its row at the root coordinates
+    fn hit(&self, (x, y): (i16, i16)) -> Option<(usize, Option<usize>)> {
+        self.levels
+            .iter()
+            .enumerate()
+            .rev()
+            .find_map(|(i, level)| level.row_at(x, y).map(|row| (i, row)))
+    }
+
+    fn choose(
+        &mut self,
+        conn: &RustConnection,
+        painter: &Painter,
+        menu: &mut MenuNode,
+        populate: &mut Populate<'_>,
+        l: usize,
+        r: usize,
+    ) -> MenuAction {
+        let child = match menu.find(self.levels[l].node) {
+            Some(node) => &node.children[r],
+            None => return MenuAction::Nothing,
+        };
+        if !selectable(child) {
+            return MenuAction::Nothing;
+        }
+        if child.kind != MenuNodeKind::Submenu {
+            return MenuAction::Activate(child.id);
+        }
+        let is_open = self
+            .levels
+            .get(l + 1)
+            .map(|sub| sub.node == child.id)
+            .unwrap_or(false);
+        if !is_open {
+            self.close_from(conn, l + 1);
+            let _ = self.open_submenu(conn, painter, menu, populate, l, r);
+        }
+        MenuAction::Nothing
+    }
+
+    fn highlight(
+        &mut self,
+        conn: &RustConnection,
+        painter: &Painter,
+        menu: &MenuNode,
+        l: usize,
+        row: Option<usize>,
+    ) {
+        if self.levels[l].highlighted != row {
+            self.levels[l].highlighted = row;
+            let _ = draw(conn, painter, menu, &self.levels[l]);
+        }
+    }
+
+    /// Open the submenu of the row, lazy submenus are populated first
+    fn open_submenu(
+        &mut self,
+        conn: &RustConnection,
+        painter: &Painter,
+        menu: &mut MenuNode,
+        populate: &mut Populate<'_>,
+        l: usize,
+        r: usize,
+    ) -> Result<(), ReplyOrIdError> {
+        let parent = &self.levels[l];
+        let id = match menu.find(parent.node) {
+            Some(node) => node.children[r].id,
+            None => return Ok(()),
+        };
+        populate(menu, id);
+        let node = match menu.find(id) {
+            Some(node) => node,
+            None => return Ok(()),
+        };
+        if node.children.is_empty() {
+            return Ok(());
+        }
+        let geometry =
+            MenuGeometry::measure(node, painter.line_height, |t| painter.text_width(conn, t));
+        let position = place_submenu(
+            (parent.x, parent.y, parent.geometry.width),
+            parent.geometry.rows[r].y,
+            (geometry.width, geometry.height),
+            self.screen,
+        );
+        self.push(conn, painter, node.id, position, geometry)
+    }
+
+    fn push(
+        &mut self,
+        conn: &RustConnection,
+        painter: &Painter,
+        node: i32,
+        (x, y): (i16, i16),
+        geometry: MenuGeometry,
+    ) -> Result<(), ReplyOrIdError> {
+        let window = conn.generate_id()?;
+        conn.create_window(
+            COPY_DEPTH_FROM_PARENT,
+            window,
+            self.root,
+            x,
+            y,
+            geometry.width,
+            geometry.height,
+            1,
+            WindowClass::INPUT_OUTPUT,
+            COPY_FROM_PARENT,
+            &CreateWindowAux::new()
+                .override_redirect(1)
+                .background_pixel(painter.palette.background)
+                .border_pixel(painter.palette.border)
+                .event_mask(
+                    EventMask::EXPOSURE
+                        | EventMask::BUTTON_PRESS
+                        | EventMask::BUTTON_RELEASE
+                        | EventMask::POINTER_MOTION
+                        | EventMask::KEY_PRESS,
+                ),
+        )?;
+        conn.map_window(window)?;
+        self.levels.push(Level {
+            window,
+            node,
+            x,
+            y,
+            geometry,
+            highlighted: None,
+        });
+        Ok(())
+    }
+
+    /// Close the menus from level `l` inwards
+    fn close_from(&mut self, conn: &RustConnection, l: usize) {
+        while self.levels.len() > l {
+            if let Some(level) = self.levels.pop() {
+                let _ = conn.destroy_window(level.window);
+            }
+        }
+    }
+}
+
+/// Draw all rows of the menu window
+fn draw(
+    conn: &RustConnection,
+    painter: &Painter,
+    menu: &MenuNode,
+    level: &Level,
+) -> Result<(), ConnectionError> {
+    let node = match menu.find(level.node) {
+        Some(node) => node,
+        None => return Ok(()),
+    };
+    let palette = &painter.palette;
+    let width = level.geometry.width;
+    let rows = node.children.iter().zip(&level.geometry.rows);
+    for (i, (child, row)) in rows.enumerate() {
+        let highlighted = level.highlighted == Some(i);
+        let background = if highlighted {
+            palette.highlight
+        } else {
+            palette.background
+        };
+        let rectangle = Rectangle {
+            x: 0,
+            y: row.y,
+            width,
+            height: row.height,
+        };
+        painter.fill(conn, level.window, background, rectangle)?;
+
+        let middle = row.y + row.height as i16 / 2;
+        if child.kind == MenuNodeKind::Separator {
+            let line = Rectangle {
+                x: 4,
+                y: middle,
+                width: width.saturating_sub(8),
+                height: 1,
+            };
+            painter.fill(conn, level.window, palette.border, line)?;
+            continue;
+        }
+
+        let color = if child.disabled {
+            palette.disabled
+        } else if highlighted {
+            palette.highlight_text
+        } else {
+            palette.text
+        };
+        let y = row.y + ITEM_PADDING as i16;
+        painter.text(
+            conn,
+            level.window,
+            (color, background),
+            level.geometry.label_x as i16,
+            y,
+            child.label.text(),
+        )?;
+        if let Some(icon) = &child.icon {
+            let size = MENU_ICON_SIZE as u16;
+            let position = (CHECK_WIDTH as i16, middle - size as i16 / 2);
+            painter.image(conn, level.window, background, position, size, icon)?;
+        }
+        if let Some(i) = child.label.mnemonic_index() {
+            let text = child.label.text();
+            let end = text[i..].chars().next().map_or(i, |c| i + c.len_utf8());
+            let underline = Rectangle {
+                x: (level.geometry.label_x + painter.text_width(conn, &text[..i])) as i16,
+                y: y + painter.ascent + 1,
+                width: painter.text_width(conn, &text[i..end]),
+                height: 1,
+            };
+            painter.fill(conn, level.window, color, underline)?;
+        }
+        if let Some(accelerator) = child.label.accelerator() {
+            let x = width.saturating_sub(ARROW_WIDTH + painter.text_width(conn, accelerator));
+            let colors = (color, background);
+            painter.text(conn, level.window, colors, x as i16, y, accelerator)?;
+        }
+        match child.kind {
+            MenuNodeKind::Checkable(true) => {
+                let check = [
+                    Point { x: 6, y: middle },
+                    Point {
+                        x: 9,
+                        y: middle + 3,
+                    },
+                    Point {
+                        x: 15,
+                        y: middle - 4,
+                    },
+                ];
+                painter.lines(conn, level.window, color, &check)?;
+            }
+            MenuNodeKind::Radio(true) => {
+                let dot = [
+                    Point {
+                        x: 11,
+                        y: middle - 4,
+                    },
+                    Point { x: 15, y: middle },
+                    Point {
+                        x: 11,
+                        y: middle + 4,
+                    },
+                    Point { x: 7, y: middle },
+                ];
+                painter.polygon(conn, level.window, color, &dot)?;
+            }
+            MenuNodeKind::Submenu => {
+                let x = width as i16 - 14;
+                let arrow = [
+                    Point { x, y: middle - 4 },
+                    Point {
+                        x: x + 4,
+                        y: middle,
+                    },
+                    Point { x, y: middle + 4 },
+                ];
+                painter.polygon(conn, level.window, color, &arrow)?;
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MenuLabel;
+
+    fn menu() -> MenuNode {
+        let node = |id, kind, label: &str, children| MenuNode {
+            id,
+            kind,
+            label: label.into(),
+            disabled: id == 3,
+            icon: None,
+            children,
+        };
+        MenuNode::root(vec![
+            node(1, MenuNodeKind::Standard, "Item", vec![]),
+            node(2, MenuNodeKind::Separator, "", vec![]),
+            node(3, MenuNodeKind::Standard, "Disabled", vec![]),
+            node(
+                4,
+                MenuNodeKind::Submenu,
+                "A much longer label than the others",
+                vec![node(5, MenuNodeKind::Checkable(true), "Check", vec![])],
+            ),
+        ])
+    }
+
+    #[test]
+    fn test_measure() {
+        // Six pixels per character, like the "fixed" font
+        let geometry = MenuGeometry::measure(&menu(), 13, |t| t.chars().count() as u16 * 6);
+        let item = 13 + ITEM_PADDING * 2;
+        assert_eq!(
+            geometry.rows,
+            vec![
+                MenuRow { y: 2, height: item },
+                MenuRow {
+                    y: 2 + item as i16,
+                    height: SEPARATOR_HEIGHT
+                },
+                MenuRow {
+                    y: 2 + (item + SEPARATOR_HEIGHT) as i16,
+                    height: item
+                },
+                MenuRow {
+                    y: 2 + (item * 2 + SEPARATOR_HEIGHT) as i16,
+                    height: item
+                },
+            ]
+        );
+        assert_eq!(geometry.height, item * 3 + SEPARATOR_HEIGHT + 4);
+        assert_eq!(geometry.width, CHECK_WIDTH + 35 * 6 + ARROW_WIDTH);
+        assert_eq!(geometry.label_x, CHECK_WIDTH);
+
+        assert_eq!(geometry.row_at(0), None);
+        assert_eq!(geometry.row_at(2), Some(0));
+        assert_eq!(geometry.row_at(2 + item as i16), Some(1));
+        assert_eq!(geometry.row_at(geometry.height as i16 - 1), None);
+
+        // Short labels get the minimum width
+        let mut sub = menu().find(4).cloned().unwrap();
+        assert_eq!(MenuGeometry::measure(&sub, 13, |_| 6).width, MIN_WIDTH);
+
+        // Icons get a column, and rows fit them
+        sub.children[0].icon = Some(IcoImage {
+            width: 1,
+            height: 1,
+            rgba: vec![0, 255, 0, 255],
+        });
+        let geometry = MenuGeometry::measure(&sub, 13, |_| 6);
+        assert_eq!(geometry.label_x, CHECK_WIDTH + ICON_WIDTH);
+        assert_eq!(geometry.rows[0].height, 16 + ITEM_PADDING * 2);
+    }
+
+    #[test]
+    fn test_mnemonic_row() {
+        let mut root = menu();
+        root.children[0].label = MenuLabel::new("Item").with_mnemonic('t');
+        root.children[2].label = MenuLabel::new("Disabled").with_mnemonic('d');
+        root.children[3].label =
+            MenuLabel::new("Ä much longer label than the others").with_mnemonic('ä');
+        assert_eq!(mnemonic_row(&root, 't' as Keysym), Some(0));
+        assert_eq!(mnemonic_row(&root, 'T' as Keysym), Some(0));
+        assert_eq!(mnemonic_row(&root, 'Ä' as Keysym), Some(3));
+
+        // Disabled items and other keys don't match
+        assert_eq!(mnemonic_row(&root, 'd' as Keysym), None);
+        assert_eq!(mnemonic_row(&root, XK_DOWN), None);
+
+        // Accelerator makes room for itself
+        root.children[0].label = MenuLabel::new("Item").with_accelerator("Ctrl+Q");
+        let geometry = MenuGeometry::measure(&root, 13, |t| t.chars().count() as u16 * 6);
+        assert_eq!(geometry.width, CHECK_WIDTH + 35 * 6 + ARROW_WIDTH);
+        root.children[0].label = MenuLabel::new(&"Item".repeat(10)).with_accelerator("Ctrl+Q");
+        let geometry = MenuGeometry::measure(&root, 13, |t| t.chars().count() as u16 * 6);
+        assert_eq!(
+            geometry.width,
+            CHECK_WIDTH + 40 * 6 + ACCELERATOR_GAP + 6 * 6 + ARROW_WIDTH
+        );
+    }
+
+    #[test]
+    fn test_next_selectable() {
+        let root = menu();
+        assert_eq!(next_selectable(&root, None, true), Some(0));
+        assert_eq!(next_selectable(&root, None, false), Some(3));
+
+        // Separator and disabled item are skipped, and it wraps around
+        assert_eq!(next_selectable(&root, Some(0), true), Some(3));
+        assert_eq!(next_selectable(&root, Some(3), true), Some(0));
+        assert_eq!(next_selectable(&root, Some(0), false), Some(3));
+        assert_eq!(next_selectable(&MenuNode::root(vec![]), None, true), None);
+    }
+
+    #[test]
+    fn test_place() {
+        let screen = (800, 600);
+        assert_eq!(place_menu((10, 10), (100, 50), screen), (10, 10));
+
+        // Bottom right corner opens up and left
+        assert_eq!(place_menu((790, 590), (100, 50), screen), (700, 540));
+
+        // Submenu goes left of the parent when there is no room on the right
+        assert_eq!(
+            place_submenu((100, 100, 120), 20, (100, 50), screen),
+            (220, 120)
+        );
+        assert_eq!(
+            place_submenu((650, 100, 120), 20, (100, 50), screen),
+            (550, 120)
+        );
+        assert_eq!(
+            place_submenu((100, 580, 120), 10, (100, 50), screen),
+            (220, 550)
+        );
+    }
+
+    #[test]
+    fn test_char2b_and_pixel() {
+        let bytes = char2b("Aé€😀")
+            .iter()
+            .map(|c| (c.byte1, c.byte2))
+            .collect::<Vec<_>>();
+        assert_eq!(bytes, vec![(0, b'A'), (0, 0xe9), (0x20, 0xac), (0, b'?')]);
+        assert_eq!(char2b(&"x".repeat(300)).len(), 255);
+
+        let rgb565 = Visualtype {
+            visual_id: 1,
+            class: VisualClass::TRUE_COLOR,
+            bits_per_rgb_value: 6,
+            colormap_entries: 64,
+            red_mask: 0xf800,
+            green_mask: 0x07e0,
+            blue_mask: 0x001f,
+        };
+        assert_eq!(pixel(Some(&rgb565), 0xff0000), 0xf800);
+        assert_eq!(pixel(Some(&rgb565), 0xffffff), 0xffff);
+        assert_eq!(pixel(None, 0xf0f0f0), 0xffffff);
+    }
+}
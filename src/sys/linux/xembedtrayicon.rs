@@ -0,0 +1,1122 @@
+use super::sniicon::{SniIcon, SniPixmap};
+use super::xembedmenu::{MenuAction, Painter, PopupMenu};
+use super::MenuSys;
+use crate::trayevent::EventDispatcher;
+use crate::{
+    Capabilities, DeliveryStats, Error, EventInfo, Icon, MenuBuilder, Modifiers, MouseButton,
+    ScrollOrientation, TrayEvent, TrayIconBase,
+};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex, MutexGuard},
+    thread::JoinHandle,
+};
+use x11rb::{
+    connection::Connection,
+    errors::ReplyOrIdError,
+    protocol::{xproto::*, Event},
+    rust_connection::RustConnection,
+    wrapper::ConnectionExt as _,
+    COPY_FROM_PARENT, CURRENT_TIME, NONE,
+};
+
+/// Size of the window until the tray manager sets it
+const ICON_SIZE: u16 = 24;
+
+/// Two presses within this many milliseconds are a double click
+const DOUBLE_CLICK_TIME: u32 = 400;
+
+const TOOLTIP_PADDING: u16 = 3;
+
+/// Opcode of `_NET_SYSTEM_TRAY_OPCODE` message
+const SYSTEM_TRAY_REQUEST_DOCK: u32 = 0;
+
+/// `_XEMBED_INFO` flag asking the embedder to map the window
+const XEMBED_MAPPED: u32 = 1;
+
+#[derive(Debug, Clone, Copy)]
+struct Atoms {
+    selection: Atom,
+    opcode: Atom,
+    visual: Atom,
+    xembed_info: Atom,
+    manager: Atom,
+    quit: Atom,
+}
+
+impl Atoms {
+    fn new(conn: &RustConnection, screen_num: usize) -> Result<Atoms, ReplyOrIdError> {
+        let atom = |name: &str| -> Result<Atom, ReplyOrIdError> {
+            Ok(conn.intern_atom(false, name.as_bytes())?.reply()?.atom)
+        };
+        Ok(Atoms {
+            selection: atom(&format!("_NET_SYSTEM_TRAY_S{}", screen_num))?,
+            opcode: atom("_NET_SYSTEM_TRAY_OPCODE")?,
+            visual: atom("_NET_SYSTEM_TRAY_VISUAL")?,
+            xembed_info: atom("_XEMBED_INFO")?,
+            manager: atom("MANAGER")?,
+            quit: atom("_TRAYICON_RS_QUIT")?,
+        })
+    }
+}
+
+/// Things the event thread needs, that never change
+#[derive(Debug)]
+struct Context {
+    window: Window,
+    screen: Screen,
+    atoms: Atoms,
+
+    /// Window has 32 bit ARGB visual of the tray manager, otherwise the icon
+    /// is blended on the background of the tray
+    argb: bool,
+    image_order: ImageOrder,
+    gc: Gcontext,
+    painter: Painter,
+    keysyms: HashMap<Keycode, Keysym>,
+}
+
+/// State shared by the tray icon and its event thread
+#[derive(Debug)]
+struct Shared<T>
+where
+    T: PartialEq + Clone + Send + 'static,
+{
+    /// Events to send once the lock is released, the receiver may call the
+    /// tray icon
+    pending: Vec<TrayEvent<T>>,
+    icon: SniIcon,
+    tooltip: String,
+    menu: Option<MenuSys<T>>,
+    popup: Option<PopupMenu>,
+    tooltip_window: Option<Window>,
+    size: (u16, u16),
+    last_press: Option<Timestamp>,
+}
+
+impl<T> Shared<T>
+where
+    T: PartialEq + Clone + Send + 'static,
+{
+    /// Close the menu if it's open, also when it's replaced or dismissed
+    fn close_popup(&mut self, conn: &RustConnection) {
+        if let Some(mut popup) = self.popup.take() {
+            popup.close(conn);
+            self.pending
+                .push(TrayEvent::MenuClose(EventInfo::now(None)));
+        }
+    }
+
+    fn hide_tooltip(&mut self, conn: &RustConnection) {
+        if let Some(window) = self.tooltip_window.take() {
+            let _ = conn.destroy_window(window);
+        }
+    }
+}
+
+/// Tray Icon as an XEmbed system tray window
+///
+/// Each tray icon has its own X connection and event thread. The window is
+/// docked to the tray manager owning `_NET_SYSTEM_TRAY_S<n>`, and docked
+/// again whenever a new tray manager announces itself.
+#[derive(Debug)]
+pub struct XEmbedTrayIcon<T>
+where
+    T: PartialEq + Clone + Send + 'static,
+{
+    conn: Arc<RustConnection>,
+    window: Window,
+    quit: Atom,
+    events: EventDispatcher<T>,
+    shared: Arc<Mutex<Shared<T>>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl<T> XEmbedTrayIcon<T>
+where
+    T: PartialEq + Clone + Send + 'static,
+{
+    /// Dock a tray icon, `display` of `None` means `$DISPLAY`
+    pub(crate) fn new(
+        display: Option<&str>,
+        events: EventDispatcher<T>,
+        menu: Option<MenuSys<T>>,
+        icon: &SniIcon,
+        tooltip: &Option<String>,
+    ) -> Result<XEmbedTrayIcon<T>, Error> {
+        let (conn, screen_num) = x11rb::connect(display).map_err(|e| Error::Os {
+            call: "XOpenDisplay",
+            code: None,
+            message: e.to_string(),
+        })?;
+        let conn = Arc::new(conn);
+        let context = create_window(&conn, screen_num).map_err(x11_error("CreateWindow"))?;
+        if !dock(&conn, &context).map_err(x11_error("SendEvent"))? {
+            let _ = conn.destroy_window(context.window);
+            return Err(Error::Os {
+                call: "GetSelectionOwner",
+                code: None,
+                message: "no system tray manager on the display".into(),
+            });
+        }
+        conn.flush().map_err(x11_error("flush"))?;
+
+        let shared = Arc::new(Mutex::new(Shared {
+            pending: vec![],
+            icon: icon.clone(),
+            tooltip: tooltip.clone().unwrap_or_default(),
+            menu,
+            popup: None,
+            tooltip_window: None,
+            size: (ICON_SIZE, ICON_SIZE),
+            last_press: None,
+        }));
+        let window = context.window;
+        let quit = context.atoms.quit;
+        let thread = {
+            let conn = conn.clone();
+            let events = events.clone();
+            let shared = shared.clone();
+            std::thread::spawn(move || run(&conn, &context, &events, &shared))
+        };
+        Ok(XEmbedTrayIcon {
+            conn,
+            window,
+            quit,
+            events,
+            shared,
+            thread: Some(thread),
+        })
+    }
+
+    fn shared(&self) -> MutexGuard<'_, Shared<T>> {
+        self.shared.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Change the shared state, and send its events after unlocking
+    fn update<F, R>(&self, f: F) -> R
+    where
+        F: FnOnce(&mut Shared<T>) -> R,
+    {
+        let (result, pending) = {
+            let mut shared = self.shared();
+            let result = f(&mut shared);
+            (result, std::mem::take(&mut shared.pending))
+        };
+        for event in pending {
+            self.events.send(event);
+        }
+        result
+    }
+}
+
+impl<T> TrayIconBase<T> for XEmbedTrayIcon<T>
+where
+    T: PartialEq + Clone + Send + 'static,
+{
+    /// Window gets every button, the wheel included
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            middle_click: true,
+            scroll: true,
+        }
+    }
+
+    fn delivery_stats(&self) -> DeliveryStats {
+        self.events.stats()
+    }
+
+    /// Set the tooltip
+    ///
+    /// Tooltip that is showing is hidden, the new one shows on next hover.
+    fn set_tooltip(&mut self, tooltip: &str) -> Result<(), Error> {
+        let mut shared = self.shared();
+        shared.tooltip = tooltip.to_string();
+        shared.hide_tooltip(&self.conn);
+        self.conn.flush().map_err(x11_error("flush"))
+    }
+
+    /// Set icon
+    fn set_icon(&mut self, icon: &Icon) -> Result<(), Error> {
+        self.shared().icon = icon.sys.clone();
+
+        // Clearing generates an expose event, the event thread repaints
+        self.conn
+            .clear_area(true, self.window, 0, 0, 0, 0)
+            .and_then(|_| self.conn.flush())
+            .map_err(x11_error("ClearArea"))
+    }
+
+    /// Set menu
+    fn set_menu(&mut self, menu: &MenuBuilder<T>) -> Result<(), Error> {
+        let menu = menu.build()?;
+        self.update(|shared| {
+            shared.close_popup(&self.conn);
+            shared.menu = Some(menu);
+        });
+        self.conn.flush().map_err(x11_error("flush"))
+    }
+}
+
+impl<T> Drop for XEmbedTrayIcon<T>
+where
+    T: PartialEq + Clone + Send + 'static,
+{
+    fn drop(&mut self) {
+        self.update(|shared| {
+            shared.close_popup(&self.conn);
+            shared.hide_tooltip(&self.conn);
+        });
+
+        // Event with no mask goes to the client that created the window,
+        // which is the event thread's own connection
+        let quit = ClientMessageEvent::new(32, self.window, self.quit, [0u32; 5]);
+        let _ = self
+            .conn
+            .send_event(false, self.window, EventMask::NO_EVENT, quit);
+        let _ = self.conn.flush();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+
+        // Destroying the window removes it from the tray
+        let _ = self.conn.destroy_window(self.window);
+        let _ = self.conn.flush();
+    }
+}
+
+/// Error of failed X11 request, X11 error code is kept if the server sent one
+fn x11_error<E>(call: &'static str) -> impl Fn(E) -> Error
+where
+    E: Into<ReplyOrIdError>,
+{
+    move |e| {
+        let e = e.into();
+        let code = match &e {
+            ReplyOrIdError::X11Error(x) => Some(x.error_code as i32),
+            _ => None,
+        };
+        Error::Os {
+            call,
+            code,
+            message: e.to_string(),
+        }
+    }
+}
+
+/// Create the icon window, with the visual the tray manager asks for
+fn create_window(conn: &RustConnection, screen_num: usize) -> Result<Context, ReplyOrIdError> {
+    let screen = conn.setup().roots[screen_num].clone();
+    let atoms = Atoms::new(conn, screen_num)?;
+    let manager = conn.get_selection_owner(atoms.selection)?.reply()?.owner;
+
+    // Tray managers that composite the icons tell the visual they want
+    let visual = if manager == NONE {
+        None
+    } else {
+        conn.get_property(false, manager, atoms.visual, AtomEnum::VISUALID, 0, 1)?
+            .reply()?
+            .value32()
+            .and_then(|mut v| v.next())
+    };
+    let argb = visual.filter(|&visual| {
+        screen
+            .allowed_depths
+            .iter()
+            .any(|d| d.depth == 32 && d.visuals.iter().any(|v| v.visual_id == visual))
+    });
+
+    let window = conn.generate_id()?;
+    let events = EventMask::EXPOSURE
+        | EventMask::STRUCTURE_NOTIFY
+        | EventMask::BUTTON_PRESS
+        | EventMask::BUTTON_RELEASE
+        | EventMask::ENTER_WINDOW
+        | EventMask::LEAVE_WINDOW;
+    match argb {
+        Some(visual) => {
+            let colormap = conn.generate_id()?;
+            conn.create_colormap(ColormapAlloc::NONE, colormap, screen.root, visual)?;
+            conn.create_window(
+                32,
+                window,
+                screen.root,
+                0,
+                0,
+                ICON_SIZE,
+                ICON_SIZE,
+                0,
+                WindowClass::INPUT_OUTPUT,
+                visual,
+                &CreateWindowAux::new()
+                    .background_pixel(0)
+                    .border_pixel(0)
+                    .colormap(colormap)
+                    .event_mask(events),
+            )?;
+        }
+        None => {
+            conn.create_window(
+                screen.root_depth,
+                window,
+                screen.root,
+                0,
+                0,
+                ICON_SIZE,
+                ICON_SIZE,
+                0,
+                WindowClass::INPUT_OUTPUT,
+                COPY_FROM_PARENT,
+                &CreateWindowAux::new()
+                    .background_pixmap(BackPixmap::PARENT_RELATIVE)
+                    .event_mask(events),
+            )?;
+        }
+    }
+    conn.change_property32(
+        PropMode::REPLACE,
+        window,
+        atoms.xembed_info,
+        atoms.xembed_info,
+        &[0, XEMBED_MAPPED],
+    )?;
+
+    // New tray managers announce themselves on the root window
+    conn.change_window_attributes(
+        screen.root,
+        &ChangeWindowAttributesAux::new().event_mask(EventMask::STRUCTURE_NOTIFY),
+    )?;
+
+    let gc = conn.generate_id()?;
+    conn.create_gc(gc, window, &CreateGCAux::new().graphics_exposures(0))?;
+    let painter = Painter::new(conn, &screen)?;
+    Ok(Context {
+        window,
+        atoms,
+        argb: argb.is_some(),
+        image_order: conn.setup().image_byte_order,
+        gc,
+        painter,
+        keysyms: keysyms(conn)?,
+        screen,
+    })
+}
+
+/// First keysym of each keycode, enough for the menu navigation keys
+fn keysyms(conn: &RustConnection) -> Result<HashMap<Keycode, Keysym>, ReplyOrIdError> {
+    let setup = conn.setup();
+    let (min, max) = (setup.min_keycode, setup.max_keycode);
+    let mapping = conn.get_keyboard_mapping(min, max - min + 1)?.reply()?;
+    let per_keycode = mapping.keysyms_per_keycode.max(1) as usize;
+    Ok(mapping
+        .keysyms
+        .chunks(per_keycode)
+        .zip(min..=max)
+        .map(|(syms, keycode)| (keycode, syms[0]))
+        .collect())
+}
+
+/// Ask the tray manager to embed the window, returns false if there is no
+/// tray manager
+fn dock(conn: &RustConnection, context: &Context) -> Result<bool, ReplyOrIdError> {
+    let manager = conn
+        .get_selection_owner(context.atoms.selection)?
+        .reply()?
+        .owner;
+    if manager == NONE {
+        return Ok(false);
+    }
+    let data = [CURRENT_TIME, SYSTEM_TRAY_REQUEST_DOCK, context.window, 0, 0];
+    let message = ClientMessageEvent::new(32, manager, context.atoms.opcode, data);
+    conn.send_event(false, manager, EventMask::NO_EVENT, message)?;
+    Ok(true)
+}
+
+/// Event thread, runs until the quit message or until the connection breaks
+fn run<T>(
+    conn: &RustConnection,
+    context: &Context,
+    events: &EventDispatcher<T>,
+    shared: &Mutex<Shared<T>>,
+) where
+    T: PartialEq + Clone + Send + 'static,
+{
+    let window = context.window;
+    while let Ok(event) = conn.wait_for_event() {
+        let mut guard = shared.lock().unwrap_or_else(|e| e.into_inner());
+        let shared = &mut *guard;
+        match event {
+            Event::ClientMessage(e) if e.type_ == context.atoms.quit => break,
+
+            // Tray manager restarted
+            Event::ClientMessage(e)
+                if e.type_ == context.atoms.manager
+                    && e.data.as_data32()[1] == context.atoms.selection =>
+            {
+                let _ = dock(conn, context);
+            }
+
+            // Tray manager went away, and left the window on the root
+            Event::ReparentNotify(e) if e.window == window && e.parent == context.screen.root => {
+                let _ = conn.unmap_window(window);
+            }
+
+            Event::ConfigureNotify(e)
+                if e.window == window && shared.size != (e.width, e.height) =>
+            {
+                shared.size = (e.width, e.height);
+                let _ = conn.clear_area(true, window, 0, 0, 0, 0);
+            }
+
+            Event::Expose(e) if e.count == 0 => {
+                if e.window == window {
+                    paint(conn, context, shared);
+                } else if Some(e.window) == shared.tooltip_window {
+                    let colors = (context.screen.black_pixel, context.painter.palette.tooltip);
+                    let padding = TOOLTIP_PADDING as i16;
+                    let _ = context.painter.text(
+                        conn,
+                        e.window,
+                        colors,
+                        padding,
+                        padding,
+                        &shared.tooltip,
+                    );
+                } else if let (Some(popup), Some(menu)) = (&shared.popup, &shared.menu) {
+                    popup.expose(conn, &context.painter, &menu.root, e.window);
+                }
+            }
+
+            // Open menu gets all pointer and keyboard events
+            Event::ButtonPress(e) if shared.popup.is_some() => {
+                let action = shared
+                    .popup
+                    .as_ref()
+                    .map(|p| p.button_press((e.root_x, e.root_y)));
+                menu_action(conn, shared, action, event_info(&e));
+            }
+            Event::ButtonRelease(e) if shared.popup.is_some() => {
+                let action = match (&mut shared.popup, &mut shared.menu) {
+                    (Some(popup), Some(MenuSys { ids, root })) => Some(popup.button_release(
+                        conn,
+                        &context.painter,
+                        root,
+                        &mut |root, id| ids.populate(root, id, &|e| events.report(e)),
+                        (e.root_x, e.root_y),
+                    )),
+                    _ => Some(MenuAction::Close),
+                };
+                menu_action(conn, shared, action, event_info(&e));
+            }
+            Event::MotionNotify(e) => {
+                if let (Some(popup), Some(MenuSys { ids, root })) =
+                    (&mut shared.popup, &mut shared.menu)
+                {
+                    popup.motion(
+                        conn,
+                        &context.painter,
+                        root,
+                        &mut |root, id| ids.populate(root, id, &|e| events.report(e)),
+                        (e.root_x, e.root_y),
+                    );
+                }
+            }
+            Event::KeyPress(e) => {
+                let keysym = context.keysyms.get(&e.detail).copied().unwrap_or(0);
+                let action = match (&mut shared.popup, &mut shared.menu) {
+                    (Some(popup), Some(MenuSys { ids, root })) => Some(popup.key(
+                        conn,
+                        &context.painter,
+                        root,
+                        &mut |root, id| ids.populate(root, id, &|e| events.report(e)),
+                        keysym,
+                    )),
+                    _ => None,
+                };
+                let info = EventInfo::now(None)
+                    .at(e.root_x.into(), e.root_y.into())
+                    .with_modifiers(modifiers(e.state));
+                menu_action(conn, shared, action, info);
+            }
+
+            Event::ButtonPress(e) if e.event == window => {
+                shared.hide_tooltip(conn);
+                if let Some((delta, orientation)) = wheel(e.detail) {
+                    shared.pending.push(TrayEvent::Scroll {
+                        delta,
+                        orientation,
+                        info: event_info(&e),
+                    });
+                } else if e.detail == 1 {
+                    match shared.last_press {
+                        Some(last) if e.time.wrapping_sub(last) < DOUBLE_CLICK_TIME => {
+                            shared.pending.push(TrayEvent::DoubleClick(event_info(&e)));
+                            shared.last_press = None;
+                        }
+                        _ => shared.last_press = Some(e.time),
+                    }
+                }
+            }
+            Event::ButtonRelease(e) if e.event == window => match e.detail {
+                1 => shared.pending.push(TrayEvent::Click(event_info(&e))),
+                2 => shared.pending.push(TrayEvent::MiddleClick(event_info(&e))),
+                3 => {
+                    shared.pending.push(TrayEvent::RightClick(event_info(&e)));
+                    if let Some(menu) = shared.menu.as_ref().filter(|m| !m.root.children.is_empty())
+                    {
+                        shared.popup = PopupMenu::open(
+                            conn,
+                            &context.painter,
+                            &context.screen,
+                            &menu.root,
+                            (e.root_x, e.root_y),
+                        )
+                        .ok();
+                        if shared.popup.is_some() {
+                            let info = event_info(&e);
+                            shared.pending.push(TrayEvent::MenuOpen(info));
+                        }
+                    }
+                }
+                _ => {}
+            },
+
+            Event::EnterNotify(e) if e.event == window => {
+                let _ = show_tooltip(conn, context, shared);
+            }
+            Event::LeaveNotify(e) if e.event == window => shared.hide_tooltip(conn),
+            _ => {}
+        }
+        let _ = conn.flush();
+
+        // Callback may call the tray icon, which takes the lock
+        let pending = std::mem::take(&mut shared.pending);
+        drop(guard);
+        for event in pending {
+            events.send(event);
+        }
+    }
+}
+
+/// Do what the menu asks for, `info` is of the input that chose it
+fn menu_action<T>(
+    conn: &RustConnection,
+    shared: &mut Shared<T>,
+    action: Option<MenuAction>,
+    info: EventInfo,
+) where
+    T: PartialEq + Clone + Send + 'static,
+{
+    match action {
+        Some(MenuAction::Activate(id)) => {
+            shared.close_popup(conn);
+            let id = shared.menu.as_ref().and_then(|m| m.ids.get(id)).cloned();
+            if let Some(id) = id {
+                shared.pending.push(TrayEvent::MenuItem { id, info });
+            }
+        }
+        Some(MenuAction::Close) => shared.close_popup(conn),
+        _ => {}
+    }
+}
+
+/// Details of a button event
+fn event_info(e: &ButtonPressEvent) -> EventInfo {
+    let button = match e.detail {
+        1 => Some(MouseButton::Left),
+        2 => Some(MouseButton::Middle),
+        3 => Some(MouseButton::Right),
+        _ => None,
+    };
+    EventInfo::now(button)
+        .at(e.root_x.into(), e.root_y.into())
+        .with_modifiers(modifiers(e.state))
+}
+
+/// Wheel step of the button, buttons 4 to 7 are up, down, left and right
+fn wheel(detail: u8) -> Option<(i32, ScrollOrientation)> {
+    match detail {
+        4 => Some((1, ScrollOrientation::Vertical)),
+        5 => Some((-1, ScrollOrientation::Vertical)),
+        6 => Some((-1, ScrollOrientation::Horizontal)),
+        7 => Some((1, ScrollOrientation::Horizontal)),
+        _ => None,
+    }
+}
+
+/// Modifier keys of the event state, Mod1 is Alt and Mod4 is Super on
+/// practically every keyboard mapping
+fn modifiers(state: KeyButMask) -> Modifiers {
+    Modifiers {
+        shift: state.contains(KeyButMask::SHIFT),
+        ctrl: state.contains(KeyButMask::CONTROL),
+        alt: state.contains(KeyButMask::MOD1),
+        logo: state.contains(KeyButMask::MOD4),
+    }
+}
+
+/// Show the tooltip below the icon, or above if the icon is at the bottom
+fn show_tooltip<T>(
+    conn: &RustConnection,
+    context: &Context,
+    shared: &mut Shared<T>,
+) -> Result<(), ReplyOrIdError>
+where
+    T: PartialEq + Clone + Send + 'static,
+{
+    if shared.tooltip.is_empty() || shared.tooltip_window.is_some() || shared.popup.is_some() {
+        return Ok(());
+    }
+    let painter = &context.painter;
+    let width = painter.text_width(conn, &shared.tooltip) + TOOLTIP_PADDING * 2;
+    let height = painter.line_height + TOOLTIP_PADDING * 2;
+    let screen = &context.screen;
+    let icon = conn
+        .translate_coordinates(context.window, screen.root, 0, 0)?
+        .reply()?;
+    let (sw, sh) = (
+        screen.width_in_pixels as i32,
+        screen.height_in_pixels as i32,
+    );
+    let x = (icon.dst_x as i32).min(sw - width as i32).max(0);
+    let below = icon.dst_y as i32 + shared.size.1 as i32 + 4;
+    let y = if below + height as i32 > sh {
+        icon.dst_y as i32 - height as i32 - 4
+    } else {
+        below
+    };
+
+    let tooltip = conn.generate_id()?;
+    conn.create_window(
+        screen.root_depth,
+        tooltip,
+        screen.root,
+        x as i16,
+        y.max(0) as i16,
+        width,
+        height,
+        1,
+        WindowClass::INPUT_OUTPUT,
+        COPY_FROM_PARENT,
+        &CreateWindowAux::new()
+            .override_redirect(1)
+            .background_pixel(painter.palette.tooltip)
+            .border_pixel(painter.palette.border)
+            .event_mask(EventMask::EXPOSURE),
+    )?;
+    conn.map_window(tooltip)?;
+    shared.tooltip_window = Some(tooltip);
+    Ok(())
+}
+
+/// Paint the icon in the current size of the window
+fn paint<T>(conn: &RustConnection, context: &Context, shared: &Shared<T>)
+where
+    T: PartialEq + Clone + Send + 'static,
+{
+    let (width, height) = shared.size;
+    let pixmap = match best_pixmap(&shared.icon, width.min(height)) {
+        Some(pixmap) => pixmap,
+        None => return,
+    };
+    let count = width as usize * height as usize;
+    let background = if context.argb {
+        vec![0; count]
+    } else {
+        // Window shows the background of the tray after clearing, so the
+        // icon is blended on that. Opaque black if it can't be read back.
+        let _ = conn.clear_area(false, context.window, 0, 0, 0, 0);
+        conn.get_image(
+            ImageFormat::Z_PIXMAP,
+            context.window,
+            0,
+            0,
+            width,
+            height,
+            !0,
+        )
+        .ok()
+        .and_then(|c| c.reply().ok())
+        .map(|r| decode_pixels(&r.data, context.image_order))
+        .filter(|pixels| pixels.len() == count)
+        .map(|pixels| pixels.into_iter().map(|p| p | 0xff00_0000).collect())
+        .unwrap_or_else(|| vec![0xff00_0000; count])
+    };
+    let pixels = compose(pixmap, width, height, background);
+    let _ = conn.put_image(
+        ImageFormat::Z_PIXMAP,
+        context.window,
+        context.gc,
+        width,
+        height,
+        0,
+        0,
+        0,
+        if context.argb {
+            32
+        } else {
+            context.screen.root_depth
+        },
+        &encode_pixels(&pixels, context.image_order),
+    );
+}
+
+/// Smallest image at least `size` pixels wide, or the largest one
+fn best_pixmap(icon: &SniIcon, size: u16) -> Option<&SniPixmap> {
+    let size = size as i32;
+    icon.pixmaps
+        .iter()
+        .filter(|p| p.width >= size)
+        .min_by_key(|p| p.width)
+        .or_else(|| icon.pixmaps.iter().max_by_key(|p| p.width))
+}
+
+/// Scale the image to fit in the middle of width x height, and draw it over
+/// the background
+///
+/// Pixels are 0xAARRGGBB with premultiplied alpha, which is what the 32 bit
+/// visual of a compositing tray expects. Scaling is nearest neighbour.
+pub(super) fn compose(
+    pixmap: &SniPixmap,
+    width: u16,
+    height: u16,
+    mut background: Vec<u32>,
+) -> Vec<u32> {
+    let size = width.min(height) as usize;
+    let (left, top) = ((width as usize - size) / 2, (height as usize - size) / 2);
+    let (pw, ph) = (pixmap.width as usize, pixmap.height as usize);
+    for y in 0..size {
+        for x in 0..size {
+            let source = ((y * ph / size) * pw + x * pw / size) * 4;
+            let argb = match pixmap.argb.get(source..source + 4) {
+                Some(argb) => argb,
+                None => continue,
+            };
+            let alpha = argb[0] as u32;
+            let target = &mut background[(top + y) * width as usize + left + x];
+            let over = |shift: u32, value: u8| {
+                let dst = (*target >> shift) & 0xff;
+                let src = value as u32 * alpha / 255;
+                (src + dst * (255 - alpha) / 255) << shift
+            };
+            *target = over(24, 255) | over(16, argb[1]) | over(8, argb[2]) | over(0, argb[3]);
+        }
+    }
+    background
+}
+
+fn decode_pixels(data: &[u8], order: ImageOrder) -> Vec<u32> {
+    data.chunks_exact(4)
+        .map(|b| {
+            let b = [b[0], b[1], b[2], b[3]];
+            if order == ImageOrder::LSB_FIRST {
+                u32::from_le_bytes(b)
+            } else {
+                u32::from_be_bytes(b)
+            }
+        })
+        .collect()
+}
+
+pub(super) fn encode_pixels(pixels: &[u32], order: ImageOrder) -> Vec<u8> {
+    pixels
+        .iter()
+        .flat_map(|p| {
+            if order == ImageOrder::LSB_FIRST {
+                p.to_le_bytes()
+            } else {
+                p.to_be_bytes()
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{IconBase, MenuBuilder, TrayIconBuilder};
+    use std::{
+        io::{BufRead, BufReader},
+        process::{Child, Command, Stdio},
+        sync::mpsc::Receiver,
+        time::{Duration, Instant},
+    };
+
+    #[derive(Copy, Clone, Eq, PartialEq, Debug)]
+    enum Events {
+        Click,
+        DoubleClick,
+        RightClick,
+        MenuOpen,
+        MenuClose,
+        MiddleClick,
+        Scroll(i32),
+        Item1,
+        Item2,
+    }
+
+    /// Private `Xvfb` server, killed on drop
+    struct TestDisplay {
+        xvfb: Child,
+        name: String,
+    }
+
+    impl TestDisplay {
+        /// Start a server, the tests using it are ignored by default as they
+        /// need `Xvfb`
+        fn start() -> TestDisplay {
+            let mut xvfb = Command::new("Xvfb")
+                .args([
+                    "-displayfd",
+                    "1",
+                    "-nolisten",
+                    "tcp",
+                    "-screen",
+                    "0",
+                    "640x480x24",
+                ])
+                .stdout(Stdio::piped())
+                .stderr(Stdio::null())
+                .spawn()
+                .expect("Xvfb is needed, run the ignored tests only where it's installed");
+            let mut number = String::new();
+            BufReader::new(xvfb.stdout.take().unwrap())
+                .read_line(&mut number)
+                .unwrap();
+            TestDisplay {
+                xvfb,
+                name: format!(":{}", number.trim()),
+            }
+        }
+    }
+
+    impl Drop for TestDisplay {
+        fn drop(&mut self) {
+            let _ = self.xvfb.kill();
+            let _ = self.xvfb.wait();
+        }
+    }
+
+    /// Stand-in for the panel's tray manager, embeds the windows that ask
+    struct TestTrayManager {
+        docked: Arc<Mutex<Vec<Window>>>,
+        _conn: Arc<RustConnection>,
+    }
+
+    impl TestTrayManager {
+        fn start(display: &TestDisplay) -> TestTrayManager {
+            let (conn, screen_num) = x11rb::connect(Some(&display.name)).unwrap();
+            let conn = Arc::new(conn);
+            let screen = conn.setup().roots[screen_num].clone();
+            let atoms = Atoms::new(&conn, screen_num).unwrap();
+            let tray = conn.generate_id().unwrap();
+            conn.create_window(
+                screen.root_depth,
+                tray,
+                screen.root,
+                0,
+                0,
+                200,
+                24,
+                0,
+                WindowClass::INPUT_OUTPUT,
+                COPY_FROM_PARENT,
+                &CreateWindowAux::new(),
+            )
+            .unwrap();
+            conn.map_window(tray).unwrap();
+            conn.set_selection_owner(tray, atoms.selection, CURRENT_TIME)
+                .unwrap();
+            conn.sync().unwrap();
+
+            let docked = Arc::new(Mutex::new(vec![]));
+            {
+                let conn = conn.clone();
+                let docked = docked.clone();
+                std::thread::spawn(move || {
+                    while let Ok(event) = conn.wait_for_event() {
+                        if let Event::ClientMessage(e) = event {
+                            let data = e.data.as_data32();
+                            if e.type_ == atoms.opcode && data[1] == SYSTEM_TRAY_REQUEST_DOCK {
+                                let icon = data[2];
+                                conn.reparent_window(icon, tray, 0, 0).unwrap();
+                                conn.map_window(icon).unwrap();
+                                conn.sync().unwrap();
+                                docked.lock().unwrap().push(icon);
+                            }
+                        }
+                    }
+                });
+            }
+            TestTrayManager {
+                docked,
+                _conn: conn,
+            }
+        }
+
+        fn wait_docked(&self) -> Window {
+            let start = Instant::now();
+            while start.elapsed() < Duration::from_secs(5) {
+                if let Some(window) = self.docked.lock().unwrap().first() {
+                    return *window;
+                }
+                std::thread::sleep(Duration::from_millis(10));
+            }
+            panic!("Tray icon was not docked");
+        }
+    }
+
+    fn build(display: &TestDisplay) -> (XEmbedTrayIcon<Events>, Receiver<Events>) {
+        let (s, r) = std::sync::mpsc::channel();
+        let builder = TrayIconBuilder::new()
+            .sender(s)
+            .icon_from_buffer(include_bytes!("../../testresource/icon1.ico"))
+            .tooltip("Cool Tray Icon")
+            .on_click(Events::Click)
+            .on_double_click(Events::DoubleClick)
+            .on_right_click(Events::RightClick)
+            .on_menu_open(Events::MenuOpen)
+            .on_menu_close(Events::MenuClose)
+            .on_middle_click(Events::MiddleClick)
+            .on_scroll(|delta, _| Some(Events::Scroll(delta)))
+            .menu(
+                MenuBuilder::new()
+                    .item("Item 1", Events::Item1)
+                    .submenu("Sub Menu", MenuBuilder::new().item("Item 2", Events::Item2)),
+            );
+        let trayicon = super::super::build_xembed(Some(&display.name), &builder).unwrap();
+        (trayicon, r)
+    }
+
+    /// Synthetic button event, as if the user pressed the button at x, y of
+    /// the root window
+    fn button(conn: &RustConnection, window: Window, press: bool, detail: u8, time: u32) {
+        let (response_type, mask) = if press {
+            (BUTTON_PRESS_EVENT, EventMask::BUTTON_PRESS)
+        } else {
+            (BUTTON_RELEASE_EVENT, EventMask::BUTTON_RELEASE)
+        };
+        let event = ButtonPressEvent {
+            response_type,
+            detail,
+            sequence: 0,
+            time,
+            root: conn.setup().roots[0].root,
+            event: window,
+            child: NONE,
+            root_x: 100,
+            root_y: 100,
+            event_x: 4,
+            event_y: 4,
+            state: KeyButMask::default(),
+            same_screen: true,
+        };
+        conn.send_event(false, window, mask, event).unwrap();
+        conn.sync().unwrap();
+    }
+
+    #[test]
+    fn test_compose() {
+        let icon = SniIcon::from_buffer(include_bytes!("../../testresource/icon1.ico"), None, None)
+            .unwrap();
+        assert_eq!(best_pixmap(&icon, 24).unwrap().width, 24);
+        assert_eq!(best_pixmap(&icon, 30).unwrap().width, 32);
+        assert_eq!(best_pixmap(&icon, 100).unwrap().width, 64);
+
+        // Half transparent red on opaque blue, and on transparent
+        let pixmap = SniPixmap {
+            width: 1,
+            height: 1,
+            argb: vec![0x80, 0xff, 0, 0],
+        };
+        let blended = compose(&pixmap, 3, 1, vec![0xff00_00ff; 3]);
+        assert_eq!(blended, vec![0xff00_00ff, 0xff80_007f, 0xff00_00ff]);
+        let premultiplied = compose(&pixmap, 1, 2, vec![0; 2]);
+        assert_eq!(premultiplied, vec![0x8080_0000, 0]);
+
+        let pixels = vec![0x0102_0304];
+        assert_eq!(
+            encode_pixels(&pixels, ImageOrder::LSB_FIRST),
+            vec![4, 3, 2, 1]
+        );
+        assert_eq!(
+            decode_pixels(
+                &encode_pixels(&pixels, ImageOrder::MSB_FIRST),
+                ImageOrder::MSB_FIRST
+            ),
+            pixels
+        );
+    }
+
+    #[test]
+    #[ignore = "needs Xvfb"]
+    fn test_dock_and_click() {
+        let display = TestDisplay::start();
+        let manager = TestTrayManager::start(&display);
+        let (trayicon, r) = build(&display);
+        let window = manager.wait_docked();
+        let (conn, _) = x11rb::connect(Some(&display.name)).unwrap();
+        let recv = || r.recv_timeout(Duration::from_secs(5)).unwrap();
+
+        button(&conn, window, true, 1, 1000);
+        button(&conn, window, false, 1, 1050);
+        button(&conn, window, true, 1, 1200);
+        button(&conn, window, false, 1, 1250);
+        assert_eq!(recv(), Events::Click);
+        assert_eq!(recv(), Events::DoubleClick);
+        assert_eq!(recv(), Events::Click);
+
+        // Wheel down and middle click
+        button(&conn, window, true, 5, 1500);
+        button(&conn, window, false, 5, 1500);
+        button(&conn, window, false, 2, 1600);
+        assert_eq!(recv(), Events::Scroll(-1));
+        assert_eq!(recv(), Events::MiddleClick);
+
+        // Right click opens the menu at the pointer, and the first item is
+        // chosen by clicking just below the pointer
+        button(&conn, window, false, 3, 2000);
+        assert_eq!(recv(), Events::RightClick);
+        assert_eq!(recv(), Events::MenuOpen);
+        let item = ButtonPressEvent {
+            response_type: BUTTON_RELEASE_EVENT,
+            detail: 1,
+            sequence: 0,
+            time: 3000,
+            root: conn.setup().roots[0].root,
+            event: window,
+            child: NONE,
+            root_x: 110,
+            root_y: 108,
+            event_x: 0,
+            event_y: 0,
+            state: KeyButMask::default(),
+            same_screen: true,
+        };
+        button(&conn, window, true, 1, 2900);
+        conn.send_event(false, window, EventMask::BUTTON_RELEASE, item)
+            .unwrap();
+        conn.sync().unwrap();
+        assert_eq!(recv(), Events::MenuClose);
+        assert_eq!(recv(), Events::Item1);
+
+        drop(trayicon);
+        assert!(r.recv_timeout(Duration::from_millis(100)).is_err());
+    }
+
+    #[test]
+    #[ignore = "needs Xvfb"]
+    fn test_no_tray_manager() {
+        let display = TestDisplay::start();
+        let (s, _r) = std::sync::mpsc::channel::<Events>();
+        let builder = TrayIconBuilder::new()
+            .sender(s)
+            .icon_from_buffer(include_bytes!("../../testresource/icon1.ico"));
+        assert_eq!(
+            super::super::build_xembed(Some(&display.name), &builder)
+                .err()
+                .map(|e| e.to_string()),
+            Some("GetSelectionOwner failed: no system tray manager on the display".into())
+        );
+    }
+}
@@ -11,7 +11,8 @@ mod bindings {
 use std::collections::HashMap;
 use wintrayicon::WinTrayIconImpl;
 
-use crate::{Error, MenuBuilder, MenuItem, TrayIconBuilder};
+use crate::trayevent::EventDispatcher;
+use crate::{Error, MenuBuilder, MenuChange, MenuItem, MenuLabel, MenuProvider, TrayIconBuilder};
 use winhmenu::WinHMenu;
 use winnotifyicon::WinNotifyIcon;
 
@@ -19,15 +20,204 @@ use winnotifyicon::WinNotifyIcon;
 pub use winhicon::WinHIcon as IconSys;
 pub use wintrayicon::WinTrayIcon as TrayIconSys;
 
+/// Ids the tray icon can send, see `TrayIconBuilder::build`
+///
+/// The events are sent on the thread of the message loop, so any id will do
+/// on Windows.
+pub trait BackendId {}
+
+impl<T> BackendId for T {}
+
 #[derive(Debug)]
 pub struct MenuSys<T>
 where
     T: PartialEq + Clone + 'static,
 {
-    ids: HashMap<usize, T>,
+    ids: MenuIds<T>,
     menu: WinHMenu,
 }
 
+/// What the command ids and the submenu handles of a menu stand for
+#[derive(Debug)]
+pub struct MenuIds<T>
+where
+    T: PartialEq + Clone + 'static,
+{
+    events: HashMap<usize, T>,
+
+    /// Lazy submenus by their `HMENU`
+    lazy: HashMap<isize, LazyMenu<T>>,
+
+    /// Last command id taken
+    last: usize,
+
+    /// Command ids of the forgotten items of lazy submenus
+    ///
+    /// `WM_COMMAND` has only 16 bits for the id, so they are taken again
+    /// instead of counting up on each opening.
+    free: Vec<usize>,
+}
+
+#[derive(Debug)]
+struct LazyMenu<T>
+where
+    T: PartialEq + Clone + 'static,
+{
+    provider: MenuProvider<T>,
+
+    /// Command ids of the items of the last opening
+    commands: Vec<usize>,
+}
+
+impl<T> MenuIds<T>
+where
+    T: PartialEq + Clone + 'static,
+{
+    fn new() -> MenuIds<T> {
+        MenuIds {
+            events: HashMap::new(),
+            lazy: HashMap::new(),
+            last: 0,
+            free: vec![],
+        }
+    }
+
+    pub fn get(&self, command: usize) -> Option<&T> {
+        self.events.get(&command)
+    }
+
+    fn next_command(&mut self) -> usize {
+        self.free.pop().unwrap_or_else(|| {
+            self.last += 1;
+            self.last
+        })
+    }
+
+    /// Fill the lazy submenu `handle` of `menu` from its provider, returns
+    /// false if the submenu is not lazy
+    ///
+    /// The items of the previous opening, nested lazy submenus included,
+    /// are forgotten first. Panic of the provider is given to `report`.
+    fn populate(&mut self, menu: &mut WinHMenu, handle: isize, report: &dyn Fn(&Error)) -> bool {
+        let provider = match self.lazy.get(&handle) {
+            Some(lazy) => lazy.provider.clone(),
+            None => return false,
+        };
+        let submenu = match menu.find_mut(handle) {
+            Some(submenu) => submenu,
+            None => return false,
+        };
+        for nested in submenu.handles() {
+            let commands = if nested == handle {
+                self.lazy
+                    .get_mut(&handle)
+                    .map(|l| std::mem::take(&mut l.commands))
+            } else {
+                self.lazy.remove(&nested).map(|l| l.commands)
+            };
+            for command in commands.unwrap_or_default() {
+                self.events.remove(&command);
+                self.free.push(command);
+            }
+        }
+        submenu.clear();
+
+        let mut made = MenuIds {
+            events: HashMap::new(),
+            lazy: HashMap::new(),
+            last: self.last,
+            free: std::mem::take(&mut self.free),
+        };
+        build_menu_inner(&mut made, submenu, &provider.provide_or_report(report));
+        self.last = made.last;
+        self.free = made.free;
+        if let Some(lazy) = self.lazy.get_mut(&handle) {
+            lazy.commands = made.events.keys().copied().collect();
+        }
+        self.events.extend(made.events);
+        self.lazy.extend(made.lazy);
+        true
+    }
+}
+
+impl<T> MenuSys<T>
+where
+    T: PartialEq + Clone + 'static,
+{
+    /// Change the items in place, false if the menu must be built again
+    ///
+    /// Only property changes of items with a command id of their own are
+    /// done in place. Submenus are found by position only.
+    fn update(&mut self, menu: &MenuBuilder<T>, changes: &[MenuChange<T>]) -> bool {
+        changes.iter().all(|change| {
+            let item = match change {
+                MenuChange::Update { path, .. } => menu.item_at(path),
+                _ => None,
+            };
+            let item = match item {
+                Some(item) => item,
+                None => return false,
+            };
+            let mut commands = self
+                .ids
+                .events
+                .iter()
+                .filter(|(_, id)| Some(*id) == item.id())
+                .map(|(command, _)| *command);
+            let command = match (commands.next(), commands.next()) {
+                (Some(command), None) => command,
+                _ => return false,
+            };
+            let image = item.icon().and_then(|i| i.image_sized(MENU_ICON_SIZE));
+            let image = image.as_deref();
+            match item {
+                MenuItem::Item { name, disabled, .. } => self.menu.update_item(
+                    command,
+                    &win32_label(name),
+                    None,
+                    false,
+                    *disabled,
+                    image,
+                ),
+                MenuItem::Checkable {
+                    name,
+                    is_checked,
+                    disabled,
+                    ..
+                } => self.menu.update_item(
+                    command,
+                    &win32_label(name),
+                    Some(*is_checked),
+                    false,
+                    *disabled,
+                    image,
+                ),
+                MenuItem::Radio {
+                    name,
+                    is_selected,
+                    disabled,
+                    ..
+                } => self.menu.update_item(
+                    command,
+                    &win32_label(name),
+                    Some(*is_selected),
+                    true,
+                    *disabled,
+                    image,
+                ),
+                MenuItem::Submenu { .. } | MenuItem::LazySubmenu { .. } | MenuItem::Separator => {
+                    false
+                }
+            }
+        })
+    }
+
+    /// Fill the submenu if it's lazy, called on `WM_INITMENUPOPUP`
+    fn populate(&mut self, handle: isize, report: &dyn Fn(&Error)) -> bool {
+        self.ids.populate(&mut self.menu, handle, report)
+    }
+}
+
 /// Build the tray icon
 pub fn build_trayicon<T>(builder: &TrayIconBuilder<T>) -> Result<TrayIconSys<T>, Error>
 where
@@ -36,10 +226,7 @@ where
     let mut menu: Option<MenuSys<T>> = None;
     let tooltip = &builder.tooltip;
     let hicon = &builder.icon.as_ref()?.sys;
-    let on_click = builder.on_click.clone();
-    let on_right_click = builder.on_right_click.clone();
-    let sender = builder.sender.clone().ok_or(Error::SenderMissing)?;
-    let on_double_click = builder.on_double_click.clone();
+    let events = EventDispatcher::new(builder)?;
     let notify_icon = WinNotifyIcon::new(hicon, tooltip);
 
     // Try to get a popup menu
@@ -47,14 +234,7 @@ where
         menu = Some(rhmenu.build()?);
     }
 
-    Ok(WinTrayIconImpl::new(
-        sender,
-        menu,
-        notify_icon,
-        on_click,
-        on_double_click,
-        on_right_click,
-    )?)
+    Ok(WinTrayIconImpl::new(events, menu, notify_icon)?)
 }
 
 /// Build the menu from Windows HMENU
@@ -62,69 +242,127 @@ pub fn build_menu<T>(builder: &MenuBuilder<T>) -> Result<MenuSys<T>, Error>
 where
     T: PartialEq + Clone + 'static,
 {
-    let mut j = 0;
-    build_menu_inner(&mut j, builder)
+    let mut ids = MenuIds::new();
+    let mut hmenu = WinHMenu::new()?;
+    build_menu_inner(&mut ids, &mut hmenu, builder);
+    Ok(MenuSys { ids, menu: hmenu })
 }
 
 /// Recursive menu builder
 ///
-/// Having a j value as mutable reference it's capable of handling nested
-/// submenus
-fn build_menu_inner<T>(j: &mut usize, builder: &MenuBuilder<T>) -> Result<MenuSys<T>, Error>
+/// Taking the command ids from `ids` it's capable of handling nested
+/// submenus. Lazy submenus are left empty until they are populated.
+fn build_menu_inner<T>(ids: &mut MenuIds<T>, hmenu: &mut WinHMenu, builder: &MenuBuilder<T>)
 where
     T: PartialEq + Clone + 'static,
 {
-    let mut hmenu = WinHMenu::new()?;
-    let mut map: HashMap<usize, T> = HashMap::new();
-    builder.menu_items.iter().for_each(|item| match item {
-        MenuItem::Submenu {
-            id,
-            name,
-            children,
-            disabled,
-            ..
-        } => {
-            if let Some(id) = id {
-                *j += 1;
-                map.insert(*j, id.clone());
+    for item in &builder.menu_items {
+        // Command id of the item itself, submenus have none
+        let mut item_command = None;
+        let added = match item {
+            MenuItem::Submenu {
+                id,
+                name,
+                children,
+                disabled,
+                ..
+            } => {
+                if let Some(id) = id {
+                    let command = ids.next_command();
+                    ids.events.insert(command, id.clone());
+                }
+                match WinHMenu::new() {
+                    Ok(mut submenu) => {
+                        build_menu_inner(ids, &mut submenu, children);
+                        hmenu.add_child_menu(&win32_label(name), submenu, *disabled)
+                    }
+                    Err(_) => false,
+                }
             }
-            if let Ok(menusys) = build_menu_inner(j, children) {
-                map.extend(menusys.ids.into_iter());
-                hmenu.add_child_menu(&name, menusys.menu, *disabled);
+
+            MenuItem::LazySubmenu {
+                id,
+                name,
+                provider,
+                disabled,
+                ..
+            } => {
+                if let Some(id) = id {
+                    let command = ids.next_command();
+                    ids.events.insert(command, id.clone());
+                }
+                match WinHMenu::new() {
+                    Ok(submenu) => {
+                        let lazy = LazyMenu {
+                            provider: provider.clone(),
+                            commands: vec![],
+                        };
+                        ids.lazy.insert(submenu.handle(), lazy);
+                        hmenu.add_child_menu(&win32_label(name), submenu, *disabled)
+                    }
+                    Err(_) => false,
+                }
             }
-        }
 
-        MenuItem::Checkable {
-            name,
-            is_checked,
-            id,
-            disabled,
-            ..
-        } => {
-            *j += 1;
-            map.insert(*j, id.clone());
-            hmenu.add_checkable_item(&name, *is_checked, *j, *disabled);
-        }
+            MenuItem::Checkable {
+                name,
+                is_checked,
+                id,
+                disabled,
+                ..
+            } => {
+                let command = ids.next_command();
+                ids.events.insert(command, id.clone());
+                item_command = Some(command);
+                hmenu.add_checkable_item(&win32_label(name), *is_checked, command, *disabled)
+            }
 
-        MenuItem::Item {
-            name, id, disabled, ..
-        } => {
-            *j += 1;
-            map.insert(*j, id.clone());
-            hmenu.add_menu_item(&name, *j, *disabled);
-        }
+            MenuItem::Radio {
+                name,
+                is_selected,
+                id,
+                disabled,
+                ..
+            } => {
+                let command = ids.next_command();
+                ids.events.insert(command, id.clone());
+                item_command = Some(command);
+                hmenu.add_radio_item(&win32_label(name), *is_selected, command, *disabled)
+            }
 
-        MenuItem::Separator => {
-            hmenu.add_separator();
+            MenuItem::Item {
+                name, id, disabled, ..
+            } => {
+                let command = ids.next_command();
+                ids.events.insert(command, id.clone());
+                item_command = Some(command);
+                hmenu.add_menu_item(&win32_label(name), command, *disabled)
+            }
+
+            MenuItem::Separator => hmenu.add_separator(),
+        };
+
+        // Item is found by its position, so only one that was added
+        let image = item.icon().and_then(|i| i.image_sized(MENU_ICON_SIZE));
+        if let (true, Some(image)) = (added, image) {
+            hmenu.set_last_item_icon(&image, item_command);
         }
-    });
+    }
+}
 
-    Ok(MenuSys {
-        ids: map,
-        menu: hmenu,
-    })
+/// Label text for `AppendMenuW`, `&` marks the mnemonic and tab separates
+/// the accelerator
+fn win32_label(label: &MenuLabel) -> String {
+    let text = label.marked('&').replace('\t', " ");
+    match label.accelerator() {
+        Some(accelerator) => format!("{}\t{}", text, accelerator),
+        None => text,
+    }
 }
 
+/// Size of the item icons, the bitmaps are shown unscaled
+const MENU_ICON_SIZE: u32 = 16;
+
 // For pattern matching, these are in own mod
 mod msgs {
     pub const WM_USER_TRAYICON: u32 = 0x400 + 1001;
@@ -177,7 +415,7 @@ pub(crate) mod tests {
             .item("Item 1", Events::Item1);
 
         if let Ok(menusys) = build_menu(&builder) {
-            assert_eq!(menusys.ids.len(), 9);
+            assert_eq!(menusys.ids.events.len(), 9);
         } else {
             panic!()
         }
@@ -1,9 +1,9 @@
 use super::bindings::{
     Windows::Win32::Controls::IMAGE_FLAGS,
     Windows::Win32::MenusAndResources::HICON,
-    Windows::Win32::SystemServices::TRUE,
+    Windows::Win32::SystemServices::{HINSTANCE, TRUE},
     Windows::Win32::WindowsAndMessaging::{
-        CopyIcon, CreateIconFromResourceEx, DestroyIcon, LookupIconIdFromDirectoryEx,
+        CopyIcon, CreateIcon, CreateIconFromResourceEx, DestroyIcon, LookupIconIdFromDirectoryEx,
     },
 };
 use crate::{Error, IconBase};
@@ -16,7 +16,7 @@ pub struct WinHIcon {
 
 impl IconBase for WinHIcon {
     fn from_buffer(
-        buffer: &'static [u8],
+        buffer: &[u8],
         width: Option<u32>,
         height: Option<u32>,
     ) -> Result<WinHIcon, Error> {
@@ -49,6 +49,32 @@ impl IconBase for WinHIcon {
         }
         Ok(WinHIcon { hicon })
     }
+
+    fn from_rgba(width: u32, height: u32, rgba: &[u8]) -> Result<WinHIcon, Error> {
+        // 32 bit color bits are BGRA, and the AND mask is left empty so the
+        // alpha channel alone decides the transparency. Mask rows are WORD
+        // aligned.
+        let bgra = rgba
+            .chunks_exact(4)
+            .flat_map(|p| [p[2], p[1], p[0], p[3]])
+            .collect::<Vec<u8>>();
+        let mask = vec![0u8; (width as usize).div_ceil(16) * 2 * height as usize];
+        let hicon = unsafe {
+            CreateIcon(
+                HINSTANCE::default(),
+                width as i32,
+                height as i32,
+                1,
+                32,
+                mask.as_ptr(),
+                bgra.as_ptr(),
+            )
+        };
+        if hicon.is_null() {
+            return Err(Error::last_os_error("CreateIcon"));
+        }
+        Ok(WinHIcon { hicon })
+    }
 }
 
 impl Clone for WinHIcon {
@@ -1,12 +1,15 @@
 use super::bindings::{
+    Windows::Win32::Gdi::{CreateBitmap, DeleteObject, HBITMAP},
     Windows::Win32::MenusAndResources::HMENU,
-    Windows::Win32::SystemServices::PWSTR,
+    Windows::Win32::SystemServices::{FALSE, PWSTR, TRUE},
     Windows::Win32::WindowsAndMessaging::{
-        AppendMenuW, CreatePopupMenu, DestroyMenu, TrackPopupMenu, HWND, MENU_ITEM_FLAGS,
-        TRACK_POPUP_MENU_FLAGS,
+        AppendMenuW, CheckMenuRadioItem, CreatePopupMenu, DestroyMenu, GetMenuItemCount,
+        RemoveMenu, SetMenuItemInfoW, TrackPopupMenu, HWND, MENUITEMINFOW, MENU_ITEM_FLAGS,
+        MENU_ITEM_MASK, MENU_ITEM_STATE, MENU_ITEM_TYPE, TRACK_POPUP_MENU_FLAGS,
     },
 };
 use super::wchar::wchar;
+use crate::icon::ico::IcoImage;
 use crate::Error;
 use std::fmt::Debug;
 
@@ -16,6 +19,9 @@ use std::fmt::Debug;
 pub struct WinHMenu {
     hmenu: HMENU,
     child_menus: Vec<WinHMenu>,
+
+    /// Item bitmaps with the command id of the item, if it has one
+    bitmaps: Vec<(Option<usize>, HBITMAP)>,
 }
 
 impl WinHMenu {
@@ -24,14 +30,60 @@ impl WinHMenu {
             hmenu: unsafe {
                 let res = CreatePopupMenu();
                 if res.is_null() {
-                    return Err(Error::OsError);
+                    return Err(Error::last_os_error("CreatePopupMenu"));
                 }
                 res
             },
             child_menus: vec![],
+            bitmaps: vec![],
         })
     }
 
+    /// Raw `HMENU`, like the `wparam` of `WM_INITMENUPOPUP`
+    pub fn handle(&self) -> isize {
+        self.hmenu.0
+    }
+
+    /// Find the menu or one of its child menus by handle
+    pub fn find_mut(&mut self, handle: isize) -> Option<&mut WinHMenu> {
+        let mut stack = vec![self];
+        while let Some(menu) = stack.pop() {
+            if menu.hmenu.0 == handle {
+                return Some(menu);
+            }
+            stack.extend(menu.child_menus.iter_mut());
+        }
+        None
+    }
+
+    /// Handles of the menu and all of its child menus
+    pub fn handles(&self) -> Vec<isize> {
+        let mut handles = vec![];
+        let mut stack = vec![self];
+        while let Some(menu) = stack.pop() {
+            handles.push(menu.hmenu.0);
+            stack.extend(menu.child_menus.iter());
+        }
+        handles
+    }
+
+    /// Remove all items, child menus and bitmaps are destroyed
+    ///
+    /// `RemoveMenu` doesn't destroy the child menus, dropping them does.
+    pub fn clear(&mut self) {
+        unsafe {
+            while GetMenuItemCount(self.hmenu) > 0 {
+                if !RemoveMenu(self.hmenu, 0, MENU_ITEM_FLAGS::MF_BYPOSITION).as_bool() {
+                    break;
+                }
+            }
+            for (_, bitmap) in self.bitmaps.drain(..) {
+                DeleteObject(bitmap);
+            }
+        }
+        self.child_menus.clear();
+    }
+
     pub fn add_menu_item(&self, name: &str, id: usize, disabled: bool) -> bool {
         let res = unsafe {
             AppendMenuW(
@@ -69,6 +121,22 @@ impl WinHMenu {
         let res = unsafe { AppendMenuW(self.hmenu, flags, id, PWSTR(wchar(name).as_mut_ptr())) };
         res.as_bool()
     }
+
+    /// Radio item is a checkable item with MFT_RADIOCHECK, which only
+    /// `CheckMenuRadioItem` sets on an appended item
+    pub fn add_radio_item(&self, name: &str, is_selected: bool, id: usize, disabled: bool) -> bool {
+        if !self.add_checkable_item(name, false, id, disabled) {
+            return false;
+        }
+        if !is_selected {
+            return true;
+        }
+        let id = id as u32;
+        let res =
+            unsafe { CheckMenuRadioItem(self.hmenu, id, id, id, MENU_ITEM_FLAGS::MF_BYCOMMAND.0) };
+        res.as_bool()
+    }
+
     pub fn add_child_menu(&mut self, name: &str, menu: WinHMenu, disabled: bool) -> bool {
         let mut flags = MENU_ITEM_FLAGS::MF_POPUP;
         if disabled {
@@ -86,24 +154,131 @@ impl WinHMenu {
         res.as_bool()
     }
 
+    /// Change the item with the command id in place, child menus included
+    ///
+    /// `checked` is `None` for items that can't be checked.
+    pub fn update_item(
+        &mut self,
+        id: usize,
+        name: &str,
+        checked: Option<bool>,
+        radio: bool,
+        disabled: bool,
+        image: Option<&IcoImage>,
+    ) -> bool {
+        let mut state = MENU_ITEM_STATE::MFS_ENABLED;
+        if checked == Some(true) {
+            state |= MENU_ITEM_STATE::MFS_CHECKED;
+        }
+        if disabled {
+            state |= MENU_ITEM_STATE::MFS_DISABLED;
+        }
+        let bitmap = match image {
+            Some(image) => create_bitmap(image),
+            None => HBITMAP::NULL,
+        };
+        if image.is_some() && bitmap.is_null() {
+            return false;
+        }
+        let mut name = wchar(name);
+        let mut info = MENUITEMINFOW {
+            cbSize: std::mem::size_of::<MENUITEMINFOW>() as u32,
+            fMask: MENU_ITEM_MASK::MIIM_STRING
+                | MENU_ITEM_MASK::MIIM_STATE
+                | MENU_ITEM_MASK::MIIM_FTYPE
+                | MENU_ITEM_MASK::MIIM_BITMAP,
+            fType: if radio {
+                MENU_ITEM_TYPE::MFT_RADIOCHECK
+            } else {
+                MENU_ITEM_TYPE::MFT_STRING
+            },
+            fState: state,
+            dwTypeData: PWSTR(name.as_mut_ptr()),
+            hbmpItem: bitmap,
+            ..Default::default()
+        };
+        let updated =
+            unsafe { SetMenuItemInfoW(self.hmenu, id as u32, FALSE, &mut info).as_bool() };
+
+        if !updated {
+            if !bitmap.is_null() {
+                unsafe { DeleteObject(bitmap) };
+            }
+            return false;
+        }
+
+        // Replaced bitmap is deleted, the new one is kept by the same menu
+        match self.replace_bitmap(id, bitmap) {
+            Some(old) => {
+                unsafe { DeleteObject(old) };
+            }
+            None if !bitmap.is_null() => self.bitmaps.push((Some(id), bitmap)),
+            None => {}
+        }
+        true
+    }
+
+    /// Put the bitmap in place of the one of the item with the command id,
+    /// returns the replaced one
+    ///
+    /// Null bitmap removes the item's bitmap.
+    fn replace_bitmap(&mut self, id: usize, bitmap: HBITMAP) -> Option<HBITMAP> {
+        if let Some(position) = self.bitmaps.iter().position(|(i, _)| *i == Some(id)) {
+            let old = if bitmap.is_null() {
+                self.bitmaps.remove(position).1
+            } else {
+                std::mem::replace(&mut self.bitmaps[position].1, bitmap)
+            };
+            return Some(old);
+        }
+        self.child_menus
+            .iter_mut()
+            .find_map(|child| child.replace_bitmap(id, bitmap))
+    }
+
+    /// Show the image next to the last added item, `id` is its command id
+    /// if it has one
+    pub fn set_last_item_icon(&mut self, image: &IcoImage, id: Option<usize>) -> bool {
+        let bitmap = create_bitmap(image);
+        if bitmap.is_null() {
+            return false;
+        }
+        self.bitmaps.push((id, bitmap));
+        let mut info = MENUITEMINFOW {
+            cbSize: std::mem::size_of::<MENUITEMINFOW>() as u32,
+            fMask: MENU_ITEM_MASK::MIIM_BITMAP,
+            hbmpItem: bitmap,
+            ..Default::default()
+        };
+        unsafe {
+            let position = GetMenuItemCount(self.hmenu) - 1;
+            position >= 0
+                && SetMenuItemInfoW(self.hmenu, position as u32, TRUE, &mut info).as_bool()
+        }
+    }
+
     pub fn add_separator(&self) -> bool {
         let res = unsafe { AppendMenuW(self.hmenu, MENU_ITEM_FLAGS::MF_SEPARATOR, 0, PWSTR::NULL) };
         res.as_bool()
     }
+}
 
-    pub fn track(&self, hwnd: HWND, x: i32, y: i32) {
-        unsafe {
-            TrackPopupMenu(
-                self.hmenu,
-                TRACK_POPUP_MENU_FLAGS::default(),
-                x,
-                y,
-                0,
-                hwnd,
-                std::ptr::null_mut(),
-            )
-        };
-    }
+/// Show the menu `handle` until it's closed
+///
+/// Takes the plain handle, the modal loop of the menu calls the window
+/// procedure which may change the menus, e.g. fill lazy submenus.
+pub fn track(handle: isize, hwnd: HWND, x: i32, y: i32) {
+    unsafe {
+        TrackPopupMenu(
+            HMENU(handle),
+            TRACK_POPUP_MENU_FLAGS::default(),
+            x,
+            y,
+            0,
+            hwnd,
+            std::ptr::null_mut(),
+        )
+    };
 }
 
 unsafe impl Send for WinHMenu {}
@@ -111,6 +286,36 @@ unsafe impl Sync for WinHMenu {}
 
 impl Drop for WinHMenu {
     fn drop(&mut self) {
-        unsafe { DestroyMenu(self.hmenu) };
+        unsafe {
+            DestroyMenu(self.hmenu);
+            for (_, bitmap) in &self.bitmaps {
+                DeleteObject(*bitmap);
+            }
+        };
+    }
+}
+
+/// Bitmap of the image, the menu keeping it deletes it
+///
+/// Menus draw 32 bit bitmaps with premultiplied alpha, rows top to
+/// bottom.
+fn create_bitmap(image: &IcoImage) -> HBITMAP {
+    let bgra = image
+        .rgba
+        .chunks_exact(4)
+        .flat_map(|p| {
+            let alpha = p[3] as u32;
+            let pre = |c: u8| (c as u32 * alpha / 255) as u8;
+            [pre(p[2]), pre(p[1]), pre(p[0]), p[3]]
+        })
+        .collect::<Vec<u8>>();
+    unsafe {
+        CreateBitmap(
+            image.width as i32,
+            image.height as i32,
+            1,
+            32,
+            bgra.as_ptr() as _,
+        )
     }
 }
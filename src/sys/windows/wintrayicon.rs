@@ -1,11 +1,11 @@
 use super::bindings::{
-    Windows::Win32::DisplayDevices::POINT,
     Windows::Win32::Gdi::HBRUSH,
+    Windows::Win32::KeyboardAndMouseInput::GetKeyState,
     Windows::Win32::MenusAndResources::{HCURSOR, HICON, HMENU},
     Windows::Win32::SystemServices::{GetModuleHandleW, HINSTANCE, LRESULT, PWSTR},
     Windows::Win32::WindowsAndMessaging,
     Windows::Win32::WindowsAndMessaging::{
-        CreateWindowExW, DefWindowProcW, GetCursorPos, GetWindowLongPtrW, RegisterClassW,
+        CreateWindowExW, DefWindowProcW, GetMessagePos, GetWindowLongPtrW, RegisterClassW,
         RegisterWindowMessageW, SendMessageW, SetForegroundWindow, SetWindowLongPtrW,
         CREATESTRUCTW, HWND, LPARAM, WINDOW_EX_STYLE, WINDOW_LONG_PTR_INDEX, WINDOW_STYLE,
         WNDCLASSW, WNDCLASS_STYLES, WPARAM,
@@ -33,8 +33,19 @@ fn HIWORD(l: DWORD) -> WORD {
 }
 
 use super::wchar::wchar;
-use super::{msgs, winnotifyicon::WinNotifyIcon, MenuSys};
-use crate::{trayiconsender::TrayIconSender, Error, Icon, MenuBuilder, TrayIconBase};
+use super::{msgs, winhmenu, winnotifyicon::WinNotifyIcon, MenuSys};
+use crate::trayevent::EventDispatcher;
+use crate::{
+    Capabilities, DeliveryStats, Error, EventInfo, Icon, MenuBuilder, MenuChange, Modifiers,
+    MouseButton, TrayEvent, TrayIconBase,
+};
+
+// Virtual key codes of the modifiers
+const VK_SHIFT: i32 = 0x10;
+const VK_CONTROL: i32 = 0x11;
+const VK_MENU: i32 = 0x12;
+const VK_LWIN: i32 = 0x5B;
+const VK_RWIN: i32 = 0x5C;
 
 pub type WinTrayIcon<T> = WindowBox<T>;
 
@@ -92,13 +103,13 @@ where
     T: PartialEq + Clone + 'static,
 {
     hwnd: HWND,
-    sender: TrayIconSender<T>,
+    events: EventDispatcher<T>,
     menu: Option<MenuSys<T>>,
     notify_icon: WinNotifyIcon,
-    on_click: Option<T>,
-    on_double_click: Option<T>,
-    on_right_click: Option<T>,
     msg_taskbarcreated: Option<UINT>,
+
+    /// Menu to show once `wndproc` returns, with the click that opened it
+    popup: Option<(isize, EventInfo)>,
 }
 
 unsafe impl<T> Send for WinTrayIconImpl<T> where T: PartialEq + Clone {}
@@ -109,14 +120,10 @@ where
     T: PartialEq + Clone + 'static,
 {
     #[allow(clippy::new_ret_no_self)]
-    #[allow(clippy::too_many_arguments)]
     pub(crate) fn new(
-        sender: TrayIconSender<T>,
+        events: EventDispatcher<T>,
         menu: Option<MenuSys<T>>,
         notify_icon: WinNotifyIcon,
-        on_click: Option<T>,
-        on_double_click: Option<T>,
-        on_right_click: Option<T>,
     ) -> Result<WinTrayIcon<T>, Error>
     where
         T: PartialEq + Clone + 'static,
@@ -143,11 +150,9 @@ where
                 hwnd: HWND::default(),
                 notify_icon,
                 menu,
-                on_click,
-                on_right_click,
-                on_double_click,
-                sender,
+                events,
                 msg_taskbarcreated: None,
+                popup: None,
             });
             let ptr = Box::into_raw(window);
             let hwnd = CreateWindowExW(
@@ -166,7 +171,7 @@ where
             );
 
             if hwnd == HWND::default() {
-                return Err(Error::OsError);
+                return Err(Error::last_os_error("CreateWindowExW"));
             }
 
             Ok(WindowBox(ptr))
@@ -192,39 +197,43 @@ where
                 match lparam.0 as UINT {
                     // Left click tray icon
                     WindowsAndMessaging::WM_LBUTTONUP => {
-                        if let Some(e) = self.on_click.as_ref() {
-                            self.sender.send(e);
-                        }
+                        let info = event_info(Some(MouseButton::Left));
+                        self.events.send(TrayEvent::Click(info));
                     }
 
                     // Right click tray icon
                     WindowsAndMessaging::WM_RBUTTONUP => {
                         // Send right click event
-                        if let Some(e) = self.on_right_click.as_ref() {
-                            self.sender.send(e);
-                        }
-
-                        // Show menu, if it's there
-                        if let Some(menu) = &self.menu {
-                            let mut pos = POINT { x: 0, y: 0 };
-                            unsafe {
-                                GetCursorPos(&mut pos as _);
-                                SetForegroundWindow(self.hwnd);
-                            }
-                            menu.menu.track(self.hwnd, pos.x, pos.y);
-                        }
+                        let info = event_info(Some(MouseButton::Right));
+                        self.events.send(TrayEvent::RightClick(info));
+
+                        // Show menu, if it's there, see `track_popup`
+                        self.popup = self.menu.as_ref().map(|m| (m.menu.handle(), info));
+                    }
+
+                    // Middle click tray icon
+                    WindowsAndMessaging::WM_MBUTTONUP => {
+                        let info = event_info(Some(MouseButton::Middle));
+                        self.events.send(TrayEvent::MiddleClick(info));
                     }
 
                     // Double click tray icon
                     WindowsAndMessaging::WM_LBUTTONDBLCLK => {
-                        if let Some(e) = self.on_double_click.as_ref() {
-                            self.sender.send(e);
-                        }
+                        let info = event_info(Some(MouseButton::Left));
+                        self.events.send(TrayEvent::DoubleClick(info));
                     }
                     _ => {}
                 }
             }
 
+            // Submenu is about to open, lazy ones are filled now
+            WindowsAndMessaging::WM_INITMENUPOPUP => {
+                let events = &self.events;
+                if let Some(menu) = self.menu.as_mut() {
+                    menu.populate(wparam.0 as isize, &|e| events.report(e));
+                }
+            }
+
             // Any of the menu commands
             //
             // https://docs.microsoft.com/en-us/windows/win32/menurc/wm-command#parameters
@@ -234,10 +243,15 @@ where
 
                 // Menu command
                 if cmd == 0 {
-                    if let Some(v) = self.menu.as_ref() {
-                        if let Some(event) = v.ids.get(&(identifier as usize)) {
-                            self.sender.send(event);
-                        }
+                    let id = self
+                        .menu
+                        .as_ref()
+                        .and_then(|v| v.ids.get(identifier as usize))
+                        .cloned();
+                    if let Some(id) = id {
+                        // Chosen with mouse or keyboard, it's not told which
+                        let info = event_info(None);
+                        self.events.send(TrayEvent::MenuItem { id, info });
                     }
                 }
             }
@@ -291,8 +305,10 @@ where
                 _ => {
                     let window_ptr = GetWindowLongPtrW(hwnd, WINDOW_LONG_PTR_INDEX::GWL_USERDATA);
                     if window_ptr != 0 {
-                        let window: &mut WinTrayIconImpl<T> = &mut *(window_ptr as *mut _);
-                        window.wndproc(msg, wparam, lparam)
+                        let window = window_ptr as *mut WinTrayIconImpl<T>;
+                        let result = (*window).wndproc(msg, wparam, lparam);
+                        Self::track_popup(window);
+                        result
                     } else {
                         DefWindowProcW(hwnd, msg, wparam, lparam)
                     }
@@ -302,14 +318,74 @@ where
     }
 }
 
+impl<T> WinTrayIconImpl<T>
+where
+    T: PartialEq + Clone + 'static,
+{
+    /// Show the menu `wndproc` asked for
+    ///
+    /// Tracking runs a modal loop that calls the window procedure again, and
+    /// that fills the lazy submenus. So no reference to the window is held
+    /// across it, only the raw pointer. Tracking returns when the menu is
+    /// closed, the chosen item comes after as WM_COMMAND.
+    unsafe fn track_popup(window: *mut WinTrayIconImpl<T>) {
+        let (handle, info) = match (*window).popup.take() {
+            Some(popup) => popup,
+            None => return,
+        };
+        let hwnd = (*window).hwnd;
+        let (x, y) = info.position.unwrap_or((0, 0));
+        SetForegroundWindow(hwnd);
+        (*window).events.send(TrayEvent::MenuOpen(info));
+        winhmenu::track(handle, hwnd, x, y);
+        (*window)
+            .events
+            .send(TrayEvent::MenuClose(event_info(None)));
+    }
+}
+
+/// Details of the message being handled, the cursor position and the
+/// modifiers are as they were when the message was posted
+///
+/// `GetKeyState` follows the messages the thread has taken from its queue,
+/// unlike `GetAsyncKeyState` or `GetCursorPos`.
+fn event_info(button: Option<MouseButton>) -> EventInfo {
+    let pressed = |key| unsafe { GetKeyState(key) < 0 };
+    let modifiers = Modifiers {
+        shift: pressed(VK_SHIFT),
+        ctrl: pressed(VK_CONTROL),
+        alt: pressed(VK_MENU),
+        logo: pressed(VK_LWIN) || pressed(VK_RWIN),
+    };
+
+    // Screen coordinates, signed on multiple monitors
+    let pos = unsafe { GetMessagePos() };
+    EventInfo::now(button)
+        .with_modifiers(modifiers)
+        .at(LOWORD(pos) as i16 as i32, HIWORD(pos) as i16 as i32)
+}
+
 impl<T> TrayIconBase<T> for WinTrayIconImpl<T>
 where
     T: PartialEq + Clone + 'static,
 {
+    /// The notification area doesn't forward the wheel to the icons, and
+    /// the hidden window never has the focus to get it
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            middle_click: true,
+            scroll: false,
+        }
+    }
+
+    fn delivery_stats(&self) -> DeliveryStats {
+        self.events.stats()
+    }
+
     /// Set the tooltip
     fn set_tooltip(&mut self, tooltip: &str) -> Result<(), Error> {
         if !self.notify_icon.set_tooltip(tooltip) {
-            return Err(Error::OsError);
+            return Err(Error::last_os_error("Shell_NotifyIconW"));
         }
         Ok(())
     }
@@ -317,7 +393,7 @@ where
     /// Set icon
     fn set_icon(&mut self, icon: &Icon) -> Result<(), Error> {
         if !self.notify_icon.set_icon(&icon.sys) {
-            return Err(Error::IconLoadingFailed);
+            return Err(Error::last_os_error("Shell_NotifyIconW"));
         }
         Ok(())
     }
@@ -331,6 +407,55 @@ where
         }
         Ok(())
     }
+
+    /// Update menu, property changes are done in place
+    fn update_menu(
+        &mut self,
+        menu: &MenuBuilder<T>,
+        changes: &[MenuChange<T>],
+    ) -> Result<(), Error> {
+        if self
+            .menu
+            .as_mut()
+            .is_some_and(|sys| sys.update(menu, changes))
+        {
+            return Ok(());
+        }
+        self.set_menu(menu)
+    }
+}
+
+impl<T> TrayIconBase<T> for WindowBox<T>
+where
+    T: PartialEq + Clone + 'static,
+{
+    fn capabilities(&self) -> Capabilities {
+        (**self).capabilities()
+    }
+
+    fn delivery_stats(&self) -> DeliveryStats {
+        (**self).delivery_stats()
+    }
+
+    fn set_tooltip(&mut self, tooltip: &str) -> Result<(), Error> {
+        (**self).set_tooltip(tooltip)
+    }
+
+    fn set_icon(&mut self, icon: &Icon) -> Result<(), Error> {
+        (**self).set_icon(icon)
+    }
+
+    fn set_menu(&mut self, menu: &MenuBuilder<T>) -> Result<(), Error> {
+        (**self).set_menu(menu)
+    }
+
+    fn update_menu(
+        &mut self,
+        menu: &MenuBuilder<T>,
+        changes: &[MenuChange<T>],
+    ) -> Result<(), Error> {
+        (**self).update_menu(menu, changes)
+    }
 }
 
 impl<T> Drop for WinTrayIconImpl<T>
@@ -0,0 +1,655 @@
+use crate::trayiconsender::{catch_panic, DeliveryPolicy, TrayIconSender};
+use crate::{Error, TrayIconBuilder};
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+/// Event of the tray icon with the details of the user action
+///
+/// Sent to the sender given with `TrayIconBuilder::event_sender`. Unlike the
+/// simple events, every action is sent, no ids need to be chosen for them.
+#[derive(Debug, Clone, PartialEq)]
+pub enum TrayEvent<T>
+where
+    T: PartialEq + Clone + 'static,
+{
+    Click(EventInfo),
+    DoubleClick(EventInfo),
+    RightClick(EventInfo),
+    MiddleClick(EventInfo),
+
+    /// Mouse wheel over the icon, `delta` is positive up or right
+    ///
+    /// The size of `delta` is what the host gives, one wheel step is 1 on
+    /// XEmbed trays and usually 120 elsewhere.
+    Scroll {
+        delta: i32,
+        orientation: ScrollOrientation,
+        info: EventInfo,
+    },
+
+    /// Menu item with the id was chosen
+    MenuItem {
+        id: T,
+        info: EventInfo,
+    },
+
+    MenuOpen(EventInfo),
+    MenuClose(EventInfo),
+}
+
+impl<T> TrayEvent<T>
+where
+    T: PartialEq + Clone + 'static,
+{
+    pub fn info(&self) -> &EventInfo {
+        match self {
+            TrayEvent::Click(info)
+            | TrayEvent::DoubleClick(info)
+            | TrayEvent::RightClick(info)
+            | TrayEvent::MiddleClick(info)
+            | TrayEvent::Scroll { info, .. }
+            | TrayEvent::MenuItem { info, .. }
+            | TrayEvent::MenuOpen(info)
+            | TrayEvent::MenuClose(info) => info,
+        }
+    }
+
+    /// Id of the chosen menu item
+    pub fn menu_item(&self) -> Option<&T> {
+        match self {
+            TrayEvent::MenuItem { id, .. } => Some(id),
+            _ => None,
+        }
+    }
+
+    /// Same user action, whatever the details
+    fn same_action(&self, other: &TrayEvent<T>) -> bool {
+        match (self, other) {
+            (TrayEvent::Click(_), TrayEvent::Click(_))
+            | (TrayEvent::DoubleClick(_), TrayEvent::DoubleClick(_))
+            | (TrayEvent::RightClick(_), TrayEvent::RightClick(_))
+            | (TrayEvent::MiddleClick(_), TrayEvent::MiddleClick(_))
+            | (TrayEvent::MenuOpen(_), TrayEvent::MenuOpen(_))
+            | (TrayEvent::MenuClose(_), TrayEvent::MenuClose(_)) => true,
+            (
+                TrayEvent::Scroll {
+                    delta, orientation, ..
+                },
+                TrayEvent::Scroll {
+                    delta: other_delta,
+                    orientation: other_orientation,
+                    ..
+                },
+            ) => delta == other_delta && orientation == other_orientation,
+            (TrayEvent::MenuItem { id, .. }, TrayEvent::MenuItem { id: other_id, .. }) => {
+                id == other_id
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Details of the user action, as far as the host tells them
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct EventInfo {
+    /// Button of the click, `None` e.g. for menu items chosen with the
+    /// keyboard
+    pub button: Option<MouseButton>,
+
+    /// Pointer position in screen coordinates
+    pub position: Option<(i32, i32)>,
+
+    /// Modifier keys held down, all false if the host doesn't tell
+    pub modifiers: Modifiers,
+
+    /// When the event was received from the host
+    pub timestamp: SystemTime,
+}
+
+impl EventInfo {
+    /// Info of an event received now
+    pub(crate) fn now(button: Option<MouseButton>) -> EventInfo {
+        EventInfo {
+            button,
+            position: None,
+            modifiers: Modifiers::default(),
+            timestamp: SystemTime::now(),
+        }
+    }
+
+    pub(crate) fn at(mut self, x: i32, y: i32) -> EventInfo {
+        self.position = Some((x, y));
+        self
+    }
+
+    pub(crate) fn with_modifiers(mut self, modifiers: Modifiers) -> EventInfo {
+        self.modifiers = modifiers;
+        self
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MouseButton {
+    Left,
+    Right,
+    Middle,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Modifiers {
+    pub shift: bool,
+    pub ctrl: bool,
+    pub alt: bool,
+
+    /// Windows or Super key
+    pub logo: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScrollOrientation {
+    Vertical,
+    Horizontal,
+}
+
+/// Events the tray icon can receive, see `TrayIcon::capabilities`
+///
+/// Clicks, the menu and its events work everywhere. The rest depend on the
+/// host, e.g. a StatusNotifierItem host may never call `Scroll`, which can't
+/// be known in advance. Those are reported as supported.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Capabilities {
+    pub middle_click: bool,
+    pub scroll: bool,
+}
+
+/// Makes the id of a scroll event, see `TrayIconBuilder::on_scroll`
+#[derive(Clone)]
+pub(crate) struct ScrollHandler<T>(Arc<dyn Fn(i32, ScrollOrientation) -> Option<T> + Send + Sync>);
+
+impl<T> ScrollHandler<T> {
+    pub(crate) fn new<F>(f: F) -> ScrollHandler<T>
+    where
+        F: Fn(i32, ScrollOrientation) -> Option<T> + Send + Sync + 'static,
+    {
+        ScrollHandler(Arc::new(f))
+    }
+}
+
+impl<T> fmt::Debug for ScrollHandler<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("ScrollHandler")
+    }
+}
+
+/// Gets the errors of event delivery, see `TrayIconBuilder::on_error`
+#[derive(Clone)]
+pub(crate) struct ErrorHandler(Arc<dyn Fn(&Error) + Send + Sync>);
+
+impl ErrorHandler {
+    pub(crate) fn new<F>(f: F) -> ErrorHandler
+    where
+        F: Fn(&Error) + Send + Sync + 'static,
+    {
+        ErrorHandler(Arc::new(f))
+    }
+}
+
+impl fmt::Debug for ErrorHandler {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("ErrorHandler")
+    }
+}
+
+/// Counts of the events given to the senders, see
+/// `TrayIcon::delivery_stats`
+///
+/// An event sent to both `sender` and `event_sender` counts twice.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct DeliveryStats {
+    /// Events the channel took or the callback returned from
+    pub sent: u64,
+
+    /// Events lost to a full or closed channel, or to a panicking callback
+    pub dropped: u64,
+
+    /// Events skipped by `DeliveryPolicy::Coalesce`
+    pub coalesced: u64,
+
+    /// Panics of the `on_error` handler, they are caught and ignored
+    pub error_handler_panics: u64,
+}
+
+#[derive(Debug, Default)]
+struct DeliveryCounters {
+    sent: AtomicU64,
+    dropped: AtomicU64,
+    coalesced: AtomicU64,
+    error_handler_panics: AtomicU64,
+}
+
+/// Sender with the last event it sent, for coalescing
+#[derive(Debug, Clone)]
+struct Delivery<E>
+where
+    E: PartialEq + Clone + 'static,
+{
+    sender: TrayIconSender<E>,
+    last: Arc<Mutex<Option<E>>>,
+}
+
+impl<E> Delivery<E>
+where
+    E: PartialEq + Clone + 'static,
+{
+    fn new(sender: TrayIconSender<E>) -> Delivery<E> {
+        Delivery {
+            sender,
+            last: Arc::new(Mutex::new(None)),
+        }
+    }
+}
+
+/// Senders of a tray icon and the ids of the simple events
+///
+/// Backends make a `TrayEvent` of each user action, this sends it to the
+/// rich sender and its id to the simple one.
+#[derive(Debug, Clone)]
+pub(crate) struct EventDispatcher<T>
+where
+    T: PartialEq + Clone + 'static,
+{
+    sender: Option<Delivery<T>>,
+    event_sender: Option<Delivery<TrayEvent<T>>>,
+    policy: DeliveryPolicy,
+    counters: Arc<DeliveryCounters>,
+    on_click: Option<T>,
+    on_double_click: Option<T>,
+    on_right_click: Option<T>,
+    on_middle_click: Option<T>,
+    on_scroll: Option<ScrollHandler<T>>,
+    on_menu_open: Option<T>,
+    on_menu_close: Option<T>,
+    on_error: Option<ErrorHandler>,
+}
+
+impl<T> EventDispatcher<T>
+where
+    T: PartialEq + Clone + 'static,
+{
+    /// Returns `Error::SenderMissing` if the builder has neither sender
+    pub(crate) fn new(builder: &TrayIconBuilder<T>) -> Result<EventDispatcher<T>, Error> {
+        if builder.sender.is_none() && builder.event_sender.is_none() {
+            return Err(Error::SenderMissing);
+        }
+        Ok(EventDispatcher {
+            sender: builder.sender.clone().map(Delivery::new),
+            event_sender: builder.event_sender.clone().map(Delivery::new),
+            policy: builder.delivery_policy,
+            counters: Arc::new(DeliveryCounters::default()),
+            on_click: builder.on_click.clone(),
+            on_double_click: builder.on_double_click.clone(),
+            on_right_click: builder.on_right_click.clone(),
+            on_middle_click: builder.on_middle_click.clone(),
+            on_scroll: builder.on_scroll.clone(),
+            on_menu_open: builder.on_menu_open.clone(),
+            on_menu_close: builder.on_menu_close.clone(),
+            on_error: builder.on_error.clone(),
+        })
+    }
+
+    pub(crate) fn send(&self, event: TrayEvent<T>) {
+        if let Some(sender) = &self.sender {
+            let id = match &event {
+                TrayEvent::Click(_) => self.on_click.clone(),
+                TrayEvent::DoubleClick(_) => self.on_double_click.clone(),
+                TrayEvent::RightClick(_) => self.on_right_click.clone(),
+                TrayEvent::MiddleClick(_) => self.on_middle_click.clone(),
+                TrayEvent::Scroll {
+                    delta, orientation, ..
+                } => self.on_scroll.as_ref().and_then(|f| {
+                    catch_panic(|| (f.0)(*delta, *orientation)).unwrap_or_else(|error| {
+                        self.counters.dropped.fetch_add(1, Ordering::Relaxed);
+                        self.report(&error);
+                        None
+                    })
+                }),
+                TrayEvent::MenuItem { id, .. } => Some(id.clone()),
+                TrayEvent::MenuOpen(_) => self.on_menu_open.clone(),
+                TrayEvent::MenuClose(_) => self.on_menu_close.clone(),
+            };
+            if let Some(id) = id {
+                self.deliver(sender, id, PartialEq::eq);
+            }
+        }
+        if let Some(sender) = &self.event_sender {
+            self.deliver(sender, event, TrayEvent::same_action);
+        }
+    }
+
+    pub(crate) fn stats(&self) -> DeliveryStats {
+        DeliveryStats {
+            sent: self.counters.sent.load(Ordering::Relaxed),
+            dropped: self.counters.dropped.load(Ordering::Relaxed),
+            coalesced: self.counters.coalesced.load(Ordering::Relaxed),
+            error_handler_panics: self.counters.error_handler_panics.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Give the error to the error handler, if it panics the panic is only
+    /// counted
+    pub(crate) fn report(&self, error: &Error) {
+        if let Some(on_error) = &self.on_error {
+            if catch_panic(|| (on_error.0)(error)).is_err() {
+                self.counters
+                    .error_handler_panics
+                    .fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Send by the policy, the failure is counted and given to the error
+    /// handler, the backends have no one to return it to
+    fn deliver<E, F>(&self, delivery: &Delivery<E>, e: E, same: F)
+    where
+        E: PartialEq + Clone + 'static,
+        F: Fn(&E, &E) -> bool,
+    {
+        let coalesce = self.policy == DeliveryPolicy::Coalesce;
+        if coalesce {
+            let last = delivery.last.lock().unwrap_or_else(|e| e.into_inner());
+            let repeat = last.as_ref().is_some_and(|last| same(last, &e));
+            if repeat && delivery.sender.is_pending() == Some(true) {
+                self.counters.coalesced.fetch_add(1, Ordering::Relaxed);
+                return;
+            }
+        }
+        match delivery.sender.send(&e, self.policy) {
+            Ok(()) => {
+                self.counters.sent.fetch_add(1, Ordering::Relaxed);
+
+                // Only an event the receiver got can be repeated
+                if coalesce {
+                    *delivery.last.lock().unwrap_or_else(|e| e.into_inner()) = Some(e);
+                }
+            }
+            Err(error) => {
+                self.counters.dropped.fetch_add(1, Ordering::Relaxed);
+                self.report(&error);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{DeliveryFailure, MenuBuilder};
+
+    #[derive(Copy, Clone, Eq, PartialEq, Debug)]
+    enum Events {
+        Click,
+        Item1,
+    }
+
+    #[test]
+    fn test_rich_events() {
+        let (s, r) = std::sync::mpsc::channel();
+        let (rich_s, rich_r) = std::sync::mpsc::channel();
+        let (_trayicon, mock) = TrayIconBuilder::new()
+            .sender(s)
+            .event_sender(rich_s)
+            .icon_from_buffer(include_bytes!("./testresource/icon1.ico"))
+            .on_click(Events::Click)
+            .menu(MenuBuilder::new().item("Item 1", Events::Item1))
+            .build_mock()
+            .unwrap();
+        mock.click();
+        mock.right_click();
+        mock.activate_menu_item(&Events::Item1).unwrap();
+
+        // Simple mode gets only the chosen ids
+        assert_eq!(
+            r.try_iter().collect::<Vec<_>>(),
+            vec![Events::Click, Events::Item1]
+        );
+        let events = rich_r.try_iter().collect::<Vec<_>>();
+        assert_eq!(events.len(), 3);
+        assert!(matches!(events[0], TrayEvent::Click(_)));
+        assert_eq!(events[0].info().button, Some(MouseButton::Left));
+        assert_eq!(events[1].info().button, Some(MouseButton::Right));
+        assert_eq!(events[2].menu_item(), Some(&Events::Item1));
+        assert_eq!(events[2].info().modifiers, Modifiers::default());
+    }
+
+    #[test]
+    fn test_callback() {
+        let events = Arc::new(std::sync::Mutex::new(vec![]));
+        let errors = Arc::new(std::sync::Mutex::new(vec![]));
+        let (_trayicon, mock) = {
+            let events = events.clone();
+            let errors = errors.clone();
+            TrayIconBuilder::new()
+                .on_event(move |e: &Events| {
+                    if *e == Events::Item1 {
+                        panic!("Item 1 failed");
+                    }
+                    events.lock().unwrap().push(*e);
+                })
+                .on_error(move |e| errors.lock().unwrap().push(e.clone()))
+                .icon_from_buffer(include_bytes!("./testresource/icon1.ico"))
+                .on_click(Events::Click)
+                .menu(MenuBuilder::new().item("Item 1", Events::Item1))
+                .build_mock()
+                .unwrap()
+        };
+        mock.click();
+        mock.activate_menu_item(&Events::Item1).unwrap();
+        mock.click();
+
+        // Panic is caught, and the later events still arrive
+        assert_eq!(*events.lock().unwrap(), vec![Events::Click, Events::Click]);
+        assert_eq!(
+            *errors.lock().unwrap(),
+            vec![Error::CallbackPanicked {
+                message: "Item 1 failed".into()
+            }]
+        );
+    }
+
+    #[test]
+    fn test_scroll_handler_panic() {
+        let (s, r) = std::sync::mpsc::channel();
+        let errors = Arc::new(Mutex::new(vec![]));
+        let (trayicon, mock) = {
+            let errors = errors.clone();
+            TrayIconBuilder::new()
+                .sender(s)
+                .on_error(move |e| errors.lock().unwrap().push(e.clone()))
+                .icon_from_buffer(include_bytes!("./testresource/icon1.ico"))
+                .on_click(Events::Click)
+                .on_scroll(|_, orientation| match orientation {
+                    ScrollOrientation::Vertical => panic!("Scroll failed"),
+                    ScrollOrientation::Horizontal => None,
+                })
+                .build_mock()
+                .unwrap()
+        };
+        mock.scroll(1, ScrollOrientation::Vertical);
+        mock.scroll(1, ScrollOrientation::Horizontal);
+        mock.click();
+        assert_eq!(r.try_iter().collect::<Vec<_>>(), vec![Events::Click]);
+        assert_eq!(trayicon.delivery_stats().dropped, 1);
+        assert_eq!(
+            *errors.lock().unwrap(),
+            vec![Error::CallbackPanicked {
+                message: "Scroll failed".into()
+            }]
+        );
+    }
+
+    #[test]
+    fn test_delivery_failure() {
+        let (s, r) = std::sync::mpsc::channel();
+        let errors = Arc::new(Mutex::new(vec![]));
+        let (trayicon, mock) = {
+            let errors = errors.clone();
+            TrayIconBuilder::new()
+                .sender(s)
+                .on_error(move |e| errors.lock().unwrap().push(e.clone()))
+                .icon_from_buffer(include_bytes!("./testresource/icon1.ico"))
+                .on_click(Events::Click)
+                .build_mock()
+                .unwrap()
+        };
+        mock.click();
+        drop(r);
+        mock.click();
+        mock.right_click();
+        assert_eq!(
+            trayicon.delivery_stats(),
+            DeliveryStats {
+                sent: 1,
+                dropped: 1,
+                coalesced: 0,
+                error_handler_panics: 0
+            }
+        );
+        assert_eq!(
+            *errors.lock().unwrap(),
+            vec![Error::EventNotDelivered {
+                reason: DeliveryFailure::Disconnected
+            }]
+        );
+    }
+
+    #[test]
+    fn test_error_handler_panic() {
+        let (s, r) = std::sync::mpsc::channel();
+        let (trayicon, mock) = TrayIconBuilder::new()
+            .sender(s)
+            .on_error(|_| panic!("Error handler failed"))
+            .icon_from_buffer(include_bytes!("./testresource/icon1.ico"))
+            .on_click(Events::Click)
+            .build_mock()
+            .unwrap();
+        drop(r);
+        mock.click();
+        mock.click();
+        assert_eq!(
+            trayicon.delivery_stats(),
+            DeliveryStats {
+                sent: 0,
+                dropped: 2,
+                coalesced: 0,
+                error_handler_panics: 2
+            }
+        );
+    }
+
+    #[cfg(feature = "crossbeam-channel")]
+    #[test]
+    fn test_delivery_policy() {
+        use std::time::Duration;
+
+        let build = |policy, s| {
+            TrayIconBuilder::new()
+                .sender_crossbeam(s)
+                .delivery_policy(policy)
+                .icon_from_buffer(include_bytes!("./testresource/icon1.ico"))
+                .on_click(Events::Click)
+                .menu(MenuBuilder::new().item("Item 1", Events::Item1))
+                .build_mock()
+                .unwrap()
+        };
+
+        // Full channel drops the rest
+        let (s, r) = crossbeam_channel::bounded(1);
+        let (trayicon, mock) = build(DeliveryPolicy::Drop, s);
+        mock.click();
+        mock.click();
+        assert_eq!(trayicon.delivery_stats().dropped, 1);
+        assert_eq!(r.try_iter().collect::<Vec<_>>(), vec![Events::Click]);
+
+        // Waits for room, and then gives up
+        let (s, _r) = crossbeam_channel::bounded(1);
+        let errors = Arc::new(Mutex::new(vec![]));
+        let (trayicon, mock) = {
+            let errors = errors.clone();
+            TrayIconBuilder::new()
+                .sender_crossbeam(s)
+                .delivery_policy(DeliveryPolicy::Block(Duration::from_millis(10)))
+                .on_error(move |e| errors.lock().unwrap().push(e.clone()))
+                .icon_from_buffer(include_bytes!("./testresource/icon1.ico"))
+                .on_click(Events::Click)
+                .build_mock()
+                .unwrap()
+        };
+        mock.click();
+        mock.click();
+        assert_eq!(trayicon.delivery_stats().dropped, 1);
+        assert_eq!(
+            *errors.lock().unwrap(),
+            vec![Error::EventNotDelivered {
+                reason: DeliveryFailure::TimedOut
+            }]
+        );
+
+        // Repeats are skipped until the receiver catches up
+        let (s, r) = crossbeam_channel::bounded(10);
+        let (trayicon, mock) = build(DeliveryPolicy::Coalesce, s);
+        mock.click();
+        mock.click();
+        mock.activate_menu_item(&Events::Item1).unwrap();
+        mock.activate_menu_item(&Events::Item1).unwrap();
+        assert_eq!(
+            r.try_iter().collect::<Vec<_>>(),
+            vec![Events::Click, Events::Item1]
+        );
+        mock.activate_menu_item(&Events::Item1).unwrap();
+        assert_eq!(r.try_iter().collect::<Vec<_>>(), vec![Events::Item1]);
+        assert_eq!(
+            trayicon.delivery_stats(),
+            DeliveryStats {
+                sent: 3,
+                dropped: 0,
+                coalesced: 2,
+                error_handler_panics: 0
+            }
+        );
+
+        // Dropped event is no repeat, the receiver never got it
+        let (s, r) = crossbeam_channel::bounded(1);
+        let (trayicon, mock) = build(DeliveryPolicy::Coalesce, s);
+        mock.click();
+        mock.activate_menu_item(&Events::Item1).unwrap();
+        mock.activate_menu_item(&Events::Item1).unwrap();
+        assert_eq!(
+            trayicon.delivery_stats(),
+            DeliveryStats {
+                sent: 1,
+                dropped: 2,
+                coalesced: 0,
+                error_handler_panics: 0
+            }
+        );
+        assert_eq!(r.try_iter().collect::<Vec<_>>(), vec![Events::Click]);
+    }
+
+    #[test]
+    fn test_event_sender_only() {
+        let (rich_s, rich_r) = std::sync::mpsc::channel();
+        let (_trayicon, mock) = TrayIconBuilder::<Events>::new()
+            .event_sender(rich_s)
+            .icon_from_buffer(include_bytes!("./testresource/icon1.ico"))
+            .build_mock()
+            .unwrap();
+        mock.double_click();
+        let events = rich_r.try_iter().collect::<Vec<_>>();
+        assert!(matches!(
+            events[..],
+            [TrayEvent::Click(_), TrayEvent::DoubleClick(_)]
+        ));
+    }
+}
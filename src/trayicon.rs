@@ -1,10 +1,10 @@
-use crate::{Error, Icon, MenuBuilder, TrayIconBase, TrayIconBuilder};
+use crate::{Capabilities, DeliveryStats, Error, Icon, MenuBuilder, TrayIconBase, TrayIconBuilder};
 
 pub struct TrayIcon<T>
 where
     T: PartialEq + Clone + 'static,
 {
-    sys: crate::TrayIconSys<T>,
+    sys: Box<dyn TrayIconBase<T>>,
     builder: TrayIconBuilder<T>,
 }
 
@@ -12,7 +12,7 @@ impl<T> TrayIcon<T>
 where
     T: PartialEq + Clone + 'static,
 {
-    pub(crate) fn new(sys: crate::TrayIconSys<T>, builder: TrayIconBuilder<T>) -> TrayIcon<T> {
+    pub(crate) fn new(sys: Box<dyn TrayIconBase<T>>, builder: TrayIconBuilder<T>) -> TrayIcon<T> {
         TrayIcon { builder, sys }
     }
 
@@ -35,8 +35,13 @@ where
         if self.builder.menu.as_ref() == Some(menu) {
             return Ok(());
         }
+        self.builder.check_menu(menu)?;
+        let changes = self.builder.menu.as_ref().map(|old| old.diff(menu));
         self.builder.menu = Some(menu.clone());
-        self.sys.set_menu(menu)
+        match changes {
+            Some(changes) => self.sys.update_menu(menu, &changes),
+            None => self.sys.set_menu(menu),
+        }
     }
 
     /// Set the tooltip if changed
@@ -48,15 +53,33 @@ where
         self.sys.set_tooltip(tooltip)
     }
 
+    /// Events the host can send
+    ///
+    /// E.g. `on_scroll` is never sent if `scroll` is false.
+    pub fn capabilities(&self) -> Capabilities {
+        self.sys.capabilities()
+    }
+
+    /// Counts of the sent, dropped and coalesced events
+    ///
+    /// A growing `dropped` count means the receiver is gone or doesn't keep
+    /// up, e.g. its thread hangs.
+    pub fn delivery_stats(&self) -> DeliveryStats {
+        self.sys.delivery_stats()
+    }
+
     /// Set disabled
     ///
     /// Prefer building a new menu if application state changes instead of
     /// mutating a menu with this method. Suggestion is to use just `set_menu`
     /// method instead of this.
+    ///
+    /// Nothing is done if the menu has no item with the id.
     pub fn set_menu_item_disabled(&mut self, id: T, disabled: bool) -> Result<(), Error> {
         if let Some(menu) = self.builder.menu.as_mut() {
-            let _ = menu.set_disabled(id, disabled);
-            let _ = self.sys.set_menu(menu);
+            if menu.set_disabled(id, disabled) {
+                return self.sys.set_menu(menu);
+            }
         }
         Ok(())
     }
@@ -66,14 +89,40 @@ where
     /// Prefer building a new menu when application state changes instead of
     /// mutating a menu with this method.  Suggestion is to use just `set_menu`
     /// method instead of this.
+    ///
+    /// Nothing is done if the menu has no checkable item with the id.
     pub fn set_menu_item_checkable(&mut self, id: T, checked: bool) -> Result<(), Error> {
         if let Some(menu) = self.builder.menu.as_mut() {
-            let _ = menu.set_checkable(id, checked);
-            let _ = self.sys.set_menu(menu);
+            if menu.set_checkable(id, checked) {
+                return self.sys.set_menu(menu);
+            }
+        }
+        Ok(())
+    }
+
+    /// Select radio item, the other items of its group are cleared
+    ///
+    /// Prefer building a new menu when application state changes instead of
+    /// mutating a menu with this method.
+    ///
+    /// Nothing is done if the menu has no radio item with the id.
+    pub fn set_menu_radio_selected(&mut self, id: T) -> Result<(), Error> {
+        if let Some(menu) = self.builder.menu.as_mut() {
+            if menu.set_radio(id) {
+                return self.sys.set_menu(menu);
+            }
         }
         Ok(())
     }
 
+    /// Get selected id of the radio group
+    ///
+    /// Prefer maintaining proper application state instead of getting the
+    /// selection with this method.
+    pub fn get_menu_radio_selected(&self, group: &str) -> Option<T> {
+        self.builder.menu.as_ref().and_then(|m| m.get_radio(group))
+    }
+
     /// Get checkable state
     ///
     /// Prefer maintaining proper application state instead of getting checkable
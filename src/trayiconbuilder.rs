@@ -1,18 +1,188 @@
-use crate::{trayiconsender::TrayIconSender, Icon, MenuBuilder, TrayIcon};
+use crate::trayevent::{ErrorHandler, ScrollHandler};
+use crate::trayiconsender::{DeliveryFailure, DeliveryPolicy, TrayIconSender};
+use crate::{
+    BackendId, Icon, MenuBuilder, MenuDiagnostic, MockTrayIcon, ScrollOrientation, TrayEvent,
+    TrayIcon,
+};
+use std::fmt::{self, Debug};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
-#[derive(Debug, Clone, Copy, PartialEq)]
+/// Errors of the tray icon, menu and icon functions
+///
+/// Errors carry what is known of the failure, e.g. the OS error code or the
+/// icon entry that couldn't be decoded. All errors are `Send + Sync`, so they
+/// can be used with `?` in functions returning `Box<dyn std::error::Error>`.
+#[derive(Debug, Clone, PartialEq)]
 pub enum Error {
-    MenuItemNotFound,
+    /// No menu item has the id, `id` is the `Debug` form of it
+    MenuItemNotFound { id: String },
+
+    /// Icon data is in unknown format or has no usable image
     IconLoadingFailed,
+
+    /// Image in the icon file couldn't be decoded, `entry` is its index in
+    /// the icon directory
+    IconEntryCorrupt {
+        entry: usize,
+        reason: IconEntryError,
+    },
+
+    /// Pixel buffer given to `Icon::from_rgba` doesn't match its dimensions
+    InvalidRgba { width: u32, height: u32, len: usize },
+
+    /// Icon file couldn't be loaded, `reason` tells why
+    IconFile { path: PathBuf, reason: Box<Error> },
+
+    /// Reading a file failed
+    Io(std::io::ErrorKind),
+
+    /// Builder has no sender
     SenderMissing,
+
+    /// Builder has no icon
     IconMissing,
-    OsError,
+
+    /// Event was lost, see `TrayIconBuilder::delivery_policy`
+    EventNotDelivered { reason: DeliveryFailure },
+
+    /// Callback given to `TrayIconBuilder::on_event`, `on_scroll` or
+    /// `MenuBuilder::lazy_submenu` panicked, `message` is the panic message
+    /// if it was a string
+    CallbackPanicked { message: String },
+
+    /// Menu has problems, see `TrayIconBuilder::reject_invalid_menus`
+    InvalidMenu(Vec<MenuDiagnostic>),
+
+    /// OS call failed, `code` is the error code of the OS if it gave one,
+    /// e.g. `GetLastError` on Windows or the X11 error code
+    Os {
+        call: &'static str,
+        code: Option<i32>,
+        message: String,
+    },
+
+    /// D-Bus call failed, `name` is the D-Bus error name if the peer sent
+    /// one, e.g. `org.freedesktop.DBus.Error.ServiceUnknown`
+    DBus {
+        call: &'static str,
+        name: Option<String>,
+        message: String,
+    },
 }
 
+impl Error {
+    /// Error of the menu item lookup
+    pub(crate) fn menu_item_not_found<T>(id: &T) -> Error
+    where
+        T: Debug,
+    {
+        Error::MenuItemNotFound {
+            id: format!("{:?}", id),
+        }
+    }
+
+    /// Error of failed OS call, from the last error of the thread
+    #[cfg(target_os = "windows")]
+    pub(crate) fn last_os_error(call: &'static str) -> Error {
+        let e = std::io::Error::last_os_error();
+        Error::Os {
+            call,
+            code: e.raw_os_error(),
+            message: e.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::MenuItemNotFound { id } => write!(f, "menu item {} not found", id),
+            Error::IconLoadingFailed => write!(f, "icon data is not a usable ICO or PNG"),
+            Error::IconEntryCorrupt { entry, reason } => {
+                write!(f, "icon entry {} can't be decoded: {}", entry, reason)
+            }
+            Error::InvalidRgba { width, height, len } => write!(
+                f,
+                "{} bytes of RGBA pixels don't make a {}x{} image",
+                len, width, height
+            ),
+            Error::IconFile { path, reason } => {
+                write!(
+                    f,
+                    "icon file {} can't be loaded: {}",
+                    path.display(),
+                    reason
+                )
+            }
+            Error::Io(kind) => write!(f, "I/O error: {:?}", kind),
+            Error::SenderMissing => write!(f, "tray icon builder has no sender"),
+            Error::IconMissing => write!(f, "tray icon builder has no icon"),
+            Error::EventNotDelivered { reason } => write!(f, "event not delivered: {}", reason),
+            Error::CallbackPanicked { message } => {
+                write!(f, "event callback panicked: {}", message)
+            }
+            Error::InvalidMenu(problems) => match problems.split_first() {
+                Some((first, [])) => write!(f, "invalid menu: {}", first),
+                Some((first, rest)) => write!(
+                    f,
+                    "invalid menu: {} and {} more problems",
+                    first,
+                    rest.len()
+                ),
+                None => write!(f, "invalid menu"),
+            },
+            Error::Os {
+                call,
+                code: Some(code),
+                message,
+            } => write!(f, "{} failed with code {}: {}", call, code, message),
+            Error::Os { call, message, .. } => write!(f, "{} failed: {}", call, message),
+            Error::DBus {
+                call,
+                name: Some(name),
+                message,
+            } => write!(f, "D-Bus call {} failed with {}: {}", call, name, message),
+            Error::DBus { call, message, .. } => {
+                write!(f, "D-Bus call {} failed: {}", call, message)
+            }
+        }
+    }
+}
+
+/// Display tells the reasons too, so errors have no source
+impl std::error::Error for Error {}
+
+/// Why an image of an icon file couldn't be decoded
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum IconEntryError {
+    /// Image data ends before the image does
+    Truncated,
+
+    /// PNG stream is broken, e.g. a checksum doesn't match
+    InvalidPng,
+
+    /// Valid image in a format the loader doesn't know, like 16 bits per
+    /// pixel or RLE compression
+    Unsupported,
+}
+
+impl fmt::Display for IconEntryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IconEntryError::Truncated => write!(f, "image data is truncated"),
+            IconEntryError::InvalidPng => write!(f, "PNG stream is invalid"),
+            IconEntryError::Unsupported => write!(f, "image format is not supported"),
+        }
+    }
+}
+
+impl std::error::Error for IconEntryError {}
+
 // Why do I need to do this, can't Rust do this automatically?
 impl From<&Error> for Error {
     fn from(e: &Error) -> Self {
-        *e
+        e.clone()
     }
 }
 
@@ -23,6 +193,7 @@ impl From<&Error> for Error {
 /// uses `std::sync::mpsc::Sender<T>`, additionally if `winit` feature is
 /// enabled you can choose to use `winit::event_loop::EventLoopProxy<T>` or with
 /// `crossbeam-channel` feature the `crossbeam_channel::Sender<T>` is available.
+/// Without a channel, `on_event` calls a function with each event.
 ///
 /// This is defined as consuming builder, this includes conditional helper
 /// `when` for composing conditionally some settings.
@@ -39,7 +210,15 @@ where
     pub(crate) on_click: Option<T>,
     pub(crate) on_double_click: Option<T>,
     pub(crate) on_right_click: Option<T>,
+    pub(crate) on_middle_click: Option<T>,
+    pub(crate) on_scroll: Option<ScrollHandler<T>>,
+    pub(crate) on_menu_open: Option<T>,
+    pub(crate) on_menu_close: Option<T>,
     pub(crate) sender: Option<TrayIconSender<T>>,
+    pub(crate) event_sender: Option<TrayIconSender<TrayEvent<T>>>,
+    pub(crate) on_error: Option<ErrorHandler>,
+    pub(crate) delivery_policy: DeliveryPolicy,
+    pub(crate) reject_invalid_menus: bool,
 }
 
 impl<T> TrayIconBuilder<T>
@@ -55,7 +234,15 @@ where
             on_click: None,
             on_double_click: None,
             on_right_click: None,
+            on_middle_click: None,
+            on_scroll: None,
+            on_menu_open: None,
+            on_menu_close: None,
             sender: None,
+            event_sender: None,
+            on_error: None,
+            delivery_policy: DeliveryPolicy::Drop,
+            reject_invalid_menus: false,
         }
     }
 
@@ -86,6 +273,86 @@ where
         self
     }
 
+    /// Optional feature, requires `async` feature
+    ///
+    /// The receiver is a `Stream` of the events, see `build_with_stream`.
+    #[cfg(feature = "async")]
+    pub fn sender_async(mut self, s: async_channel::Sender<T>) -> Self {
+        self.sender = Some(TrayIconSender::Async(s));
+        self
+    }
+
+    /// Call a function with the events, instead of sending them
+    ///
+    /// The function runs on the event thread of the tray icon, or on the
+    /// thread of the message loop on Windows. If it panics the panic is
+    /// caught and given to `on_error` as `Error::CallbackPanicked`.
+    pub fn on_event<F>(mut self, f: F) -> Self
+    where
+        F: Fn(&T) + Send + Sync + 'static,
+    {
+        self.sender = Some(TrayIconSender::Callback(Arc::new(f)));
+        self
+    }
+
+    /// Function getting the errors of event delivery, which have no caller
+    /// to return to
+    ///
+    /// It's called on the thread sending the events, e.g. with
+    /// `Error::EventNotDelivered` when the receiver is gone or stays full.
+    /// If it panics the panic is caught and counted in
+    /// `DeliveryStats::error_handler_panics`.
+    pub fn on_error<F>(mut self, f: F) -> Self
+    where
+        F: Fn(&Error) + Send + Sync + 'static,
+    {
+        self.on_error = Some(ErrorHandler::new(f));
+        self
+    }
+
+    /// What to do with events the channel can't take right away, dropping
+    /// them by default
+    ///
+    /// Lost events are counted in `TrayIcon::delivery_stats` and given to
+    /// `on_error`.
+    pub fn delivery_policy(mut self, policy: DeliveryPolicy) -> Self {
+        self.delivery_policy = policy;
+        self
+    }
+
+    /// Send every event with its details, in addition to or instead of the
+    /// ids sent with `sender`
+    ///
+    /// Either sender is enough to build the tray icon.
+    pub fn event_sender(mut self, s: std::sync::mpsc::Sender<TrayEvent<T>>) -> Self {
+        self.event_sender = Some(TrayIconSender::Std(s));
+        self
+    }
+
+    /// Optional feature, requires `winit` feature
+    #[cfg(feature = "winit")]
+    pub fn event_sender_winit(
+        mut self,
+        s: winit::event_loop::EventLoopProxy<TrayEvent<T>>,
+    ) -> Self {
+        self.event_sender = Some(TrayIconSender::Winit(s));
+        self
+    }
+
+    /// Optional feature, requires `crossbeam-channel` feature
+    #[cfg(feature = "crossbeam-channel")]
+    pub fn event_sender_crossbeam(mut self, s: crossbeam_channel::Sender<TrayEvent<T>>) -> Self {
+        self.event_sender = Some(TrayIconSender::Crossbeam(s));
+        self
+    }
+
+    /// Optional feature, requires `async` feature
+    #[cfg(feature = "async")]
+    pub fn event_sender_async(mut self, s: async_channel::Sender<TrayEvent<T>>) -> Self {
+        self.event_sender = Some(TrayIconSender::Async(s));
+        self
+    }
+
     pub fn tooltip(mut self, tooltip: &str) -> Self {
         self.tooltip = Some(tooltip.to_string());
         self
@@ -106,6 +373,52 @@ where
         self
     }
 
+    /// Event of the middle button, not every host sends it, see
+    /// `TrayIcon::capabilities`
+    pub fn on_middle_click(mut self, id: T) -> Self {
+        self.on_middle_click = Some(id);
+        self
+    }
+
+    /// Event of the mouse wheel, made from the delta and the orientation
+    ///
+    /// The delta is positive up or right, `None` sends nothing. Not every
+    /// host sends these, see `TrayIcon::capabilities`. If the function panics
+    /// the event is dropped and `on_error` gets `Error::CallbackPanicked`.
+    ///
+    /// ```
+    /// # use trayicon::{ScrollOrientation, TrayIconBuilder};
+    /// #[derive(Clone, PartialEq)]
+    /// enum Events {
+    ///     Volume(i32),
+    /// }
+    /// let builder = TrayIconBuilder::new().on_scroll(|delta, orientation| match orientation {
+    ///     ScrollOrientation::Vertical => Some(Events::Volume(delta)),
+    ///     ScrollOrientation::Horizontal => None,
+    /// });
+    /// # let _ = builder;
+    /// ```
+    pub fn on_scroll<F>(mut self, f: F) -> Self
+    where
+        F: Fn(i32, ScrollOrientation) -> Option<T> + Send + Sync + 'static,
+    {
+        self.on_scroll = Some(ScrollHandler::new(f));
+        self
+    }
+
+    /// Event sent when the menu is shown
+    pub fn on_menu_open(mut self, id: T) -> Self {
+        self.on_menu_open = Some(id);
+        self
+    }
+
+    /// Event sent when the menu is hidden, also when it's dismissed without
+    /// choosing an item
+    pub fn on_menu_close(mut self, id: T) -> Self {
+        self.on_menu_close = Some(id);
+        self
+    }
+
     pub fn icon(mut self, icon: Icon) -> Self {
         self.icon = Ok(icon);
         self
@@ -116,6 +429,22 @@ where
         self
     }
 
+    pub fn icon_from_bytes<B>(mut self, bytes: B) -> Self
+    where
+        B: Into<Arc<[u8]>>,
+    {
+        self.icon = Icon::from_bytes(bytes, None, None);
+        self
+    }
+
+    pub fn icon_from_path<P>(mut self, path: P) -> Self
+    where
+        P: AsRef<Path>,
+    {
+        self.icon = Icon::from_path(path, None, None);
+        self
+    }
+
     pub fn menu(mut self, menu: MenuBuilder<T>) -> Self
     where
         T: PartialEq + Clone + 'static,
@@ -124,7 +453,140 @@ where
         self
     }
 
-    pub fn build(self) -> Result<TrayIcon<T>, Error> {
-        Ok(TrayIcon::new(crate::build_trayicon(&self)?, self))
+    /// Fail with `Error::InvalidMenu` when the menu has problems, in `build`
+    /// and in `TrayIcon::set_menu`
+    ///
+    /// Menus are checked only in debug builds, so mistakes surface in tests
+    /// and release builds show the menu as is. See `MenuBuilder::validate`.
+    pub fn reject_invalid_menus(mut self, reject: bool) -> Self {
+        self.reject_invalid_menus = reject;
+        self
+    }
+
+    /// Error if the menu is rejected
+    pub(crate) fn check_menu(&self, menu: &MenuBuilder<T>) -> Result<(), Error> {
+        if !cfg!(debug_assertions) || !self.reject_invalid_menus {
+            return Ok(());
+        }
+        let problems = menu.validate();
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(Error::InvalidMenu(problems))
+        }
+    }
+
+    /// Build the tray icon
+    ///
+    /// On Linux the ids must be `Send + Sync`, the backends send the events
+    /// from threads of their own, see `BackendId`.
+    pub fn build(self) -> Result<TrayIcon<T>, Error>
+    where
+        T: BackendId,
+    {
+        if let Some(menu) = &self.menu {
+            self.check_menu(menu)?;
+        }
+        let sys: crate::TrayIconSys<T> = crate::build_trayicon(&self)?;
+        Ok(TrayIcon::new(Box::new(sys), self))
+    }
+
+    /// Build with the events sent to a `Stream`, requires `async` feature
+    ///
+    /// The stream is an unbounded `async_channel::Receiver`, which works
+    /// with any async runtime. It ends when the tray icon is dropped. The
+    /// sender given before is replaced.
+    #[cfg(feature = "async")]
+    pub fn build_with_stream(self) -> Result<(TrayIcon<T>, async_channel::Receiver<T>), Error>
+    where
+        T: BackendId,
+    {
+        let (s, r) = async_channel::unbounded();
+        Ok((self.sender_async(s).build()?, r))
+    }
+
+    /// Build with the in-memory mock backend
+    ///
+    /// Nothing is shown, instead the returned handle records the changes made
+    /// through `TrayIcon` and simulates user actions. Events are sent with the
+    /// sender as usual, this allows testing applications without a desktop.
+    pub fn build_mock(self) -> Result<(TrayIcon<T>, MockTrayIcon<T>), Error> {
+        if let Some(menu) = &self.menu {
+            self.check_menu(menu)?;
+        }
+        let mock = MockTrayIcon::new(&self)?;
+        Ok((TrayIcon::new(Box::new(mock.clone()), self), mock))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::error::Error as _;
+
+    #[cfg(feature = "async")]
+    #[test]
+    fn test_mock_stream() {
+        use futures_lite::{future::block_on, StreamExt};
+
+        #[derive(Copy, Clone, Eq, PartialEq, Debug)]
+        enum Events {
+            Click,
+            DoubleClick,
+            Item1,
+        }
+
+        let (s, stream) = async_channel::unbounded();
+        let (trayicon, mock) = TrayIconBuilder::new()
+            .sender_async(s)
+            .icon_from_buffer(include_bytes!("./testresource/icon1.ico"))
+            .on_click(Events::Click)
+            .on_double_click(Events::DoubleClick)
+            .menu(MenuBuilder::new().item("Item 1", Events::Item1))
+            .build_mock()
+            .unwrap();
+        mock.double_click();
+        mock.activate_menu_item(&Events::Item1).unwrap();
+        mock.click();
+
+        // Stream ends when the tray icon is gone
+        drop((trayicon, mock));
+        assert_eq!(
+            block_on(stream.collect::<Vec<_>>()),
+            vec![
+                Events::Click,
+                Events::DoubleClick,
+                Events::Item1,
+                Events::Click
+            ]
+        );
+    }
+
+    #[test]
+    fn test_error_context() {
+        fn boxed(e: Error) -> Box<dyn std::error::Error + Send + Sync> {
+            e.into()
+        }
+        let e = Error::IconFile {
+            path: PathBuf::from("icon.ico"),
+            reason: Box::new(Error::IconEntryCorrupt {
+                entry: 2,
+                reason: IconEntryError::Truncated,
+            }),
+        };
+        assert_eq!(
+            e.to_string(),
+            "icon file icon.ico can't be loaded: icon entry 2 can't be decoded: image data is truncated"
+        );
+        assert!(e.source().is_none());
+        assert_eq!(
+            boxed(Error::Os {
+                call: "CreateIcon",
+                code: Some(87),
+                message: "The parameter is incorrect.".into()
+            })
+            .to_string(),
+            "CreateIcon failed with code 87: The parameter is incorrect."
+        );
     }
 }
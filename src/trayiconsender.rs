@@ -1,5 +1,56 @@
+use crate::Error;
+use std::fmt;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// What is done when an event can't be sent right away, see
+/// `TrayIconBuilder::delivery_policy`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DeliveryPolicy {
+    /// Drop the event if the channel is full
+    #[default]
+    Drop,
+
+    /// Wait up to the timeout for room in the channel, then drop the event
+    ///
+    /// Waiting stops the event thread of the tray icon, on Windows the
+    /// thread of the message loop.
+    Block(Duration),
+
+    /// Skip an event equal to the last one while the receiver hasn't taken
+    /// that yet, otherwise like `Drop`
+    ///
+    /// Only channels that tell their length can be seen to be behind, i.e.
+    /// crossbeam and async channels. With the rest this is like `Drop`.
+    Coalesce,
+}
+
+/// Why an event wasn't delivered
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeliveryFailure {
+    /// Bounded channel had no room
+    Full,
+
+    /// Channel had no room within the timeout of `DeliveryPolicy::Block`
+    TimedOut,
+
+    /// Receiver or event loop is gone
+    Disconnected,
+}
+
+impl fmt::Display for DeliveryFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DeliveryFailure::Full => write!(f, "channel is full"),
+            DeliveryFailure::TimedOut => write!(f, "channel stayed full until the timeout"),
+            DeliveryFailure::Disconnected => write!(f, "receiver is gone"),
+        }
+    }
+}
+
 /// Tray Icon event sender
-#[derive(Debug, Clone)]
+#[derive(Clone)]
 pub enum TrayIconSender<T>
 where
     T: PartialEq + Clone + 'static,
@@ -11,25 +62,127 @@ where
 
     #[cfg(feature = "crossbeam-channel")]
     Crossbeam(crossbeam_channel::Sender<T>),
+
+    #[cfg(feature = "async")]
+    Async(async_channel::Sender<T>),
+
+    /// Called on the backend's event thread, see `TrayIconBuilder::on_event`
+    Callback(Arc<dyn Fn(&T) + Send + Sync>),
 }
 
 impl<T> TrayIconSender<T>
 where
     T: PartialEq + Clone + 'static,
 {
-    pub fn send(&self, e: &T) {
+    /// Send the event, a panic of the callback is returned as
+    /// `Error::CallbackPanicked`
+    ///
+    /// Only bounded channels can be full, the `std` and `winit` ones fail
+    /// only when the receiver is gone.
+    #[cfg_attr(
+        not(any(feature = "crossbeam-channel", feature = "async")),
+        allow(unused_variables)
+    )]
+    pub fn send(&self, e: &T, policy: DeliveryPolicy) -> Result<(), Error> {
+        let failure = |reason| Error::EventNotDelivered { reason };
         match self {
-            TrayIconSender::Std(s) => {
-                let _ = s.send(e.clone());
-            }
+            TrayIconSender::Std(s) => s
+                .send(e.clone())
+                .map_err(|_| failure(DeliveryFailure::Disconnected)),
             #[cfg(feature = "winit")]
-            TrayIconSender::Winit(s) => {
-                let _ = s.send_event(e.clone());
-            }
+            TrayIconSender::Winit(s) => s
+                .send_event(e.clone())
+                .map_err(|_| failure(DeliveryFailure::Disconnected)),
             #[cfg(feature = "crossbeam-channel")]
             TrayIconSender::Crossbeam(s) => {
-                let _ = s.try_send(e.clone());
+                use crossbeam_channel::{SendTimeoutError, TrySendError};
+                match policy {
+                    DeliveryPolicy::Block(timeout) => {
+                        s.send_timeout(e.clone(), timeout).map_err(|e| match e {
+                            SendTimeoutError::Timeout(_) => failure(DeliveryFailure::TimedOut),
+                            SendTimeoutError::Disconnected(_) => {
+                                failure(DeliveryFailure::Disconnected)
+                            }
+                        })
+                    }
+                    _ => s.try_send(e.clone()).map_err(|e| match e {
+                        TrySendError::Full(_) => failure(DeliveryFailure::Full),
+                        TrySendError::Disconnected(_) => failure(DeliveryFailure::Disconnected),
+                    }),
+                }
             }
+            #[cfg(feature = "async")]
+            TrayIconSender::Async(s) => {
+                // Channel has no blocking send with a timeout, and the backend
+                // threads have no async runtime, so it's polled
+                let deadline = match policy {
+                    DeliveryPolicy::Block(timeout) => Some(std::time::Instant::now() + timeout),
+                    _ => None,
+                };
+                let mut e = e.clone();
+                loop {
+                    match s.try_send(e) {
+                        Ok(()) => return Ok(()),
+                        Err(async_channel::TrySendError::Closed(_)) => {
+                            return Err(failure(DeliveryFailure::Disconnected))
+                        }
+                        Err(async_channel::TrySendError::Full(back)) => match deadline {
+                            Some(deadline) if std::time::Instant::now() < deadline => {
+                                e = back;
+                                std::thread::sleep(Duration::from_millis(1));
+                            }
+                            Some(_) => return Err(failure(DeliveryFailure::TimedOut)),
+                            None => return Err(failure(DeliveryFailure::Full)),
+                        },
+                    }
+                }
+            }
+            TrayIconSender::Callback(f) => catch_panic(|| f(e)),
+        }
+    }
+
+    /// Whether events sent earlier are still waiting for the receiver,
+    /// `None` if the channel doesn't tell
+    pub fn is_pending(&self) -> Option<bool> {
+        match self {
+            #[cfg(feature = "crossbeam-channel")]
+            TrayIconSender::Crossbeam(s) => Some(!s.is_empty()),
+            #[cfg(feature = "async")]
+            TrayIconSender::Async(s) => Some(!s.is_empty()),
+            _ => None,
+        }
+    }
+}
+
+/// Run user code on a backend thread, a panic is returned as
+/// `Error::CallbackPanicked`
+///
+/// Unwinding must not cross the window procedure or the D-Bus executor.
+pub(crate) fn catch_panic<R>(f: impl FnOnce() -> R) -> Result<R, Error> {
+    catch_unwind(AssertUnwindSafe(f)).map_err(|payload| {
+        let message = payload
+            .downcast_ref::<&str>()
+            .map(|s| s.to_string())
+            .or_else(|| payload.downcast_ref::<String>().cloned())
+            .unwrap_or_default();
+        Error::CallbackPanicked { message }
+    })
+}
+
+impl<T> fmt::Debug for TrayIconSender<T>
+where
+    T: PartialEq + Clone + 'static,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TrayIconSender::Std(s) => f.debug_tuple("Std").field(s).finish(),
+            #[cfg(feature = "winit")]
+            TrayIconSender::Winit(s) => f.debug_tuple("Winit").field(s).finish(),
+            #[cfg(feature = "crossbeam-channel")]
+            TrayIconSender::Crossbeam(s) => f.debug_tuple("Crossbeam").field(s).finish(),
+            #[cfg(feature = "async")]
+            TrayIconSender::Async(s) => f.debug_tuple("Async").field(s).finish(),
+            TrayIconSender::Callback(_) => f.write_str("Callback"),
         }
     }
 }